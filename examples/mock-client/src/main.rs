@@ -25,6 +25,7 @@ async fn main() {
 struct PoW {
     current: ByteArray32,
     difficulty: ByteArray32,
+    server_time: u64,
     #[allow(dead_code)]
     message: String,
 }
@@ -58,7 +59,7 @@ async fn single_request() -> Result<(), Box<dyn std::error::Error>> {
     loop {
         println!("difficulty: {:?}", pow.difficulty);
 
-        let timestamp = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).expect("failed to get timestamp").as_secs();
+        let timestamp = pow.server_time;
         let mut data = pow.current.as_bytes().to_vec();
         data.extend(timestamp.to_be_bytes());
         data.extend(path.as_bytes());
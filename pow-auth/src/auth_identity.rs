@@ -1,21 +1,225 @@
-use secp256k1::Message;
+use std::{
+    fmt,
+    hash::{Hash, Hasher},
+    str::FromStr,
+};
+
+use p256::ecdsa::signature::hazmat::PrehashVerifier;
+use secp256k1::{ecdsa, schnorr, Message, XOnlyPublicKey};
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum CryptoError {
+    #[error("unknown key type: {0}")]
+    UnknownKeyType(String),
+    #[error("invalid hex: {0}")]
+    Hex(#[from] hex::FromHexError),
+    #[error("{0}")]
+    Secp256k1(#[from] secp256k1::Error),
+    #[error("{0}")]
+    Signature(#[from] ed25519_dalek::SignatureError),
+    #[error("signature of another key type")]
+    KeyTypeMismatch,
+}
+
+/// How the key of a grant signs. Every type signs the SHA-256 digest of the
+/// signed data, which for P-256 matches plain ECDSA with SHA-256.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum KeyType {
+    /// ECDSA over secp256k1, with a compressed or uncompressed key and DER
+    /// signatures.
+    #[default]
+    #[serde(alias = "ecdsa")]
+    Secp256k1,
+    /// BIP-340 Schnorr, with an x-only key and 64 byte signatures.
+    Schnorr,
+    /// Ed25519, with a 32 byte key and 64 byte signatures.
+    Ed25519,
+    /// ECDSA over NIST P-256, with a SEC1 encoded key and DER signatures.
+    P256,
+}
+
+impl KeyType {
+    const ALL: [KeyType; 4] = [
+        KeyType::Secp256k1,
+        KeyType::Schnorr,
+        KeyType::Ed25519,
+        KeyType::P256,
+    ];
+
+    /// Prefix of the key in `X-Auth-PublicKey`, also its name in the config.
+    pub fn name(&self) -> &'static str {
+        match self {
+            KeyType::Secp256k1 => "secp256k1",
+            KeyType::Schnorr => "schnorr",
+            KeyType::Ed25519 => "ed25519",
+            KeyType::P256 => "p256",
+        }
+    }
+
+    pub fn signature_format(&self) -> &'static str {
+        match self {
+            KeyType::Secp256k1 | KeyType::P256 => "a DER format string",
+            KeyType::Schnorr | KeyType::Ed25519 => "64 bytes in hex",
+        }
+    }
+
+    /// `alg` of RFC 9421 signatures. Only `ed25519` and `ecdsa-p256-sha256`
+    /// are registered, the secp256k1 names follow them.
+    pub fn algorithm(&self) -> &'static str {
+        match self {
+            KeyType::Secp256k1 => "ecdsa-secp256k1-sha256",
+            KeyType::Schnorr => "schnorr-secp256k1-sha256",
+            KeyType::Ed25519 => "ed25519",
+            KeyType::P256 => "ecdsa-p256-sha256",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PublicKey {
+    Secp256k1(secp256k1::PublicKey),
+    Schnorr(XOnlyPublicKey),
+    Ed25519(ed25519_dalek::VerifyingKey),
+    P256(p256::ecdsa::VerifyingKey),
+}
+
+impl PublicKey {
+    /// Parses a hex encoded key of `key_type`.
+    pub fn parse(key_type: KeyType, s: &str) -> Result<Self, CryptoError> {
+        let key = match key_type {
+            KeyType::Secp256k1 => PublicKey::Secp256k1(s.parse()?),
+            KeyType::Schnorr => PublicKey::Schnorr(s.parse()?),
+            KeyType::Ed25519 => {
+                let mut bytes = [0; 32];
+                hex::decode_to_slice(s, &mut bytes)?;
+                PublicKey::Ed25519(ed25519_dalek::VerifyingKey::from_bytes(&bytes)?)
+            }
+            KeyType::P256 => PublicKey::P256(p256::ecdsa::VerifyingKey::from_sec1_bytes(
+                &hex::decode(s)?,
+            )?),
+        };
+        Ok(key)
+    }
+
+    pub fn key_type(&self) -> KeyType {
+        match self {
+            PublicKey::Secp256k1(_) => KeyType::Secp256k1,
+            PublicKey::Schnorr(_) => KeyType::Schnorr,
+            PublicKey::Ed25519(_) => KeyType::Ed25519,
+            PublicKey::P256(_) => KeyType::P256,
+        }
+    }
+
+    /// SHA-256 of the key as printed, in hex.
+    pub fn fingerprint(&self) -> String {
+        hex::encode(Sha256::digest(self.to_string().as_bytes()))
+    }
+
+    fn to_bytes(self) -> Vec<u8> {
+        match self {
+            PublicKey::Secp256k1(key) => key.serialize().to_vec(),
+            PublicKey::Schnorr(key) => key.serialize().to_vec(),
+            PublicKey::Ed25519(key) => key.to_bytes().to_vec(),
+            PublicKey::P256(key) => key.to_encoded_point(true).as_bytes().to_vec(),
+        }
+    }
+}
+
+/// P-256 keys don't hash, the compressed encoding of every key does.
+impl Hash for PublicKey {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.key_type().hash(state);
+        self.to_bytes().hash(state);
+    }
+}
+
+/// Parses `X-Auth-PublicKey`, `<key type>:<hex key>`. Without a prefix the
+/// key is secp256k1, x-only if it is 32 bytes long.
+impl FromStr for PublicKey {
+    type Err = CryptoError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((name, key)) => {
+                let key_type = KeyType::ALL
+                    .into_iter()
+                    .find(|key_type| key_type.name() == name)
+                    .ok_or_else(|| CryptoError::UnknownKeyType(name.to_string()))?;
+                Self::parse(key_type, key)
+            }
+            None if s.len() == 64 => Self::parse(KeyType::Schnorr, s),
+            None => Self::parse(KeyType::Secp256k1, s),
+        }
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}",
+            self.key_type().name(),
+            hex::encode(self.to_bytes())
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signature {
+    Secp256k1(ecdsa::Signature),
+    Schnorr(schnorr::Signature),
+    Ed25519(ed25519_dalek::Signature),
+    P256(p256::ecdsa::Signature),
+}
+
+impl Signature {
+    /// Parses a hex signature in the format of `key_type`.
+    pub fn parse(key_type: KeyType, s: &str) -> Result<Self, CryptoError> {
+        let signature = match key_type {
+            KeyType::Secp256k1 => Signature::Secp256k1(s.parse()?),
+            KeyType::Schnorr => Signature::Schnorr(s.parse()?),
+            KeyType::Ed25519 => {
+                Signature::Ed25519(ed25519_dalek::Signature::from_slice(&hex::decode(s)?)?)
+            }
+            KeyType::P256 => Signature::P256(p256::ecdsa::Signature::from_der(&hex::decode(s)?)?),
+        };
+        Ok(signature)
+    }
+
+    /// Parses a signature of RFC 9421, 64 bytes for every key type with
+    /// ECDSA as `r || s`.
+    pub fn from_bytes(key_type: KeyType, bytes: &[u8]) -> Result<Self, CryptoError> {
+        let signature = match key_type {
+            KeyType::Secp256k1 => {
+                // libsecp256k1 only verifies the lower of the two s values,
+                // which other libraries don't settle on.
+                let mut signature = ecdsa::Signature::from_compact(bytes)?;
+                signature.normalize_s();
+                Signature::Secp256k1(signature)
+            }
+            KeyType::Schnorr => Signature::Schnorr(schnorr::Signature::from_slice(bytes)?),
+            KeyType::Ed25519 => Signature::Ed25519(ed25519_dalek::Signature::from_slice(bytes)?),
+            KeyType::P256 => Signature::P256(p256::ecdsa::Signature::from_slice(bytes)?),
+        };
+        Ok(signature)
+    }
+}
 
 pub struct AuthIdentity<'a, D> {
-    pub_key: &'a secp256k1::PublicKey,
+    pub_key: &'a PublicKey,
     data: D,
-    signature: &'a secp256k1::ecdsa::Signature,
+    signature: &'a Signature,
 }
 
 impl<'a, D> AuthIdentity<'a, D>
 where
     D: Into<Message> + Clone,
 {
-    pub fn new(
-        pub_key: &'a secp256k1::PublicKey,
-        data: D,
-        signature: &'a secp256k1::ecdsa::Signature,
-    ) -> Self {
+    pub fn new(pub_key: &'a PublicKey, data: D, signature: &'a Signature) -> Self {
         Self {
             pub_key,
             data,
@@ -23,13 +227,104 @@ where
         }
     }
 
-    pub fn verify(&self) -> Result<(), secp256k1::Error> {
-        let secp = secp256k1::Secp256k1::new();
+    pub fn verify(&self) -> Result<(), CryptoError> {
+        let secp = secp256k1::Secp256k1::verification_only();
         let msg: Message = self.data.clone().into();
-        secp.verify_ecdsa(&msg, self.signature, self.pub_key)
+        match (self.pub_key, self.signature) {
+            (PublicKey::Secp256k1(key), Signature::Secp256k1(signature)) => {
+                secp.verify_ecdsa(&msg, signature, key)?
+            }
+            (PublicKey::Schnorr(key), Signature::Schnorr(signature)) => {
+                secp.verify_schnorr(signature, &msg, key)?
+            }
+            (PublicKey::Ed25519(key), Signature::Ed25519(signature)) => {
+                key.verify_strict(msg.as_ref(), signature)?
+            }
+            (PublicKey::P256(key), Signature::P256(signature)) => {
+                key.verify_prehash(msg.as_ref(), signature)?
+            }
+            _ => return Err(CryptoError::KeyTypeMismatch),
+        }
+        Ok(())
+    }
+}
+
+/// A part of the request a signature covers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Component {
+    Method,
+    Authority,
+    /// Path and query, as in `:path`.
+    Path,
+    Timestamp,
+    Nonce,
+    /// The `Content-Digest` header (RFC 9530), left out of requests without
+    /// a body.
+    ContentDigest,
+}
+
+impl Component {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Component::Method => "method",
+            Component::Authority => "authority",
+            Component::Path => "path",
+            Component::Timestamp => "timestamp",
+            Component::Nonce => "nonce",
+            Component::ContentDigest => "content-digest",
+        }
+    }
+}
+
+/// Opens every canonical signing string, so that a signature can't be taken
+/// for one over another format.
+pub const SIGNING_VERSION: &str = "pow-auth-v1";
+
+/// The canonical string a client signs, one `name:value` line per covered
+/// component after the version line:
+///
+/// ```text
+/// pow-auth-v1
+/// method:POST
+/// authority:example.com
+/// path:/api/transfer?dry_run=1
+/// timestamp:1610000000
+/// nonce:6f1c0a5e
+/// content-digest:sha-256=:RK/0qy18MlBSVnWgjwz6lZEWjP/lF5HF9bvEF8FabDg=:
+/// ```
+///
+/// Components always appear in this order, whatever order the route lists
+/// them in. The signature is over the SHA-256 of the string.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SigningString(String);
+
+impl SigningString {
+    pub fn new<'a>(components: impl IntoIterator<Item = (Component, &'a str)>) -> Self {
+        let mut components: Vec<_> = components.into_iter().collect();
+        components.sort_by_key(|(component, _)| *component);
+        let mut string = SIGNING_VERSION.to_string();
+        for (component, value) in components {
+            string.push('\n');
+            string.push_str(component.name());
+            string.push(':');
+            string.push_str(value);
+        }
+        Self(string)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<SigningString> for Message {
+    fn from(value: SigningString) -> Self {
+        Message::from_digest(Sha256::digest(value.0.as_bytes()).into())
     }
 }
 
+/// Path and timestamp only, the format before [`SigningString`].
 #[derive(Debug, Clone)]
 pub struct AuthFactors<'a> {
     url: &'a str,
@@ -55,16 +350,19 @@ impl From<AuthFactors<'_>> for Message {
 #[cfg(test)]
 mod test {
     use hex_literal::hex;
-    use secp256k1::{PublicKey, Secp256k1, SecretKey};
+    use secp256k1::{Keypair, Secp256k1, SecretKey};
 
-    use super::{AuthFactors, AuthIdentity};
+    use super::{
+        AuthFactors, AuthIdentity, Component, CryptoError, KeyType, Message, PublicKey, Signature,
+        SigningString,
+    };
     #[test]
     fn test() {
         let hex_secret = hex!("3f880ce0892ac66019804c80292d4e90a38aa70a9dabad3f4314bf050f492afc");
         let secret = SecretKey::from_slice(&hex_secret).unwrap();
         println!("{:?}", secret);
         let secp = Secp256k1::new();
-        let pub_key = PublicKey::from_secret_key(&secp, &secret);
+        let pub_key = PublicKey::Secp256k1(secp256k1::PublicKey::from_secret_key(&secp, &secret));
 
         let url = "/api/v1/hello";
         let timestamp = 1619823600;
@@ -72,8 +370,104 @@ mod test {
         let factors = AuthFactors::new(url, timestamp);
         // let msg: Message = factors.into();
         // println!("{:?}", msg);
-        let signature = secp.sign_ecdsa(&factors.clone().into(), &secret);
+        let signature = Signature::Secp256k1(secp.sign_ecdsa(&factors.clone().into(), &secret));
         let identity = AuthIdentity::new(&pub_key, factors, &signature);
         println!("{:?}", identity.verify());
     }
+
+    #[test]
+    fn signing_string() {
+        let string = SigningString::new([
+            (Component::Nonce, "6f1c0a5e"),
+            (Component::Method, "POST"),
+            (Component::Timestamp, "1610000000"),
+            (Component::Path, "/api/transfer?dry_run=1"),
+            (Component::Authority, "example.com"),
+        ]);
+        assert_eq!(
+            string.as_str(),
+            "pow-auth-v1\nmethod:POST\nauthority:example.com\n\
+             path:/api/transfer?dry_run=1\ntimestamp:1610000000\nnonce:6f1c0a5e"
+        );
+
+        let secret = SecretKey::from_slice(&[7; 32]).unwrap();
+        let secp = Secp256k1::new();
+        let pub_key = PublicKey::Secp256k1(secp256k1::PublicKey::from_secret_key(&secp, &secret));
+        let signature = Signature::Secp256k1(secp.sign_ecdsa(&string.clone().into(), &secret));
+        assert!(AuthIdentity::new(&pub_key, string, &signature)
+            .verify()
+            .is_ok());
+
+        let delete = SigningString::new([
+            (Component::Method, "DELETE"),
+            (Component::Path, "/api/transfer?dry_run=1"),
+        ]);
+        assert!(AuthIdentity::new(&pub_key, delete, &signature)
+            .verify()
+            .is_err());
+    }
+
+    #[test]
+    fn schnorr() {
+        let secp = Secp256k1::new();
+        let keypair = Keypair::from_secret_key(&secp, &SecretKey::from_slice(&[7; 32]).unwrap());
+        let factors = AuthFactors::new("/api/v1/hello", 1619823600);
+        let signature = secp.sign_schnorr_no_aux_rand(&factors.clone().into(), &keypair);
+
+        let pub_key: PublicKey = keypair.x_only_public_key().0.to_string().parse().unwrap();
+        assert_eq!(pub_key.key_type(), KeyType::Schnorr);
+        let signature = Signature::parse(KeyType::Schnorr, &signature.to_string()).unwrap();
+        assert!(AuthIdentity::new(&pub_key, factors.clone(), &signature)
+            .verify()
+            .is_ok());
+
+        // The same secret as an ECDSA key doesn't verify Schnorr signatures.
+        let ecdsa: PublicKey = keypair.public_key().to_string().parse().unwrap();
+        assert_eq!(ecdsa.key_type(), KeyType::Secp256k1);
+        assert!(AuthIdentity::new(&ecdsa, factors, &signature)
+            .verify()
+            .is_err());
+    }
+
+    #[test]
+    fn ed25519_and_p256() {
+        use ed25519_dalek::Signer;
+        use p256::ecdsa::signature::hazmat::PrehashSigner;
+
+        let message: Message = AuthFactors::new("/api/v1/hello", 1619823600).into();
+
+        let ed25519 = ed25519_dalek::SigningKey::from_bytes(&[7; 32]);
+        let pub_key: PublicKey = format!("ed25519:{}", hex::encode(ed25519.verifying_key()))
+            .parse()
+            .unwrap();
+        let signature = hex::encode(ed25519.sign(message.as_ref()).to_bytes());
+        let signature = Signature::parse(KeyType::Ed25519, &signature).unwrap();
+        assert!(AuthIdentity::new(&pub_key, message, &signature)
+            .verify()
+            .is_ok());
+
+        let p256 = p256::ecdsa::SigningKey::from_bytes(&[7; 32].into()).unwrap();
+        let encoded = p256.verifying_key().to_encoded_point(false);
+        let pub_key: PublicKey = format!("p256:{}", hex::encode(encoded.as_bytes()))
+            .parse()
+            .unwrap();
+        // Keys print compressed, with their type.
+        assert_eq!(pub_key.to_string().len(), "p256:".len() + 66);
+        assert_eq!(pub_key.to_string().parse::<PublicKey>().unwrap(), pub_key);
+        let signature: p256::ecdsa::Signature = p256.sign_prehash(message.as_ref()).unwrap();
+        let signature = hex::encode(signature.to_der().as_bytes());
+        let signature = Signature::parse(KeyType::P256, &signature).unwrap();
+        assert!(AuthIdentity::new(&pub_key, message, &signature)
+            .verify()
+            .is_ok());
+
+        let other: Message = AuthFactors::new("/api/v1/hello", 1619823601).into();
+        assert!(AuthIdentity::new(&pub_key, other, &signature)
+            .verify()
+            .is_err());
+        assert!(matches!(
+            "rsa:00".parse::<PublicKey>(),
+            Err(CryptoError::UnknownKeyType(_))
+        ));
+    }
 }
@@ -1,41 +1,157 @@
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap, HashSet};
 
 use pow_runtime::log_level::LogLevel;
-use pow_types::{cidr::CIDR, config::VirtualHost};
-use secp256k1::PublicKey;
-use serde::{Deserialize, Serialize};
+use pow_types::{cidr::CIDR, config::VirtualHost, timestamp::TimestampWindow};
+use serde::{de::Error, Deserialize, Serialize};
+
+use crate::{
+    auth_identity::{Component, KeyType, PublicKey},
+    revocation::KeyRejection,
+    scope::Scope,
+};
 
 #[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Token {
     pub name: String,
-    pub public_key: PublicKey,
+    /// Hex encoded, x-only for Schnorr and SEC1 for P-256 keys.
+    pub public_key: String,
+    pub key_type: Option<KeyType>,
+    /// `keyid` of its RFC 9421 signatures, the key as in `X-Auth-PublicKey`
+    /// by default.
+    pub key_id: Option<String>,
+    /// Unix seconds from which the key is valid.
+    pub not_before: Option<u64>,
+    /// Unix seconds after which the key is no longer valid.
+    pub not_after: Option<u64>,
+    /// Limits the key within the route, it may do anything there otherwise.
+    pub scope: Option<Scope>,
 }
 
 #[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
-enum RawSetting {
+enum RawAccess {
     Grants(Vec<Token>),
     Public,
 }
 
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+struct RawSetting {
+    #[serde(flatten)]
+    access: RawAccess,
+    timestamp_window: Option<TimestampWindow>,
+    signed_components: Option<BTreeSet<Component>>,
+    legacy_signatures: Option<bool>,
+}
+
 #[derive(Debug, Eq, PartialEq)]
-pub enum Setting {
-    Grants(HashMap<PublicKey, String>),
+pub struct Grant {
+    pub name: String,
+    pub key_id: String,
+    pub not_before: Option<u64>,
+    pub not_after: Option<u64>,
+    pub scope: Scope,
+}
+
+impl Grant {
+    /// Whether the key may sign at `now`, revocations aside.
+    pub fn check(&self, now: u64) -> Result<(), KeyRejection> {
+        if self.not_before.is_some_and(|not_before| now < not_before) {
+            return Err(KeyRejection::NotYetValid);
+        }
+        if self.not_after.is_some_and(|not_after| now > not_after) {
+            return Err(KeyRejection::Expired);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum Access {
+    Grants(HashMap<PublicKey, Grant>),
     Public,
 }
 
-impl From<RawSetting> for Setting {
-    fn from(raw: RawSetting) -> Self {
-        match raw {
-            RawSetting::Grants(grants_vec) => {
+#[derive(Debug, Eq, PartialEq)]
+pub struct Setting {
+    pub access: Access,
+    pub timestamp_window: TimestampWindow,
+    /// Parts of the request the signature covers, everything but the body
+    /// digest by default.
+    pub signed_components: BTreeSet<Component>,
+    /// Also accept signatures over the path and timestamp only, for clients
+    /// that predate the canonical signing string.
+    pub legacy_signatures: bool,
+}
+
+fn default_signed_components() -> BTreeSet<Component> {
+    BTreeSet::from([
+        Component::Method,
+        Component::Authority,
+        Component::Path,
+        Component::Timestamp,
+        Component::Nonce,
+    ])
+}
+
+impl TryFrom<RawSetting> for Setting {
+    type Error = String;
+
+    fn try_from(raw: RawSetting) -> Result<Self, Self::Error> {
+        let signed_components = raw
+            .signed_components
+            .unwrap_or_else(default_signed_components);
+        // Replay protection relies on both being signed.
+        if !signed_components.contains(&Component::Timestamp)
+            || !signed_components.contains(&Component::Nonce)
+        {
+            return Err("signed_components must include timestamp and nonce".to_string());
+        }
+        let access = match raw.access {
+            RawAccess::Grants(grants_vec) => {
                 let mut grants = HashMap::new();
+                let mut key_ids = HashSet::new();
                 for token in grants_vec {
-                    grants.insert(token.public_key, token.name);
+                    let key_type = token.key_type.unwrap_or_default();
+                    let public_key = PublicKey::parse(key_type, &token.public_key)
+                        .map_err(|e| format!("invalid public key of {}: {}", token.name, e))?;
+                    let key_id = token.key_id.unwrap_or_else(|| public_key.to_string());
+                    if !key_ids.insert(key_id.clone()) {
+                        return Err(format!("key_id {} of {} is taken", key_id, token.name));
+                    }
+                    if let (Some(not_before), Some(not_after)) = (token.not_before, token.not_after)
+                    {
+                        if not_before > not_after {
+                            return Err(format!(
+                                "not_before of {} is after its not_after",
+                                token.name
+                            ));
+                        }
+                    }
+                    let scope = token.scope.unwrap_or_default();
+                    scope
+                        .validate()
+                        .map_err(|e| format!("invalid scope of {}: {}", token.name, e))?;
+                    grants.insert(
+                        public_key,
+                        Grant {
+                            name: token.name,
+                            key_id,
+                            not_before: token.not_before,
+                            not_after: token.not_after,
+                            scope,
+                        },
+                    );
                 }
-                Setting::Grants(grants)
+                Access::Grants(grants)
             }
-            RawSetting::Public => Setting::Public,
-        }
+            RawAccess::Public => Access::Public,
+        };
+        Ok(Setting {
+            access,
+            timestamp_window: raw.timestamp_window.unwrap_or_default(),
+            signed_components,
+            legacy_signatures: raw.legacy_signatures.unwrap_or_default(),
+        })
     }
 }
 
@@ -44,7 +160,8 @@ impl<'de> Deserialize<'de> for Setting {
     where
         D: serde::Deserializer<'de>,
     {
-        RawSetting::deserialize(deserializer).map(Setting::from)
+        RawSetting::deserialize(deserializer)
+            .and_then(|raw| Setting::try_from(raw).map_err(D::Error::custom))
     }
 }
 
@@ -53,4 +170,170 @@ pub struct Config<T> {
     pub virtual_hosts: Vec<VirtualHost<T>>,
     pub whitelist: Option<Vec<CIDR>>,
     pub log_level: Option<LogLevel>,
+    /// Header telling the upstream which grant signed the request,
+    /// `X-Auth-Identity` by default.
+    pub identity_header: Option<String>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::scope::AccessClass;
+
+    #[test]
+    fn parse_settings() {
+        let config: Vec<VirtualHost<Setting>> = serde_yaml::from_str(
+            r#"
+- host: "example.com"
+  routes:
+  - path: "/api"
+    public: null
+    children:
+    - path: "/users"
+      signed_components: [content_digest, nonce, timestamp, method, path]
+      legacy_signatures: true
+      timestamp_window:
+        past_secs: 30
+        future_secs: 5
+      grants:
+      - name: "Alice"
+        public_key: "039e70a683d711ab788433b4cabddbd10dce4bb1f29c67cc3219b325053b0f2f1c"
+      - name: "Bob"
+        key_type: schnorr
+        key_id: "bob"
+        not_before: 1700000000
+        not_after: 1800000000
+        scope:
+          methods: [GET]
+          paths: ["/*"]
+          class: read
+        public_key: "9e70a683d711ab788433b4cabddbd10dce4bb1f29c67cc3219b325053b0f2f1c"
+"#,
+        )
+        .expect("failed to parse config");
+
+        let api = &config[0].routes[0];
+        assert_eq!(api.config.access, Access::Public);
+        assert_eq!(api.config.timestamp_window, TimestampWindow::default());
+        assert_eq!(api.config.signed_components, default_signed_components());
+        assert!(!api.config.legacy_signatures);
+
+        let users = &api.children.as_ref().expect("missing children")[0];
+        let Access::Grants(ref grants) = users.config.access else {
+            panic!("expected grants");
+        };
+        let key_types: Vec<_> = ["Alice", "Bob"]
+            .iter()
+            .map(|name| {
+                grants
+                    .iter()
+                    .find(|(_, grant)| grant.name == *name)
+                    .map(|(k, _)| k.key_type())
+            })
+            .collect();
+        assert_eq!(
+            key_types,
+            [Some(KeyType::Secp256k1), Some(KeyType::Schnorr)]
+        );
+        let bob = grants.values().find(|grant| grant.name == "Bob").unwrap();
+        assert_eq!(bob.key_id, "bob");
+        assert_eq!(bob.scope.class, Some(AccessClass::Read));
+        let alice = grants.values().find(|grant| grant.name == "Alice").unwrap();
+        assert_eq!(
+            alice.key_id,
+            "secp256k1:039e70a683d711ab788433b4cabddbd10dce4bb1f29c67cc3219b325053b0f2f1c"
+        );
+        assert!(bob.scope.allows("GET", "/1"));
+        assert_eq!(
+            users.config.timestamp_window,
+            TimestampWindow {
+                past_secs: 30,
+                future_secs: 5
+            }
+        );
+        assert_eq!(
+            users.config.signed_components,
+            BTreeSet::from([
+                Component::Method,
+                Component::Path,
+                Component::Timestamp,
+                Component::Nonce,
+                Component::ContentDigest
+            ])
+        );
+        assert!(users.config.legacy_signatures);
+    }
+
+    #[test]
+    fn key_validity() {
+        let grant = Grant {
+            name: "Bob".to_string(),
+            key_id: "bob".to_string(),
+            not_before: Some(1_700_000_000),
+            not_after: Some(1_800_000_000),
+            scope: Scope::default(),
+        };
+        assert_eq!(grant.check(1_699_999_999), Err(KeyRejection::NotYetValid));
+        assert_eq!(grant.check(1_700_000_000), Ok(()));
+        assert_eq!(grant.check(1_800_000_000), Ok(()));
+        assert_eq!(grant.check(1_800_000_001), Err(KeyRejection::Expired));
+
+        let err = serde_yaml::from_str::<Setting>(
+            r#"
+grants:
+- name: "Alice"
+  public_key: "039e70a683d711ab788433b4cabddbd10dce4bb1f29c67cc3219b325053b0f2f1c"
+  not_before: 1800000000
+  not_after: 1700000000
+"#,
+        )
+        .expect_err("accepted empty validity window");
+        assert!(err
+            .to_string()
+            .contains("not_before of Alice is after its not_after"));
+    }
+
+    #[test]
+    fn rejects_taken_key_id() {
+        let err = serde_yaml::from_str::<Setting>(
+            r#"
+grants:
+- name: "Alice"
+  key_id: "treasury"
+  public_key: "039e70a683d711ab788433b4cabddbd10dce4bb1f29c67cc3219b325053b0f2f1c"
+- name: "Bob"
+  key_id: "treasury"
+  key_type: schnorr
+  public_key: "9e70a683d711ab788433b4cabddbd10dce4bb1f29c67cc3219b325053b0f2f1c"
+"#,
+        )
+        .expect_err("accepted taken key_id");
+        assert!(err.to_string().contains("key_id treasury of Bob is taken"));
+    }
+
+    #[test]
+    fn rejects_key_of_wrong_type() {
+        let err = serde_yaml::from_str::<Setting>(
+            r#"
+grants:
+- name: "Alice"
+  key_type: schnorr
+  public_key: "039e70a683d711ab788433b4cabddbd10dce4bb1f29c67cc3219b325053b0f2f1c"
+"#,
+        )
+        .expect_err("accepted ECDSA key as Schnorr");
+        assert!(err.to_string().contains("invalid public key of Alice"));
+    }
+
+    #[test]
+    fn requires_signed_timestamp_and_nonce() {
+        let err = serde_yaml::from_str::<Setting>(
+            r#"
+public: null
+signed_components: [method, path, nonce]
+"#,
+        )
+        .expect_err("accepted unsigned timestamp");
+        assert!(err.to_string().contains("must include timestamp and nonce"));
+    }
 }
@@ -0,0 +1,83 @@
+use base64::{engine::general_purpose::STANDARD, Engine};
+use sha2::{Digest, Sha256, Sha512};
+use thiserror::Error;
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum DigestError {
+    #[error("no supported algorithm, expect sha-256 or sha-512")]
+    Unsupported,
+    #[error("malformed entry: {0}")]
+    Malformed(String),
+    #[error("{0} does not match the body")]
+    Mismatch(&'static str),
+}
+
+/// Checks `body` against a `Content-Digest` header (RFC 9530), such as
+/// `sha-256=:RK/0qy18MlBSVnWgjwz6lZEWjP/lF5HF9bvEF8FabDg=:`. Entries with an
+/// unknown algorithm are skipped, every other one has to match.
+pub fn verify(header: &str, body: &[u8]) -> Result<(), DigestError> {
+    let mut checked = false;
+    for entry in header.split(',') {
+        // Parameters don't change the digest.
+        let entry = entry.split(';').next().unwrap_or_default().trim();
+        let (algorithm, value) = entry
+            .split_once('=')
+            .ok_or_else(|| DigestError::Malformed(entry.to_string()))?;
+        let (algorithm, actual) = match algorithm.trim() {
+            "sha-256" => ("sha-256", Sha256::digest(body).to_vec()),
+            "sha-512" => ("sha-512", Sha512::digest(body).to_vec()),
+            _ => continue,
+        };
+        let expected = value
+            .trim()
+            .strip_prefix(':')
+            .and_then(|v| v.strip_suffix(':'))
+            .and_then(|v| STANDARD.decode(v).ok())
+            .ok_or_else(|| DigestError::Malformed(entry.to_string()))?;
+        if expected != actual {
+            return Err(DigestError::Mismatch(algorithm));
+        }
+        checked = true;
+    }
+    match checked {
+        true => Ok(()),
+        false => Err(DigestError::Unsupported),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn verifies_content_digest() {
+        // The example body of RFC 9530, with a trailing newline.
+        let body = b"{\"hello\": \"world\"}\n";
+        assert_eq!(
+            verify(
+                "sha-256=:RK/0qy18MlBSVnWgjwz6lZEWjP/lF5HF9bvEF8FabDg=:",
+                body
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            verify(
+                "unixsum=:MTIzNA==:, sha-512=:YMAam51Jz/jOATT6/zvHrLVgOYTGFy1d6GJiOHTohq4yP+pgk4vf2aCsyRZOtw8MjkM7iw7yZ/WkppmM44T3qg==:",
+                body
+            ),
+            Ok(())
+        );
+        assert_eq!(
+            verify(
+                "sha-256=:RK/0qy18MlBSVnWgjwz6lZEWjP/lF5HF9bvEF8FabDg=:",
+                b"{}"
+            ),
+            Err(DigestError::Mismatch("sha-256"))
+        );
+        assert_eq!(verify("md5=:AAAA:", body), Err(DigestError::Unsupported));
+        assert!(matches!(
+            verify("sha-256=RK/0qy18MlBSVnWgjwz6lZEWjP/lF5HF9bvEF8FabDg=", body),
+            Err(DigestError::Malformed(_))
+        ));
+    }
+}
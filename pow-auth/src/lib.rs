@@ -1,26 +1,50 @@
 pub mod auth_identity;
 pub mod config;
+pub mod digest;
+pub mod message_signature;
+pub mod replay;
+pub mod revocation;
+pub mod scope;
 
-use std::{net::SocketAddr, sync::Arc};
-
-use auth_identity::{AuthFactors, AuthIdentity};
-use config::{Config, Setting};
-use pow_runtime::{response::Response, Ctx, HttpHook, Runtime, RuntimeBox};
-use pow_types::{cidr::CIDR, config::Router};
-use proxy_wasm::{
-    traits::{Context, RootContext},
-    types::LogLevel,
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex, OnceLock},
 };
-use secp256k1::{ecdsa::Signature, PublicKey};
+
+use auth_identity::{AuthFactors, AuthIdentity, Component, PublicKey, Signature, SigningString};
+use config::{Access, Config, Grant, Setting};
+use message_signature::SignatureInput;
+use pow_runtime::{headers::HeaderMutations, response::Response, Ctx, HttpHook, Interest, Runtime};
+use pow_types::{cidr::CIDR, config::Router, timestamp::TimestampError};
+use proxy_wasm::{traits::Context, types::LogLevel};
+use replay::SeenNonces;
+use revocation::{KeyRejection, Revocations};
+use scope::relative_path;
 
 const HEADER_PUBLIC_KEY_NAME: &str = "X-Auth-PublicKey";
 const HEADER_SIGNATURE_NAME: &str = "X-Auth-Signature";
 const HEADER_TIMESTAMP_NAME: &str = "X-Auth-Timestamp";
+const HEADER_NONCE_NAME: &str = "X-Auth-Nonce";
+const HEADER_CONTENT_DIGEST_NAME: &str = "Content-Digest";
+const HEADER_SIGNATURE_INPUT_NAME: &str = "Signature-Input";
+const HEADER_MESSAGE_SIGNATURE_NAME: &str = "Signature";
+const HEADER_ACCEPT_SIGNATURE_NAME: &str = "Accept-Signature";
+const DEFAULT_IDENTITY_HEADER: &str = "X-Auth-Identity";
+
+const PROPERTY_GRANT: &str = "pow_auth.grant";
+const PROPERTY_KEY_FINGERPRINT: &str = "pow_auth.key_fingerprint";
+
+/// Longest accepted `X-Auth-Nonce`, it is kept in shared data.
+const MAX_NONCE_LEN: usize = 128;
 
+/// Largest body buffered to check its `Content-Digest`.
+const MAX_SIGNED_BODY_BYTES: usize = 1 << 20;
+
+#[cfg(target_arch = "wasm32")]
 proxy_wasm::main! {{
     proxy_wasm::set_log_level(LogLevel::Trace);
-    proxy_wasm::set_root_context(move |context_id| -> Box<dyn RootContext> {
-        Box::new(RuntimeBox::new(Plugin { _context_id: context_id, inner: None }))
+    proxy_wasm::set_root_context(move |context_id| -> Box<dyn proxy_wasm::traits::RootContext> {
+        Box::new(pow_runtime::RuntimeBox::new(Plugin { context_id, inner: None }))
     });
 }}
 
@@ -93,11 +117,16 @@ impl From<Error> for Response {
 struct Inner {
     router: Router<Setting>,
     whitelist: Vec<CIDR>,
+    nonces: SeenNonces,
+    revocations: Revocations,
+    identity_header: String,
 }
 
+// Only constructed by the wasm entry point.
 #[derive(Clone)]
+#[cfg_attr(not(target_arch = "wasm32"), allow(dead_code))]
 struct Plugin {
-    _context_id: u32,
+    context_id: u32,
     inner: Option<Arc<Inner>>,
 }
 
@@ -105,7 +134,7 @@ impl Context for Plugin {}
 impl Runtime for Plugin {
     type Hook = Hook;
 
-    fn on_vm_start(&mut self, _vm_configuration_size: usize) -> bool {
+    fn on_vm_start(&mut self, _vm_configuration: Option<Vec<u8>>) -> bool {
         log::info!("Auth filter starting...");
         true
     }
@@ -133,6 +162,7 @@ impl Runtime for Plugin {
         proxy_wasm::set_log_level(config.log_level.map(Into::into).unwrap_or(LogLevel::Trace));
 
         let whitelist = config.whitelist.take().unwrap_or_default();
+        let identity_header = config.identity_header.take();
 
         let router: Router<Setting> = match config.virtual_hosts.try_into() {
             Ok(router) => router,
@@ -147,7 +177,13 @@ impl Runtime for Plugin {
             }
         };
 
-        self.inner = Some(Arc::new(Inner { router, whitelist }));
+        self.inner = Some(Arc::new(Inner {
+            router,
+            whitelist,
+            nonces: SeenNonces::new(self.context_id),
+            revocations: Revocations::default(),
+            identity_header: identity_header.unwrap_or_else(|| DEFAULT_IDENTITY_HEADER.to_string()),
+        }));
         log::info!("Auth filter configured...");
         true
     }
@@ -156,6 +192,8 @@ impl Runtime for Plugin {
         Some(Hook {
             ctx: Ctx::new(_context_id),
             plugin: self.inner.clone().expect("plugin not configured"),
+            signs_body: OnceLock::new(),
+            content_digest: Mutex::new(None),
         })
     }
 }
@@ -164,16 +202,20 @@ impl Runtime for Plugin {
 pub struct UnauthorizedResponse {
     error: String,
     message: String,
+    /// Server clock in unix seconds, for clients to correct their skew.
+    server_time: u64,
 }
 
-fn unauthorized(error: &str) -> Error {
+fn rejection(code: u32, error: &str, message: &str, mut headers: Vec<(String, String)>) -> Error {
     let body = UnauthorizedResponse {
         error: error.to_owned(),
-        message: "Lacks valid authentication credentials for the requested resource".to_string(),
+        message: message.to_owned(),
+        server_time: now(),
     };
+    headers.push(("Content-Type".to_string(), "application/json".to_string()));
     Error::response(Response {
-        code: 429,
-        headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+        code,
+        headers,
         body: Some(
             serde_json::to_string(&body)
                 .expect("failed to serialize response")
@@ -183,6 +225,39 @@ fn unauthorized(error: &str) -> Error {
     })
 }
 
+const UNAUTHORIZED_MESSAGE: &str =
+    "Lacks valid authentication credentials for the requested resource";
+
+fn unauthorized(error: &str) -> Error {
+    rejection(401, error, UNAUTHORIZED_MESSAGE, vec![])
+}
+
+/// No credentials at all, tells the client what to sign for the route.
+fn challenge(found: &Setting, end_of_stream: bool) -> Error {
+    let accept_signature =
+        message_signature::accept_signature(&found.signed_components, !end_of_stream);
+    rejection(
+        401,
+        "Missing credentials",
+        UNAUTHORIZED_MESSAGE,
+        vec![(HEADER_ACCEPT_SIGNATURE_NAME.to_string(), accept_signature)],
+    )
+}
+
+/// A verified signature by a key that may not make the request, distinct
+/// from credentials that don't check out.
+fn denied(error: &str, message: &str) -> Error {
+    log::debug!("denied verified key: {}", error);
+    rejection(403, error, message, vec![])
+}
+
+fn key_rejected(rejection: KeyRejection) -> Error {
+    denied(
+        rejection.as_str(),
+        "The key is not valid for signing at this time",
+    )
+}
+
 fn forbidden(message: &str) -> Error {
     let body = serde_json::json!({ "message": message });
     Error::response(Response {
@@ -196,6 +271,11 @@ fn forbidden(message: &str) -> Error {
 pub struct Hook {
     ctx: Ctx,
     plugin: Arc<Inner>,
+    /// Whether the route signs a `Content-Digest` the request carries, so
+    /// the body has to be buffered to check it.
+    signs_body: OnceLock<bool>,
+    /// Signed `Content-Digest` waiting for the body.
+    content_digest: Mutex<Option<String>>,
 }
 
 impl Hook {
@@ -213,6 +293,12 @@ impl Hook {
             .ok_or_else(|| forbidden(&format!("missing header: {}", key)))
     }
 
+    fn get_optional_header(&self, key: &str) -> Result<Option<String>, Error> {
+        self.ctx
+            .get_http_request_header(key)
+            .map_err(|s| Error::status(&format!("failed to get header: {}", key), s))
+    }
+
     fn get_path(&self) -> Result<String, Error> {
         self.ctx
             .get_http_request_path()
@@ -227,16 +313,92 @@ fn now() -> u64 {
         .as_secs()
 }
 
-impl HttpHook for Hook {
-    fn filter_name() -> Option<&'static str> {
-        Some("auth")
+fn check_timestamp(found: &Setting, timestamp: u64) -> Result<(), Error> {
+    found
+        .timestamp_window
+        .check(timestamp, now())
+        .map_err(|e| match e {
+            TimestampError::Expired => unauthorized("Request timestamp is too old"),
+            TimestampError::InFuture => unauthorized("Request timestamp is in the future"),
+        })
+}
+
+fn missing(header: &str) -> Error {
+    unauthorized(&format!("Missing {} in header", header))
+}
+
+impl Hook {
+    /// Whether the body has to be checked against a Content-Digest, because
+    /// the route or the signature covers it.
+    fn signs_body(&self) -> bool {
+        let (Ok(Some(host)), Ok(path), Ok(Some(_))) = (
+            self.ctx.get_http_request_header(":authority"),
+            self.get_path(),
+            self.ctx.get_http_request_header(HEADER_CONTENT_DIGEST_NAME),
+        ) else {
+            return false;
+        };
+        let Some(found) = self.plugin.router.matches(&host, &path) else {
+            return false;
+        };
+        found.signed_components.contains(&Component::ContentDigest)
+            || self
+                .ctx
+                .get_http_request_header(HEADER_SIGNATURE_INPUT_NAME)
+                .ok()
+                .flatten()
+                .and_then(|input| SignatureInput::parse(&input).ok())
+                .is_some_and(|input| input.covers("content-digest"))
     }
 
-    async fn on_request_headers(
+    /// Builds the string the route expects to be signed, see [`SigningString`].
+    fn signing_string(
         &self,
-        _num_headers: usize,
-        _end_of_stream: bool,
-    ) -> Result<(), impl Into<Response>> {
+        found: &Setting,
+        host: &str,
+        path: &str,
+        timestamp: &str,
+        content_digest: Option<&str>,
+        end_of_stream: bool,
+    ) -> Result<SigningString, Error> {
+        let components = &found.signed_components;
+        let method = match components.contains(&Component::Method) {
+            true => Some(self.get_header(":method")?),
+            false => None,
+        };
+        let nonce = match components.contains(&Component::Nonce) {
+            true => Some(
+                self.get_header(HEADER_NONCE_NAME)
+                    .map_err(|_| missing(HEADER_NONCE_NAME))?,
+            ),
+            false => None,
+        };
+        let content_digest = match components.contains(&Component::ContentDigest) {
+            true if content_digest.is_none() && !end_of_stream => {
+                return Err(missing(HEADER_CONTENT_DIGEST_NAME))
+            }
+            true => content_digest,
+            false => None,
+        };
+        let values = [
+            (Component::Method, method.as_deref()),
+            (Component::Authority, Some(host)),
+            (Component::Path, Some(path)),
+            (Component::Timestamp, Some(timestamp)),
+            (Component::Nonce, nonce.as_deref()),
+            (Component::ContentDigest, content_digest),
+        ];
+        Ok(SigningString::new(values.into_iter().filter_map(
+            |(component, value)| {
+                value
+                    .filter(|_| components.contains(&component))
+                    .map(|value| (component, value))
+            },
+        )))
+    }
+
+    /// Verifies the request, returns the identity behind a verified signature.
+    async fn check(&self, end_of_stream: bool) -> Result<Option<Identity>, Error> {
         let addr = self.get_client_addr()?;
         let addr: SocketAddr = addr
             .parse()
@@ -247,7 +409,7 @@ impl HttpHook for Hook {
             .iter()
             .any(|cidr| cidr.contains(addr.ip()))
         {
-            return Ok(());
+            return Ok(None);
         }
 
         let host = self.get_header(":authority")?;
@@ -257,64 +419,1030 @@ impl HttpHook for Hook {
 
         let Some(found) = self.plugin.router.matches(&host, &path) else {
             log::debug!("no matched route found, skip auth check");
-            return Ok(());
+            return Ok(None);
         };
 
+        let verified = match self.get_optional_header(HEADER_SIGNATURE_INPUT_NAME)? {
+            Some(input) => {
+                self.verify_message_signature(&found, &host, &path, &input, end_of_stream)?
+            }
+            None if self.get_optional_header(HEADER_PUBLIC_KEY_NAME)?.is_none() => {
+                return Err(challenge(&found, end_of_stream))
+            }
+            None => self.verify_auth_headers(&found, &host, &path, end_of_stream)?,
+        };
+        let Some(verified) = verified else {
+            return Ok(None);
+        };
+
+        // Only tell the key holder that the key is no good.
+        self.check_key(&verified.public_key, verified.grant)?;
+        let method = self.get_header(":method")?;
+        if !verified
+            .grant
+            .scope
+            .allows(&method, relative_path(found.pattern(), &path))
+        {
+            return Err(denied(
+                "out_of_scope",
+                "The key is not allowed to make this request",
+            ));
+        }
+        if let Some((nonce, expires_at)) = &verified.nonce {
+            self.check_replay(&verified.public_key, nonce, *expires_at)?;
+        }
+        *self
+            .content_digest
+            .lock()
+            .expect("failed to lock content digest") = verified.content_digest;
+        Ok(Some(Identity {
+            grant: verified.grant.name.clone(),
+            key_fingerprint: verified.public_key.fingerprint(),
+        }))
+    }
 
-        let timestamp = self
+    /// Verifies the `X-Auth-*` headers.
+    fn verify_auth_headers<'a>(
+        &self,
+        found: &'a Setting,
+        host: &str,
+        path: &str,
+        end_of_stream: bool,
+    ) -> Result<Option<Verified<'a>>, Error> {
+        let raw_timestamp = self
             .get_header(HEADER_TIMESTAMP_NAME)
-            .map_err(|_| unauthorized(&format!("Missing {} in header", HEADER_TIMESTAMP_NAME)))?;
+            .map_err(|_| missing(HEADER_TIMESTAMP_NAME))?;
 
-        let timestamp = timestamp
+        let timestamp = raw_timestamp
             .parse::<u64>()
             .map_err(|_| unauthorized("Invalid timestamp"))?;
 
-        if timestamp + 60 < now() {
-            return Err(unauthorized("Request timestamp is too old"));
-        }
+        check_timestamp(found, timestamp)?;
 
         let public_key: PublicKey = self
             .get_header(HEADER_PUBLIC_KEY_NAME)
-            .map_err(|_| unauthorized(&format!("Missing {} in header", HEADER_PUBLIC_KEY_NAME)))?
+            .map_err(|_| missing(HEADER_PUBLIC_KEY_NAME))?
             .parse()
             .map_err(|e| unauthorized(&format!("Invalid public key: {}", e)))?;
 
-        let Setting::Grants(ref grants) = *found else {
-            return Ok(());
+        let Access::Grants(ref grants) = found.access else {
+            return Ok(None);
         };
 
-        match grants.get(&public_key) {
-            Some(trusted_name) => {
-                log::debug!("found public key in grants: {}, continue...", trusted_name);
+        let grant = match grants.get(&public_key) {
+            Some(grant) => {
+                log::debug!("found public key in grants: {}, continue...", grant.name);
+                grant
             }
             None => return Err(unauthorized("Public key not found in grants")),
-        }
+        };
 
-        let signature: Signature = self
+        let key_type = public_key.key_type();
+        let signature = self
             .get_header(HEADER_SIGNATURE_NAME)
-            .map_err(|_| unauthorized(&format!("Missing {} in header", HEADER_SIGNATURE_NAME)))?
-            .parse()
-            .map_err(|e| {
-                unauthorized(&format!(
-                    "Invalid signature, expect a DER format string: {}",
-                    e
-                ))
-            })?;
-
-        let factors = AuthFactors::new(&path, timestamp);
-        let auth_identity = AuthIdentity::new(&public_key, factors, &signature);
-        auth_identity
-            .verify()
-            .map_err(|e| unauthorized(&format!("Failed to verify signature: {}", e)))
+            .map_err(|_| missing(HEADER_SIGNATURE_NAME))?;
+        let signature = Signature::parse(key_type, &signature).map_err(|e| {
+            unauthorized(&format!(
+                "Invalid signature, expect {}: {}",
+                key_type.signature_format(),
+                e
+            ))
+        })?;
+
+        let content_digest = self.get_optional_header(HEADER_CONTENT_DIGEST_NAME)?;
+        let verified = self
+            .signing_string(
+                found,
+                host,
+                path,
+                &raw_timestamp,
+                content_digest.as_deref(),
+                end_of_stream,
+            )
+            .and_then(|signing_string| {
+                AuthIdentity::new(&public_key, signing_string, &signature)
+                    .verify()
+                    .map_err(|e| unauthorized(&format!("Failed to verify signature: {}", e)))
+            });
+        match verified {
+            Ok(()) => Ok(Some(Verified {
+                public_key,
+                grant,
+                nonce: Some((
+                    self.get_header(HEADER_NONCE_NAME)?,
+                    found.timestamp_window.expires_at(timestamp),
+                )),
+                content_digest: content_digest
+                    .filter(|_| found.signed_components.contains(&Component::ContentDigest)),
+            })),
+            Err(_) if found.legacy_signatures => {
+                let factors = AuthFactors::new(path, timestamp);
+                AuthIdentity::new(&public_key, factors, &signature)
+                    .verify()
+                    .map_err(|e| unauthorized(&format!("Failed to verify signature: {}", e)))?;
+                log::debug!("accepted legacy signature");
+                Ok(Some(Verified {
+                    public_key,
+                    grant,
+                    nonce: None,
+                    content_digest: None,
+                }))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Verifies `Signature-Input` and `Signature` (RFC 9421). Only the first
+    /// signature counts, by the grant whose `key_id` is its `keyid`.
+    fn verify_message_signature<'a>(
+        &self,
+        found: &'a Setting,
+        host: &str,
+        path: &str,
+        input: &str,
+        end_of_stream: bool,
+    ) -> Result<Option<Verified<'a>>, Error> {
+        let input = SignatureInput::parse(input).map_err(|e| {
+            unauthorized(&format!("Invalid {}: {}", HEADER_SIGNATURE_INPUT_NAME, e))
+        })?;
+        let created = input
+            .created
+            .ok_or_else(|| unauthorized("Missing created in signature parameters"))?;
+        check_timestamp(found, created)?;
+        if input.expires.is_some_and(|expires| now() > expires) {
+            return Err(unauthorized("Signature has expired"));
+        }
+
+        let Access::Grants(ref grants) = found.access else {
+            return Ok(None);
+        };
+
+        let key_id = input
+            .key_id
+            .as_deref()
+            .ok_or_else(|| unauthorized("Missing keyid in signature parameters"))?;
+        let Some((public_key, grant)) = grants.iter().find(|(_, grant)| grant.key_id == key_id)
+        else {
+            return Err(unauthorized("Key id not found in grants"));
+        };
+        log::debug!("found key id in grants: {}, continue...", grant.name);
+
+        let key_type = public_key.key_type();
+        if input
+            .alg
+            .as_deref()
+            .is_some_and(|alg| alg != key_type.algorithm())
+        {
+            return Err(unauthorized(&format!(
+                "Invalid alg, expect {}",
+                key_type.algorithm()
+            )));
+        }
+        let nonce = input
+            .nonce
+            .clone()
+            .ok_or_else(|| unauthorized("Missing nonce in signature parameters"))?;
+
+        let content_digest = self.get_optional_header(HEADER_CONTENT_DIGEST_NAME)?;
+        for component in &found.signed_components {
+            let required = match component {
+                // Only a body has to be digested.
+                Component::ContentDigest => content_digest.is_some() || !end_of_stream,
+                _ => true,
+            };
+            if required && !input.covers_component(*component, path.contains('?')) {
+                return Err(unauthorized(&format!(
+                    "Signature does not cover {}",
+                    component.name()
+                )));
+            }
+        }
+        let base = input.signature_base(|name| self.component_value(name, host, path))?;
+
+        let signature = self
+            .get_header(HEADER_MESSAGE_SIGNATURE_NAME)
+            .map_err(|_| missing(HEADER_MESSAGE_SIGNATURE_NAME))?;
+        let signature = message_signature::signature(&signature, &input.label).map_err(|e| {
+            unauthorized(&format!("Invalid {}: {}", HEADER_MESSAGE_SIGNATURE_NAME, e))
+        })?;
+        let signature = Signature::from_bytes(key_type, &signature).map_err(|e| {
+            unauthorized(&format!(
+                "Invalid signature, expect {}: {}",
+                key_type.algorithm(),
+                e
+            ))
+        })?;
+        message_signature::verify(public_key, &base, &signature)
+            .map_err(|e| unauthorized(&format!("Failed to verify signature: {}", e)))?;
+
+        Ok(Some(Verified {
+            public_key: *public_key,
+            grant,
+            nonce: Some((nonce, found.timestamp_window.expires_at(created))),
+            content_digest: content_digest.filter(|_| input.covers("content-digest")),
+        }))
+    }
+
+    /// Value of a component an RFC 9421 signature covers, derived ones as far
+    /// as they make sense for a request.
+    fn component_value(&self, name: &str, host: &str, path: &str) -> Result<String, Error> {
+        let (path_only, query) = path.split_once('?').unwrap_or((path, ""));
+        let value = match name {
+            "@method" => self.get_header(":method")?,
+            "@authority" => host.to_ascii_lowercase(),
+            "@scheme" => self.get_header(":scheme")?.to_ascii_lowercase(),
+            "@target-uri" => format!(
+                "{}://{}{}",
+                self.get_header(":scheme")?.to_ascii_lowercase(),
+                host.to_ascii_lowercase(),
+                path
+            ),
+            "@request-target" => path.to_string(),
+            "@path" => path_only.to_string(),
+            "@query" => format!("?{}", query),
+            name if name.starts_with('@') || name.starts_with(':') => {
+                return Err(unauthorized(&format!("Unsupported component {}", name)))
+            }
+            name => self
+                .get_optional_header(name)?
+                .ok_or_else(|| unauthorized(&format!("Missing covered header {}", name)))?
+                .trim()
+                .to_string(),
+        };
+        Ok(value)
+    }
+
+    fn check_key(&self, public_key: &PublicKey, grant: &Grant) -> Result<(), Error> {
+        grant.check(now()).map_err(key_rejected)?;
+        let revoked = self
+            .plugin
+            .revocations
+            .is_revoked(public_key)
+            .map_err(|s| Error::status("failed to read revoked keys", s))?;
+        match revoked {
+            true => Err(key_rejected(KeyRejection::Revoked)),
+            false => Ok(()),
+        }
+    }
+
+    /// Rejects a nonce `public_key` signed before, until `expires_at` when
+    /// the timestamp check takes over.
+    fn check_replay(
+        &self,
+        public_key: &PublicKey,
+        nonce: &str,
+        expires_at: u64,
+    ) -> Result<(), Error> {
+        if nonce.is_empty() || nonce.len() > MAX_NONCE_LEN {
+            return Err(unauthorized(&format!(
+                "Invalid nonce, expect 1 to {} characters",
+                MAX_NONCE_LEN
+            )));
+        }
+        let fresh = self
+            .plugin
+            .nonces
+            .record(&public_key.to_string(), nonce, expires_at, now())
+            .map_err(|e| Error::other("failed to record nonce", Box::new(e)))?;
+        match fresh {
+            true => Ok(()),
+            false => Err(unauthorized("Nonce has been used already")),
+        }
+    }
+}
+
+/// A signature that checks out, by a key the route grants.
+struct Verified<'a> {
+    public_key: PublicKey,
+    grant: &'a Grant,
+    /// Nonce to record until the timestamp expires, none for legacy
+    /// signatures.
+    nonce: Option<(String, u64)>,
+    /// Content-Digest the signature covers, to check the body against.
+    content_digest: Option<String>,
+}
+
+/// The grant whose key signed a request.
+#[derive(Debug)]
+struct Identity {
+    grant: String,
+    key_fingerprint: String,
+}
+
+impl Hook {
+    /// Hands the identity to later filters and access logs. Envoy keeps
+    /// properties set by Wasm as filter state, under `wasm.<name>`.
+    fn publish(&self, identity: &Identity) {
+        for (name, value) in [
+            (PROPERTY_GRANT, &identity.grant),
+            (PROPERTY_KEY_FINGERPRINT, &identity.key_fingerprint),
+        ] {
+            if let Err(e) = self.ctx.set_property(vec![name], Some(value.as_bytes())) {
+                log::warn!("failed to set property {}: {:?}", name, e);
+            }
+        }
+    }
+}
+
+impl HttpHook for Hook {
+    fn filter_name() -> Option<&'static str> {
+        Some("auth")
+    }
+
+    fn interest(&self) -> Interest {
+        let signs_body = *self.signs_body.get_or_init(|| self.signs_body());
+        Interest {
+            request_body: signs_body.then_some(MAX_SIGNED_BODY_BYTES),
+            ..Default::default()
+        }
+    }
+
+    async fn on_request_headers(
+        &self,
+        _num_headers: usize,
+        _end_of_stream: bool,
+    ) -> Result<impl Into<HeaderMutations>, impl Into<Response>> {
+        let identity = self.check(_end_of_stream).await?;
+        let identity_header = &self.plugin.identity_header;
+        // The credentials are meant for this filter only, and only it may
+        // vouch for an identity.
+        let mutations = HeaderMutations::default()
+            .remove(HEADER_PUBLIC_KEY_NAME)
+            .remove(HEADER_SIGNATURE_NAME)
+            .remove(HEADER_TIMESTAMP_NAME)
+            .remove(HEADER_NONCE_NAME)
+            .remove(HEADER_SIGNATURE_INPUT_NAME)
+            .remove(HEADER_MESSAGE_SIGNATURE_NAME)
+            .remove(identity_header);
+        let Some(identity) = identity else {
+            return Ok::<_, Error>(mutations);
+        };
+        self.publish(&identity);
+        Ok(mutations.set(identity_header, identity.grant))
+    }
+
+    async fn on_request_body(
+        &self,
+        body: Vec<u8>,
+    ) -> Result<impl Into<HeaderMutations>, impl Into<Response>> {
+        let content_digest = self
+            .content_digest
+            .lock()
+            .expect("failed to lock content digest")
+            .take();
+        match content_digest {
+            Some(header) => digest::verify(&header, &body).map_err(|e| {
+                unauthorized(&format!("Invalid {}: {}", HEADER_CONTENT_DIGEST_NAME, e))
+            }),
+            None => Ok::<_, Error>(()),
+        }
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use ed25519_dalek::Signer;
     use hex_literal::hex;
-    use secp256k1::{PublicKey, Secp256k1, SecretKey};
+    use p256::ecdsa::signature::hazmat::PrehashSigner;
+    use pow_host::{Host, Stream};
+    use pow_runtime::RuntimeBox;
+    use proxy_wasm::types::Action;
+    use secp256k1::{Keypair, Message, PublicKey, Secp256k1, SecretKey};
+
+    use crate::{
+        auth_identity::{AuthFactors, Component, KeyType, SigningString},
+        message_signature::{SignatureBase, SignatureInput},
+        now,
+        revocation::REVOKED_KEYS,
+        Plugin, MAX_NONCE_LEN,
+    };
+
+    const SECRET: [u8; 32] = [7; 32];
+    const KEY_TYPES: [KeyType; 4] = [
+        KeyType::Secp256k1,
+        KeyType::Schnorr,
+        KeyType::Ed25519,
+        KeyType::P256,
+    ];
+
+    fn keypair() -> Keypair {
+        Keypair::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&SECRET).unwrap())
+    }
+
+    fn ed25519() -> ed25519_dalek::SigningKey {
+        ed25519_dalek::SigningKey::from_bytes(&SECRET)
+    }
+
+    fn p256() -> p256::ecdsa::SigningKey {
+        p256::ecdsa::SigningKey::from_bytes(&SECRET.into()).unwrap()
+    }
+
+    /// Hex encoded public key, as in the grants.
+    fn public_key(key_type: KeyType) -> String {
+        match key_type {
+            KeyType::Secp256k1 => keypair().public_key().to_string(),
+            KeyType::Schnorr => keypair().x_only_public_key().0.to_string(),
+            KeyType::Ed25519 => hex::encode(ed25519().verifying_key()),
+            KeyType::P256 => hex::encode(p256().verifying_key().to_encoded_point(true)),
+        }
+    }
+
+    /// Public key as in `X-Auth-PublicKey`.
+    fn header_key(key_type: KeyType) -> String {
+        format!("{}:{}", key_type.name(), public_key(key_type))
+    }
+
+    fn sign(key_type: KeyType, message: impl Into<Message>) -> String {
+        let secp = Secp256k1::new();
+        let message = message.into();
+        match key_type {
+            KeyType::Secp256k1 => secp
+                .sign_ecdsa(&message, &keypair().secret_key())
+                .to_string(),
+            KeyType::Schnorr => secp
+                .sign_schnorr_no_aux_rand(&message, &keypair())
+                .to_string(),
+            KeyType::Ed25519 => hex::encode(ed25519().sign(message.as_ref()).to_bytes()),
+            KeyType::P256 => {
+                let signature: p256::ecdsa::Signature =
+                    p256().sign_prehash(message.as_ref()).unwrap();
+                hex::encode(signature.to_der())
+            }
+        }
+    }
+
+    /// Signature of RFC 9421, without base64.
+    fn sign_message(key_type: KeyType, base: &SignatureBase) -> Vec<u8> {
+        let secp = Secp256k1::new();
+        let digest: Message = base.clone().into();
+        match key_type {
+            KeyType::Secp256k1 => secp
+                .sign_ecdsa(&digest, &keypair().secret_key())
+                .serialize_compact()
+                .to_vec(),
+            KeyType::Schnorr => secp
+                .sign_schnorr_no_aux_rand(&digest, &keypair())
+                .serialize()
+                .to_vec(),
+            KeyType::Ed25519 => ed25519().sign(base.as_str().as_bytes()).to_bytes().to_vec(),
+            KeyType::P256 => {
+                let signature: p256::ecdsa::Signature =
+                    p256().sign_prehash(digest.as_ref()).unwrap();
+                signature.to_bytes().to_vec()
+            }
+        }
+    }
+
+    fn start() -> Host {
+        let host = Host::new(|context_id| {
+            Box::new(RuntimeBox::new(Plugin {
+                context_id,
+                inner: None,
+            }))
+        });
+        let grants: String = KEY_TYPES
+            .iter()
+            .map(|key_type| {
+                format!(
+                    "\n    - name: {0}\n      key_type: {0}\n      public_key: \"{1}\"",
+                    key_type.name(),
+                    public_key(*key_type)
+                )
+            })
+            .collect();
+        let grants = format!("grants:{}", grants);
+        let config = format!(
+            r#"
+identity_header: X-Wallet-Identity
+virtual_hosts:
+- host: example.com
+  routes:
+  - path: /api/*
+    {grants}
+  - path: /upload
+    signed_components: [method, authority, path, timestamp, nonce, content_digest]
+    {grants}
+  - path: /legacy
+    legacy_signatures: true
+    {grants}
+  - path: /expired
+    grants:
+    - name: Alice
+      public_key: "{secp256k1}"
+      not_after: 1700000000
+  - path: /pending
+    grants:
+    - name: Alice
+      public_key: "{secp256k1}"
+      not_before: 4000000000
+  - path: /bank/*
+    grants:
+    - name: Auditor
+      public_key: "{secp256k1}"
+      scope:
+        class: read
+    - name: Treasury
+      key_type: ed25519
+      key_id: treasury
+      public_key: "{ed25519}"
+      scope:
+        methods: [POST]
+        paths: ["/transfer", "/accounts/*/transfer"]
+"#,
+            ed25519 = public_key(KeyType::Ed25519),
+            secp256k1 = public_key(KeyType::Secp256k1),
+        );
+        assert!(host.start(None, config.as_bytes()));
+        host
+    }
+
+    static NONCES: AtomicUsize = AtomicUsize::new(0);
+
+    /// A request signed by `SECRET`, with a fresh nonce.
+    struct Request<'a> {
+        method: &'a str,
+        path: &'a str,
+        timestamp: String,
+        nonce: String,
+        content_digest: Option<&'a str>,
+        key_type: KeyType,
+        extra_headers: Vec<(&'a str, &'a str)>,
+        /// Components and parameters of an RFC 9421 signature to send
+        /// instead of the `X-Auth-*` headers, such as `("@method");alg="ed25519"`.
+        message_signature: Option<&'a str>,
+        key_id: Option<&'a str>,
+    }
+
+    impl<'a> Request<'a> {
+        fn new(method: &'a str, path: &'a str) -> Self {
+            Self {
+                method,
+                path,
+                timestamp: now().to_string(),
+                nonce: format!("nonce-{}", NONCES.fetch_add(1, Ordering::Relaxed)),
+                content_digest: None,
+                key_type: KeyType::Secp256k1,
+                extra_headers: vec![],
+                message_signature: None,
+                key_id: None,
+            }
+        }
+
+        fn message_signature(mut self, covered: &'a str) -> Self {
+            self.message_signature = Some(covered);
+            self
+        }
+
+        /// `Signature-Input` and `Signature` over `covered`, with the values
+        /// of `signed` if given.
+        fn message_signature_headers(
+            &self,
+            covered: &str,
+            signed: Option<&Request>,
+        ) -> (String, String) {
+            let key_id = self
+                .key_id
+                .map_or_else(|| header_key(self.key_type), str::to_string);
+            let input = format!(
+                "sig1={};created={};keyid=\"{}\";nonce=\"{}\"",
+                covered, self.timestamp, key_id, self.nonce
+            );
+            let signed = signed.unwrap_or(self);
+            let (path, query) = signed.path.split_once('?').unwrap_or((signed.path, ""));
+            let base = SignatureInput::parse(&input)
+                .expect("invalid signature input")
+                .signature_base(|name| {
+                    let value = match name {
+                        "@method" => signed.method.to_string(),
+                        "@authority" => "example.com".to_string(),
+                        "@path" => path.to_string(),
+                        "@query" => format!("?{}", query),
+                        "content-digest" => signed.content_digest.unwrap_or_default().to_string(),
+                        _ => return Err(name.to_string()),
+                    };
+                    Ok(value)
+                })
+                .expect("unexpected component");
+            let signature = sign_message(self.key_type, &base);
+            (input, format!("sig1=:{}:", STANDARD.encode(signature)))
+        }
+
+        fn content_digest(mut self, content_digest: &'a str) -> Self {
+            self.content_digest = Some(content_digest);
+            self
+        }
+
+        fn signing_string(&self) -> SigningString {
+            let mut components = vec![
+                (Component::Method, self.method),
+                (Component::Authority, "example.com"),
+                (Component::Path, self.path),
+                (Component::Timestamp, self.timestamp.as_str()),
+                (Component::Nonce, self.nonce.as_str()),
+            ];
+            if let Some(content_digest) = self.content_digest {
+                components.push((Component::ContentDigest, content_digest));
+            }
+            SigningString::new(components)
+        }
+
+        /// Sends the headers, with the signature over `signed` instead of
+        /// the request itself if given.
+        fn send(&self, host: &Host, signed: Option<Message>, end_of_stream: bool) -> Stream {
+            let message = signed.unwrap_or_else(|| self.signing_string().into());
+            let signature = sign(self.key_type, message);
+            let public_key = header_key(self.key_type);
+            let mut headers = vec![
+                (":method", self.method),
+                (":authority", "example.com"),
+                (":path", self.path),
+            ];
+            let message_signature = self
+                .message_signature
+                .map(|covered| self.message_signature_headers(covered, None));
+            match &message_signature {
+                Some((input, signature)) => headers.extend([
+                    ("Signature-Input", input.as_str()),
+                    ("Signature", signature.as_str()),
+                ]),
+                None => headers.extend([
+                    ("X-Auth-Timestamp", self.timestamp.as_str()),
+                    ("X-Auth-Nonce", self.nonce.as_str()),
+                    ("X-Auth-PublicKey", public_key.as_str()),
+                    ("X-Auth-Signature", signature.as_str()),
+                ]),
+            }
+            if let Some(content_digest) = self.content_digest {
+                headers.push(("Content-Digest", content_digest));
+            }
+            headers.extend(&self.extra_headers);
+            let stream = host.stream("10.0.0.1:1234");
+            assert_eq!(
+                stream.request_headers(&headers, end_of_stream),
+                Action::Pause
+            );
+            host.tick();
+            stream
+        }
+    }
+
+    fn status(stream: &Stream) -> Option<u32> {
+        stream.local_response().map(|r| r.status)
+    }
+
+    #[test]
+    fn binds_method_and_strips_credentials() {
+        let host = start();
+        let get = Request::new("GET", "/api/users?page=2");
+        let stream = get.send(&host, None, true);
+        assert!(stream.request_resumed());
+        assert_eq!(stream.request_header("X-Auth-Signature"), None);
+        assert_eq!(stream.request_header("X-Auth-Nonce"), None);
+
+        let mut delete = Request::new("DELETE", "/api/users?page=2");
+        delete.nonce = "reused".to_string();
+        let mut signed = Request::new("GET", "/api/users?page=2");
+        signed.nonce = "reused".to_string();
+        let stream = delete.send(&host, Some(signed.signing_string().into()), true);
+        assert_eq!(status(&stream), Some(401));
+    }
+
+    #[test]
+    fn verifies_content_digest() {
+        let host = start();
+        let body = b"{\"hello\": \"world\"}\n";
+        let digest = "sha-256=:RK/0qy18MlBSVnWgjwz6lZEWjP/lF5HF9bvEF8FabDg=:";
 
-    use crate::auth_identity::AuthFactors;
+        let stream = Request::new("POST", "/upload")
+            .content_digest(digest)
+            .send(&host, None, false);
+        assert!(!stream.request_resumed());
+        stream.request_body(body, true);
+        host.tick();
+        assert!(stream.request_resumed());
+
+        let stream = Request::new("POST", "/upload")
+            .content_digest(digest)
+            .send(&host, None, false);
+        stream.request_body(b"{}", true);
+        host.tick();
+        assert_eq!(status(&stream), Some(401));
+
+        // A body needs a digest, a request without one doesn't.
+        let stream = Request::new("POST", "/upload").send(&host, None, false);
+        assert_eq!(status(&stream), Some(401));
+        let stream = Request::new("GET", "/upload").send(&host, None, true);
+        assert!(stream.request_resumed());
+    }
+
+    #[test]
+    fn accepts_legacy_signatures_behind_flag() {
+        let host = start();
+        let legacy = AuthFactors::new("/legacy", now());
+        let stream = Request::new("GET", "/legacy").send(&host, Some(legacy.into()), true);
+        assert!(stream.request_resumed());
+
+        let legacy = AuthFactors::new("/api/users", now());
+        let stream = Request::new("GET", "/api/users").send(&host, Some(legacy.into()), true);
+        assert_eq!(status(&stream), Some(401));
+    }
+
+    #[test]
+    fn accepts_every_key_type() {
+        let host = start();
+        for key_type in KEY_TYPES {
+            let mut request = Request::new("GET", "/api/users");
+            request.key_type = key_type;
+            let stream = request.send(&host, None, true);
+            assert!(stream.request_resumed(), "{:?} rejected", key_type);
+        }
+
+        // An ECDSA signature under the x-only key, which goes without prefix.
+        let mut request = Request::new("GET", "/api/users");
+        request.key_type = KeyType::Schnorr;
+        let ecdsa = sign(KeyType::Secp256k1, request.signing_string());
+        let public_key = public_key(KeyType::Schnorr);
+        let headers = [
+            (":method", "GET"),
+            (":authority", "example.com"),
+            (":path", "/api/users"),
+            ("X-Auth-Timestamp", request.timestamp.as_str()),
+            ("X-Auth-Nonce", request.nonce.as_str()),
+            ("X-Auth-PublicKey", public_key.as_str()),
+            ("X-Auth-Signature", ecdsa.as_str()),
+        ];
+        let stream = host.stream("10.0.0.1:1234");
+        stream.request_headers(&headers, true);
+        host.tick();
+        assert_eq!(status(&stream), Some(401));
+    }
+
+    fn error(stream: &Stream) -> serde_json::Value {
+        let body = stream.local_response().expect("missing response").body;
+        serde_json::from_slice(&body).expect("malformed response")
+    }
+
+    #[test]
+    fn rejects_keys_out_of_their_window() {
+        let host = start();
+        let stream = Request::new("GET", "/expired").send(&host, None, true);
+        assert_eq!(status(&stream), Some(403));
+        assert_eq!(error(&stream)["error"], "key_expired");
+
+        let stream = Request::new("GET", "/pending").send(&host, None, true);
+        assert_eq!(status(&stream), Some(403));
+        assert_eq!(error(&stream)["error"], "key_not_yet_valid");
+
+        // Nobody but the key holder learns about it.
+        let other = AuthFactors::new("/expired", now());
+        let stream = Request::new("GET", "/expired").send(&host, Some(other.into()), true);
+        assert_eq!(status(&stream), Some(401));
+    }
+
+    #[test]
+    fn rejects_revoked_keys() {
+        let host = start();
+        let revoked = serde_json::json!([header_key(KeyType::Ed25519)]).to_string();
+        host.set_shared_data(REVOKED_KEYS, revoked.as_bytes());
+
+        let mut request = Request::new("GET", "/api/users");
+        request.key_type = KeyType::Ed25519;
+        let stream = request.send(&host, None, true);
+        assert_eq!(status(&stream), Some(403));
+        assert_eq!(error(&stream)["error"], "key_revoked");
+        assert!(Request::new("GET", "/api/users")
+            .send(&host, None, true)
+            .request_resumed());
+
+        // A malformed update keeps the previous list.
+        host.set_shared_data(REVOKED_KEYS, b"[\"rsa:00\"]");
+        let mut request = Request::new("GET", "/api/users");
+        request.key_type = KeyType::Ed25519;
+        assert_eq!(status(&request.send(&host, None, true)), Some(403));
+
+        host.set_shared_data(REVOKED_KEYS, b"[]");
+        let mut request = Request::new("GET", "/api/users");
+        request.key_type = KeyType::Ed25519;
+        assert!(request.send(&host, None, true).request_resumed());
+    }
+
+    #[test]
+    fn enforces_scopes() {
+        let host = start();
+        let stream = Request::new("GET", "/bank/accounts?page=2").send(&host, None, true);
+        assert!(stream.request_resumed());
+        let stream = Request::new("POST", "/bank/transfer").send(&host, None, true);
+        assert_eq!(status(&stream), Some(403));
+        assert_eq!(error(&stream)["error"], "out_of_scope");
+
+        let treasury = |method, path| {
+            let mut request = Request::new(method, path);
+            request.key_type = KeyType::Ed25519;
+            request.send(&host, None, true)
+        };
+        assert!(treasury("POST", "/bank/transfer").request_resumed());
+        assert!(treasury("POST", "/bank/accounts/42/transfer").request_resumed());
+        assert_eq!(
+            status(&treasury("POST", "/bank/accounts/42/close")),
+            Some(403)
+        );
+        assert_eq!(status(&treasury("GET", "/bank/accounts")), Some(403));
+
+        // Scopes only apply to verified signatures.
+        let other = AuthFactors::new("/bank/transfer", now());
+        let stream = Request::new("POST", "/bank/transfer").send(&host, Some(other.into()), true);
+        assert_eq!(status(&stream), Some(401));
+    }
+
+    #[test]
+    fn forwards_identity() {
+        let host = start();
+        let spoofed = ("X-Wallet-Identity", "Treasury");
+        let mut request = Request::new("GET", "/api/users");
+        request.key_type = KeyType::P256;
+        request.extra_headers.push(spoofed);
+        let stream = request.send(&host, None, true);
+        assert!(stream.request_resumed());
+        assert_eq!(
+            stream.request_header("X-Wallet-Identity").as_deref(),
+            Some("p256")
+        );
+        assert_eq!(
+            stream.property(&["pow_auth.grant"]).as_deref(),
+            Some(&b"p256"[..])
+        );
+        let fingerprint = header_key(KeyType::P256)
+            .parse::<crate::auth_identity::PublicKey>()
+            .unwrap()
+            .fingerprint();
+        assert_eq!(
+            stream.property(&["pow_auth.key_fingerprint"]),
+            Some(fingerprint.into_bytes())
+        );
+
+        // Requests that pass without a signature don't keep a spoofed copy.
+        let stream = host.stream("10.0.0.1:1234");
+        stream.request_headers(
+            &[
+                (":method", "GET"),
+                (":authority", "other.com"),
+                (":path", "/"),
+                spoofed,
+            ],
+            true,
+        );
+        host.tick();
+        assert!(stream.request_resumed());
+        assert_eq!(stream.request_header("X-Wallet-Identity"), None);
+        assert_eq!(stream.property(&["pow_auth.grant"]), None);
+    }
+
+    const COVERED: &str = r#"("@method" "@authority" "@path" "@query")"#;
+
+    #[test]
+    fn verifies_message_signatures() {
+        let host = start();
+        for key_type in KEY_TYPES {
+            let mut request = Request::new("GET", "/api/users?page=2").message_signature(COVERED);
+            request.key_type = key_type;
+            let stream = request.send(&host, None, true);
+            assert!(stream.request_resumed(), "{:?} rejected", key_type);
+            assert_eq!(stream.request_header("Signature-Input"), None);
+            assert_eq!(stream.request_header("Signature"), None);
+            assert_eq!(
+                stream.request_header("X-Wallet-Identity").as_deref(),
+                Some(key_type.name())
+            );
+            // The nonce is gone with the first request.
+            assert_eq!(status(&request.send(&host, None, true)), Some(401));
+        }
+
+        let treasury = |covered| {
+            let mut request = Request::new("POST", "/bank/transfer").message_signature(covered);
+            request.key_type = KeyType::Ed25519;
+            request.key_id = Some("treasury");
+            request.send(&host, None, true)
+        };
+        assert!(treasury(COVERED).request_resumed());
+        assert!(treasury(r#"("@method" "@authority" "@path");alg="ed25519""#).request_resumed());
+        let stream = treasury(r#"("@method" "@authority" "@path");alg="ecdsa-p256-sha256""#);
+        assert_eq!(status(&stream), Some(401));
+
+        // Every component of the route has to be covered, the query too.
+        let request = Request::new("GET", "/api/users?page=2")
+            .message_signature(r#"("@method" "@authority" "@path")"#);
+        let stream = request.send(&host, None, true);
+        assert_eq!(status(&stream), Some(401));
+        assert_eq!(error(&stream)["error"], "Signature does not cover path");
+
+        // Signed for another method.
+        let delete = Request::new("DELETE", "/api/users").message_signature(COVERED);
+        let mut get = Request::new("GET", "/api/users");
+        get.nonce = delete.nonce.clone();
+        let (input, signature) = delete.message_signature_headers(COVERED, Some(&get));
+        let stream = host.stream("10.0.0.1:1234");
+        stream.request_headers(
+            &[
+                (":method", "DELETE"),
+                (":authority", "example.com"),
+                (":path", "/api/users"),
+                ("Signature-Input", &input),
+                ("Signature", &signature),
+            ],
+            true,
+        );
+        host.tick();
+        assert_eq!(status(&stream), Some(401));
+    }
+
+    #[test]
+    fn verifies_message_signature_digest() {
+        let host = start();
+        let digest = "sha-256=:RK/0qy18MlBSVnWgjwz6lZEWjP/lF5HF9bvEF8FabDg=:";
+        let stream = Request::new("POST", "/upload")
+            .content_digest(digest)
+            .message_signature(r#"("@method" "@authority" "@path" "@query" "content-digest")"#)
+            .send(&host, None, false);
+        stream.request_body(b"{\"hello\": \"world\"}\n", true);
+        host.tick();
+        assert!(stream.request_resumed());
+
+        let stream = Request::new("POST", "/upload")
+            .content_digest(digest)
+            .message_signature(COVERED)
+            .send(&host, None, false);
+        assert_eq!(status(&stream), Some(401));
+
+        // A covered digest is checked on routes that don't require one too.
+        let covered = r#"("@method" "@authority" "@path" "@query" "content-digest")"#;
+        for (body, resumed) in [(&b"{\"hello\": \"world\"}\n"[..], true), (b"{}", false)] {
+            let stream = Request::new("POST", "/api/users")
+                .content_digest(digest)
+                .message_signature(covered)
+                .send(&host, None, false);
+            assert!(!stream.request_resumed());
+            stream.request_body(body, true);
+            host.tick();
+            assert_eq!(stream.request_resumed(), resumed);
+            assert_eq!(status(&stream), (!resumed).then_some(401));
+        }
+    }
+
+    #[test]
+    fn challenges_missing_credentials() {
+        let host = start();
+        let stream = host.stream("10.0.0.1:1234");
+        stream.request_headers(
+            &[
+                (":method", "POST"),
+                (":authority", "example.com"),
+                (":path", "/upload"),
+            ],
+            false,
+        );
+        host.tick();
+        let response = stream.local_response().expect("missing response");
+        assert_eq!(response.status, 401);
+        let accept_signature = response
+            .headers
+            .iter()
+            .find(|(name, _)| name == "Accept-Signature")
+            .map(|(_, value)| value.as_str());
+        assert_eq!(
+            accept_signature,
+            Some(
+                r#"sig1=("@method" "@authority" "@path" "@query" "content-digest");created;nonce"#
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_replayed_nonce() {
+        let host = start();
+        let request = Request::new("GET", "/api/users");
+        assert!(request.send(&host, None, true).request_resumed());
+        // Seen nonces live in shared data, where every worker looks them up.
+        let stream = request.send(&host, None, true);
+        assert_eq!(status(&stream), Some(401));
+        assert!(host
+            .shared_data(&format!(
+                "pow_auth.nonce:{}:{}",
+                header_key(KeyType::Secp256k1),
+                request.nonce
+            ))
+            .is_some());
+
+        assert!(Request::new("GET", "/api/users")
+            .send(&host, None, true)
+            .request_resumed());
+
+        let mut request = Request::new("GET", "/api/users");
+        request.nonce = "n".repeat(MAX_NONCE_LEN + 1);
+        assert_eq!(status(&request.send(&host, None, true)), Some(401));
+    }
 
     #[test]
     fn test() {
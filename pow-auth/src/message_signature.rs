@@ -0,0 +1,522 @@
+use std::{collections::BTreeSet, fmt};
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use secp256k1::Message;
+use sha2::{Digest, Sha256};
+
+use crate::auth_identity::{AuthIdentity, Component, CryptoError, PublicKey, Signature};
+
+/// Label of the signature `Accept-Signature` asks for.
+const LABEL: &str = "sig1";
+
+/// A bare item of a structured field (RFC 8941), decimals aside.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Item {
+    Integer(i64),
+    String(String),
+    Token(String),
+    Bytes(Vec<u8>),
+    Boolean(bool),
+}
+
+type Parameters = Vec<(String, Item)>;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Member {
+    Item(Item, Parameters),
+    InnerList(Vec<(Item, Parameters)>, Parameters),
+}
+
+impl fmt::Display for Item {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Item::Integer(n) => write!(f, "{}", n),
+            Item::String(s) => write!(f, "\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\"")),
+            Item::Token(t) => f.write_str(t),
+            Item::Bytes(b) => write!(f, ":{}:", STANDARD.encode(b)),
+            Item::Boolean(b) => write!(f, "?{}", *b as u8),
+        }
+    }
+}
+
+fn serialize_parameters(parameters: &Parameters, out: &mut String) {
+    for (key, value) in parameters {
+        out.push(';');
+        out.push_str(key);
+        if *value != Item::Boolean(true) {
+            out.push('=');
+            out.push_str(&value.to_string());
+        }
+    }
+}
+
+fn serialize_inner_list(items: &[(Item, Parameters)], parameters: &Parameters) -> String {
+    let mut out = String::from("(");
+    for (i, (item, item_parameters)) in items.iter().enumerate() {
+        if i > 0 {
+            out.push(' ');
+        }
+        out.push_str(&item.to_string());
+        serialize_parameters(item_parameters, &mut out);
+    }
+    out.push(')');
+    serialize_parameters(parameters, &mut out);
+    out
+}
+
+/// Parses the structured field dictionaries of `Signature-Input` and
+/// `Signature`, which only hold ASCII.
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn new(input: &'a str) -> Self {
+        Self {
+            input: input.trim_matches([' ', '\t']),
+            pos: 0,
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.input.as_bytes().get(self.pos).copied()
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        let found = self.peek() == Some(c);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn expect(&mut self, c: u8) -> Result<(), String> {
+        match self.eat(c) {
+            true => Ok(()),
+            false => Err(self.unexpected()),
+        }
+    }
+
+    fn skip_while(&mut self, f: impl Fn(u8) -> bool) -> &'a str {
+        let start = self.pos;
+        while self.peek().is_some_and(&f) {
+            self.pos += 1;
+        }
+        &self.input[start..self.pos]
+    }
+
+    fn unexpected(&self) -> String {
+        match self.input[self.pos..].chars().next() {
+            Some(c) => format!("unexpected {:?} at {}", c, self.pos),
+            None => "unexpected end".to_string(),
+        }
+    }
+
+    fn dictionary(mut self) -> Result<Vec<(String, Member)>, String> {
+        let mut members: Vec<(String, Member)> = vec![];
+        while self.peek().is_some() {
+            let key = self.key()?;
+            let member = match self.eat(b'=') {
+                true if self.peek() == Some(b'(') => {
+                    let items = self.inner_list()?;
+                    Member::InnerList(items, self.parameters()?)
+                }
+                true => {
+                    let item = self.bare_item()?;
+                    Member::Item(item, self.parameters()?)
+                }
+                false => Member::Item(Item::Boolean(true), self.parameters()?),
+            };
+            // The last of duplicate keys wins.
+            members.retain(|(k, _)| *k != key);
+            members.push((key, member));
+            self.skip_while(|c| c == b' ' || c == b'\t');
+            if self.peek().is_none() {
+                break;
+            }
+            self.expect(b',')?;
+            self.skip_while(|c| c == b' ' || c == b'\t');
+            if self.peek().is_none() {
+                return Err("trailing comma".to_string());
+            }
+        }
+        Ok(members)
+    }
+
+    fn key(&mut self) -> Result<String, String> {
+        if !self
+            .peek()
+            .is_some_and(|c| c.is_ascii_lowercase() || c == b'*')
+        {
+            return Err(self.unexpected());
+        }
+        let key = self
+            .skip_while(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || b"_-.*".contains(&c));
+        Ok(key.to_string())
+    }
+
+    fn inner_list(&mut self) -> Result<Vec<(Item, Parameters)>, String> {
+        self.expect(b'(')?;
+        let mut items = vec![];
+        loop {
+            self.skip_while(|c| c == b' ');
+            if self.eat(b')') {
+                return Ok(items);
+            }
+            let item = self.bare_item()?;
+            items.push((item, self.parameters()?));
+            if !matches!(self.peek(), Some(b' ' | b')')) {
+                return Err(self.unexpected());
+            }
+        }
+    }
+
+    fn parameters(&mut self) -> Result<Parameters, String> {
+        let mut parameters: Parameters = vec![];
+        while self.eat(b';') {
+            self.skip_while(|c| c == b' ');
+            let key = self.key()?;
+            let value = match self.eat(b'=') {
+                true => self.bare_item()?,
+                false => Item::Boolean(true),
+            };
+            parameters.retain(|(k, _)| *k != key);
+            parameters.push((key, value));
+        }
+        Ok(parameters)
+    }
+
+    fn bare_item(&mut self) -> Result<Item, String> {
+        match self.peek() {
+            Some(b'"') => self.string(),
+            Some(b':') => self.bytes(),
+            Some(b'?') => {
+                self.pos += 1;
+                let value = match self.peek() {
+                    Some(b'0') => false,
+                    Some(b'1') => true,
+                    _ => return Err(self.unexpected()),
+                };
+                self.pos += 1;
+                Ok(Item::Boolean(value))
+            }
+            Some(c) if c == b'-' || c.is_ascii_digit() => self.integer(),
+            Some(c) if c.is_ascii_alphabetic() || c == b'*' => {
+                let token = self
+                    .skip_while(|c| c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~:/".contains(&c));
+                Ok(Item::Token(token.to_string()))
+            }
+            _ => Err(self.unexpected()),
+        }
+    }
+
+    fn integer(&mut self) -> Result<Item, String> {
+        let start = self.pos;
+        self.eat(b'-');
+        let digits = self.skip_while(|c| c.is_ascii_digit());
+        if digits.is_empty() || digits.len() > 15 || self.peek() == Some(b'.') {
+            return Err(format!("unsupported number at {}", start));
+        }
+        self.input[start..self.pos]
+            .parse()
+            .map(Item::Integer)
+            .map_err(|e| e.to_string())
+    }
+
+    fn string(&mut self) -> Result<Item, String> {
+        self.expect(b'"')?;
+        let mut string = String::new();
+        loop {
+            match self.peek() {
+                Some(b'"') => {
+                    self.pos += 1;
+                    return Ok(Item::String(string));
+                }
+                Some(b'\\') => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some(c @ (b'"' | b'\\')) => string.push(c as char),
+                        _ => return Err(self.unexpected()),
+                    }
+                }
+                Some(c @ 0x20..=0x7e) => string.push(c as char),
+                _ => return Err(self.unexpected()),
+            }
+            self.pos += 1;
+        }
+    }
+
+    fn bytes(&mut self) -> Result<Item, String> {
+        self.expect(b':')?;
+        let encoded = self.skip_while(|c| c.is_ascii_alphanumeric() || b"+/=".contains(&c));
+        self.expect(b':')?;
+        STANDARD
+            .decode(encoded)
+            .map(Item::Bytes)
+            .map_err(|e| e.to_string())
+    }
+}
+
+/// The first signature of a `Signature-Input` header (RFC 9421), such as
+/// `sig1=("@method" "@authority" "@path" "@query");created=1618884473;keyid="alice";nonce="b3k2"`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignatureInput {
+    /// Key of the signature in the `Signature` header.
+    pub label: String,
+    /// Names of the covered components, in order.
+    pub components: Vec<String>,
+    pub created: Option<u64>,
+    pub expires: Option<u64>,
+    pub nonce: Option<String>,
+    pub key_id: Option<String>,
+    pub alg: Option<String>,
+    /// The value of `@signature-params`.
+    params: String,
+}
+
+impl SignatureInput {
+    pub fn parse(header: &str) -> Result<Self, String> {
+        let (label, member) = Parser::new(header)
+            .dictionary()?
+            .into_iter()
+            .next()
+            .ok_or("no signature")?;
+        let Member::InnerList(items, parameters) = member else {
+            return Err(format!("{} is not an inner list", label));
+        };
+        let mut components: Vec<String> = vec![];
+        for (item, item_parameters) in &items {
+            let Item::String(name) = item else {
+                return Err(format!("component {} is not a string", item));
+            };
+            if !item_parameters.is_empty() {
+                return Err(format!(
+                    "parameters of component {} are not supported",
+                    name
+                ));
+            }
+            if components.contains(name) {
+                return Err(format!("component {} is covered twice", name));
+            }
+            components.push(name.clone());
+        }
+        let mut input = SignatureInput {
+            params: serialize_inner_list(&items, &parameters),
+            label,
+            components,
+            created: None,
+            expires: None,
+            nonce: None,
+            key_id: None,
+            alg: None,
+        };
+        for (key, value) in parameters {
+            match (key.as_str(), value) {
+                ("created", Item::Integer(n)) if n >= 0 => input.created = Some(n as u64),
+                ("expires", Item::Integer(n)) if n >= 0 => input.expires = Some(n as u64),
+                ("nonce", Item::String(s)) => input.nonce = Some(s),
+                ("keyid", Item::String(s)) => input.key_id = Some(s),
+                ("alg", Item::String(s)) => input.alg = Some(s),
+                ("created" | "expires" | "nonce" | "keyid" | "alg", value) => {
+                    return Err(format!("invalid {}: {}", key, value))
+                }
+                // Such as tag, which is up to the application.
+                _ => {}
+            }
+        }
+        Ok(input)
+    }
+
+    pub fn covers(&self, name: &str) -> bool {
+        self.components.iter().any(|c| c == name)
+    }
+
+    /// Whether the signature covers `component` of a route, for a request
+    /// whose path has a query if `has_query`.
+    pub fn covers_component(&self, component: Component, has_query: bool) -> bool {
+        match component {
+            Component::Method => self.covers("@method"),
+            Component::Authority => self.covers("@authority") || self.covers("@target-uri"),
+            Component::Path => {
+                self.covers("@request-target")
+                    || self.covers("@target-uri")
+                    || (self.covers("@path") && (self.covers("@query") || !has_query))
+            }
+            Component::Timestamp => self.created.is_some(),
+            Component::Nonce => self.nonce.is_some(),
+            Component::ContentDigest => self.covers("content-digest"),
+        }
+    }
+
+    /// Builds the signature base from `value`, which gives the value of a
+    /// covered component by its name.
+    pub fn signature_base<E>(
+        &self,
+        mut value: impl FnMut(&str) -> Result<String, E>,
+    ) -> Result<SignatureBase, E> {
+        let mut base = String::new();
+        for name in &self.components {
+            base.push_str(&format!("\"{}\": {}\n", name, value(name)?));
+        }
+        base.push_str(&format!("\"@signature-params\": {}", self.params));
+        Ok(SignatureBase(base))
+    }
+}
+
+/// Looks up the signature labelled `label` in a `Signature` header.
+pub fn signature(header: &str, label: &str) -> Result<Vec<u8>, String> {
+    match Parser::new(header)
+        .dictionary()?
+        .into_iter()
+        .find(|(key, _)| key == label)
+    {
+        Some((_, Member::Item(Item::Bytes(bytes), _))) => Ok(bytes),
+        Some(_) => Err(format!("{} is not a byte sequence", label)),
+        None => Err(format!("no signature labelled {}", label)),
+    }
+}
+
+/// `Accept-Signature` asking for a signature over `components` of a route,
+/// the body digest only if the request has a `body`.
+pub fn accept_signature(components: &BTreeSet<Component>, body: bool) -> String {
+    let mut covered = vec![];
+    let mut params = String::new();
+    for component in components {
+        match component {
+            Component::Method => covered.push("\"@method\""),
+            Component::Authority => covered.push("\"@authority\""),
+            Component::Path => covered.extend(["\"@path\"", "\"@query\""]),
+            Component::ContentDigest if body => covered.push("\"content-digest\""),
+            Component::ContentDigest => {}
+            Component::Timestamp => params.push_str(";created"),
+            Component::Nonce => params.push_str(";nonce"),
+        }
+    }
+    format!("{}=({}){}", LABEL, covered.join(" "), params)
+}
+
+/// The string an RFC 9421 signature is over.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignatureBase(String);
+
+impl SignatureBase {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<SignatureBase> for Message {
+    fn from(value: SignatureBase) -> Self {
+        Message::from_digest(Sha256::digest(value.0.as_bytes()).into())
+    }
+}
+
+/// Verifies `signature` over `base`. Ed25519 signs the base itself as the
+/// RFC has it, every other key type its SHA-256.
+pub fn verify(
+    public_key: &PublicKey,
+    base: &SignatureBase,
+    signature: &Signature,
+) -> Result<(), CryptoError> {
+    match (public_key, signature) {
+        (PublicKey::Ed25519(key), Signature::Ed25519(signature)) => {
+            Ok(key.verify_strict(base.0.as_bytes(), signature)?)
+        }
+        _ => AuthIdentity::new(public_key, base.clone(), signature).verify(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+
+    use super::*;
+    use crate::auth_identity::KeyType;
+
+    #[test]
+    fn parses_signature_input() {
+        let input = SignatureInput::parse(
+            r#"sig1=("@method" "@path" "@query");created=1618884473;keyid="alice";nonce="b3k2";tag="app", sig2=("@method")"#,
+        )
+        .expect("failed to parse signature input");
+        assert_eq!(input.label, "sig1");
+        assert_eq!(input.components, ["@method", "@path", "@query"]);
+        assert_eq!(input.created, Some(1618884473));
+        assert_eq!(input.expires, None);
+        assert_eq!(input.nonce.as_deref(), Some("b3k2"));
+        assert_eq!(input.key_id.as_deref(), Some("alice"));
+        assert!(input.covers_component(Component::Path, true));
+        assert!(!input.covers_component(Component::Authority, false));
+        assert_eq!(
+            input.params,
+            r#"("@method" "@path" "@query");created=1618884473;keyid="alice";nonce="b3k2";tag="app""#
+        );
+
+        for invalid in [
+            r#"sig1=:AAAA:"#,
+            r#"sig1=("@method";req)"#,
+            r#"sig1=("@method" "@method")"#,
+            r#"sig1=("@method");created=1.5"#,
+            r#"sig1=("@method");created=-1"#,
+            r#"sig1=("@method");keyid=alice"#,
+            r#"sig1=("@method"),"#,
+            "",
+        ] {
+            assert!(SignatureInput::parse(invalid).is_err(), "{}", invalid);
+        }
+    }
+
+    #[test]
+    fn verifies_rfc_example() {
+        // The Ed25519 example of RFC 9421, B.2.6.
+        let input = SignatureInput::parse(
+            r#"sig-b26=("date" "@method" "@path" "@authority" "content-type" "content-length");created=1618884473;keyid="test-key-ed25519""#,
+        )
+        .expect("failed to parse signature input");
+        let base = input
+            .signature_base(|name| {
+                let value = match name {
+                    "date" => "Tue, 20 Apr 2021 02:07:55 GMT",
+                    "@method" => "POST",
+                    "@path" => "/foo",
+                    "@authority" => "example.com",
+                    "content-type" => "application/json",
+                    "content-length" => "18",
+                    _ => return Err(name.to_string()),
+                };
+                Ok(value.to_string())
+            })
+            .expect("failed to build signature base");
+        let bytes = signature(
+            "sig-b26=:wqcAqbmYJ2ji2glfAMaRy4gruYYnx2nEFN2HN6jrnDnQCK1u02Gb04v9EDgwUPiu4A0w6vuQv5lIp5WPpBKRCw==:",
+            &input.label,
+        )
+        .expect("failed to parse signature");
+        let key = URL_SAFE_NO_PAD
+            .decode("JrQLj5P_89iXES9-vFgrIy29clF9CC_oPPsw3c5D0bs")
+            .unwrap();
+        let key = PublicKey::parse(KeyType::Ed25519, &hex::encode(key)).unwrap();
+        let signature = Signature::from_bytes(KeyType::Ed25519, &bytes).unwrap();
+        verify(&key, &base, &signature).expect("failed to verify RFC example");
+    }
+
+    #[test]
+    fn asks_for_route_components() {
+        let components = BTreeSet::from([
+            Component::Method,
+            Component::Path,
+            Component::Timestamp,
+            Component::Nonce,
+            Component::ContentDigest,
+        ]);
+        assert_eq!(
+            accept_signature(&components, false),
+            r#"sig1=("@method" "@path" "@query");created;nonce"#
+        );
+        assert_eq!(
+            accept_signature(&components, true),
+            r#"sig1=("@method" "@path" "@query" "content-digest");created;nonce"#
+        );
+    }
+}
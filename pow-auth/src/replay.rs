@@ -0,0 +1,43 @@
+use std::time::Duration;
+
+use pow_runtime::kv_store::{Error, ExpiringKVStore};
+
+/// Nonces of verified signatures, kept in shared data until their timestamp
+/// expires so that every worker rejects a replay.
+pub struct SeenNonces {
+    store: ExpiringKVStore<u64>,
+}
+
+impl SeenNonces {
+    pub fn new(context_id: u32) -> Self {
+        Self {
+            store: ExpiringKVStore::new(context_id, "pow_auth.nonce:"),
+        }
+    }
+
+    /// Records `nonce` for `public_key` until `expires_at`, returns false if
+    /// it was recorded already.
+    pub fn record(
+        &self,
+        public_key: &str,
+        nonce: &str,
+        expires_at: u64,
+        now: u64,
+    ) -> Result<bool, Error> {
+        let key = format!("{}:{}", public_key, nonce);
+        let mut fresh = false;
+        // Entries past their expiry may linger until the next gc.
+        self.store.update(&key, |seen| {
+            fresh = !matches!(seen, Some(seen) if seen > now);
+            match fresh {
+                true => expires_at,
+                false => seen.unwrap_or(expires_at),
+            }
+        })?;
+        if fresh {
+            self.store
+                .enqueue_expires(&key, Duration::from_secs(expires_at.saturating_sub(now)))?;
+        }
+        Ok(fresh)
+    }
+}
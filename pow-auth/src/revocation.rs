@@ -0,0 +1,84 @@
+use std::{collections::HashSet, sync::Mutex};
+
+use proxy_wasm::{hostcalls, types::Status};
+
+use crate::auth_identity::PublicKey;
+
+/// Shared data key of the revocation list.
+pub const REVOKED_KEYS: &str = "pow_auth.revoked_keys";
+
+/// Why a granted key may not sign right now.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyRejection {
+    NotYetValid,
+    Expired,
+    Revoked,
+}
+
+impl KeyRejection {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyRejection::NotYetValid => "key_not_yet_valid",
+            KeyRejection::Expired => "key_expired",
+            KeyRejection::Revoked => "key_revoked",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Cache {
+    cas: Option<u32>,
+    keys: HashSet<PublicKey>,
+}
+
+/// Keys revoked at runtime: a JSON array of keys in the `X-Auth-PublicKey`
+/// format, which an operator or another plugin of the VM writes to shared
+/// data under [`REVOKED_KEYS`]. Parsed again only when it changes.
+#[derive(Default)]
+pub struct Revocations {
+    cache: Mutex<Cache>,
+}
+
+impl Revocations {
+    pub fn is_revoked(&self, key: &PublicKey) -> Result<bool, Status> {
+        let mut cache = self.cache.lock().expect("failed to lock revocations");
+        let (raw, cas) = hostcalls::get_shared_data(REVOKED_KEYS)?;
+        if cas != cache.cas {
+            cache.cas = cas;
+            match raw.as_deref().map(parse).unwrap_or(Ok(HashSet::new())) {
+                Ok(keys) => {
+                    log::info!("loaded {} revoked keys", keys.len());
+                    cache.keys = keys;
+                }
+                // Rather the previous list than none at all.
+                Err(e) => log::warn!("malformed revocation list, keeping the previous one: {}", e),
+            }
+        }
+        Ok(cache.keys.contains(key))
+    }
+}
+
+fn parse(raw: &[u8]) -> Result<HashSet<PublicKey>, String> {
+    let keys: Vec<String> = serde_json::from_slice(raw).map_err(|e| e.to_string())?;
+    keys.iter()
+        .map(|key| key.parse().map_err(|e| format!("{}: {}", key, e)))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_revocation_list() {
+        let keys = parse(
+            br#"["schnorr:9e70a683d711ab788433b4cabddbd10dce4bb1f29c67cc3219b325053b0f2f1c",
+                "039e70a683d711ab788433b4cabddbd10dce4bb1f29c67cc3219b325053b0f2f1c"]"#,
+        )
+        .expect("failed to parse revocation list");
+        assert_eq!(keys.len(), 2);
+
+        assert!(parse(br#"["rsa:00"]"#).is_err());
+        assert!(parse(b"not json").is_err());
+    }
+}
@@ -0,0 +1,193 @@
+use serde::{Deserialize, Serialize};
+
+/// Whether a request only reads, going by its method.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AccessClass {
+    /// `GET`, `HEAD` and `OPTIONS`.
+    Read,
+    /// Any method.
+    Write,
+}
+
+impl AccessClass {
+    pub fn of(method: &str) -> Self {
+        match method.to_ascii_uppercase().as_str() {
+            "GET" | "HEAD" | "OPTIONS" => AccessClass::Read,
+            _ => AccessClass::Write,
+        }
+    }
+}
+
+/// What a key may do under the route of its grant. Every part that is set
+/// has to allow the request.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Scope {
+    pub methods: Option<Vec<String>>,
+    /// Patterns relative to the route, where `*` matches within a single
+    /// segment and a `**` segment any number of them, e.g.
+    /// `/accounts/*/balance` or `/files/**`. Paths with `.` or `..`
+    /// segments or encoded slashes match none of them.
+    pub paths: Option<Vec<String>>,
+    pub class: Option<AccessClass>,
+}
+
+impl Scope {
+    pub fn validate(&self) -> Result<(), String> {
+        for path in self.paths.iter().flatten() {
+            if !path.starts_with('/') {
+                return Err(format!("scope path {} must start with /", path));
+            }
+            if path
+                .split('/')
+                .any(|segment| segment.contains("**") && segment != "**")
+            {
+                return Err(format!(
+                    "scope path {} may only use ** as a whole segment",
+                    path
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the scope allows `method` on `path`, relative to the route.
+    pub fn allows(&self, method: &str, path: &str) -> bool {
+        let method_allowed = self.methods.as_ref().map_or(true, |methods| {
+            methods.iter().any(|m| m.eq_ignore_ascii_case(method))
+        });
+        let path_allowed = self.paths.as_ref().map_or(true, |paths| {
+            is_canonical(path) && paths.iter().any(|pattern| glob(pattern, path))
+        });
+        let class_allowed = match self.class {
+            Some(AccessClass::Read) => AccessClass::of(method) == AccessClass::Read,
+            Some(AccessClass::Write) | None => true,
+        };
+        method_allowed && path_allowed && class_allowed
+    }
+}
+
+/// Part of `path` below the static prefix of the route `pattern`, without
+/// the query, e.g. `/users/1` for `/api/users/1?page=2` under `/api/*`.
+pub fn relative_path<'a>(pattern: &str, path: &'a str) -> &'a str {
+    let path = path.split_once('?').map_or(path, |(path, _)| path);
+    let prefix_len = pattern.find([':', '*', '<']).unwrap_or(pattern.len());
+    let prefix = pattern[..prefix_len].trim_end_matches('/');
+    match path.strip_prefix(prefix) {
+        Some("") => "/",
+        Some(relative) if relative.starts_with('/') => relative,
+        _ => path,
+    }
+}
+
+/// Whether `path` has no `.` or `..` segments, plain or percent-encoded,
+/// and no encoded slashes the upstream could decode into other segments.
+fn is_canonical(path: &str) -> bool {
+    let lower = path.to_ascii_lowercase();
+    !lower.contains("%2f")
+        && !lower.contains("%5c")
+        && lower
+            .split('/')
+            .all(|segment| !matches!(segment.replace("%2e", ".").as_str(), "." | ".."))
+}
+
+fn glob(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<_> = pattern.split('/').collect();
+    let path: Vec<_> = path.split('/').collect();
+    glob_segments(&pattern, &path)
+}
+
+fn glob_segments(pattern: &[&str], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((&"**", rest)) => (0..=path.len()).any(|i| glob_segments(rest, &path[i..])),
+        Some((segment, rest)) => path
+            .split_first()
+            .is_some_and(|(first, path)| glob_segment(segment, first) && glob_segments(rest, path)),
+    }
+}
+
+/// A lone `*` matches any non-empty segment, otherwise `*` matches any run
+/// of characters within the segment.
+fn glob_segment(pattern: &str, segment: &str) -> bool {
+    if pattern == "*" {
+        return !segment.is_empty();
+    }
+    match pattern.split_once('*') {
+        None => pattern == segment,
+        Some((head, tail)) => {
+            let Some(rest) = segment.strip_prefix(head) else {
+                return false;
+            };
+            (0..=rest.len())
+                .filter(|i| rest.is_char_boundary(*i))
+                .any(|i| glob_segment(tail, &rest[i..]))
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn relative_paths() {
+        assert_eq!(relative_path("/api/*", "/api/users/1?page=2"), "/users/1");
+        assert_eq!(relative_path("/api/users/:id", "/api/users/1"), "/1");
+        assert_eq!(relative_path("/upload", "/upload"), "/");
+        assert_eq!(relative_path("/*", "/json"), "/json");
+    }
+
+    #[test]
+    fn scopes() {
+        let read_only = Scope {
+            class: Some(AccessClass::Read),
+            ..Default::default()
+        };
+        assert!(read_only.allows("GET", "/accounts"));
+        assert!(!read_only.allows("POST", "/transfer"));
+
+        let transfer = Scope {
+            methods: Some(vec!["post".to_string()]),
+            paths: Some(vec![
+                "/transfer".to_string(),
+                "/accounts/*/transfer".to_string(),
+            ]),
+            class: Some(AccessClass::Write),
+        };
+        assert!(transfer.allows("POST", "/transfer"));
+        assert!(transfer.allows("POST", "/accounts/42/transfer"));
+        assert!(!transfer.allows("POST", "/accounts/42/close"));
+        assert!(!transfer.allows("DELETE", "/transfer"));
+        assert!(!transfer.allows("POST", "/accounts/42/x/transfer"));
+        assert!(!transfer.allows("POST", "/accounts//transfer"));
+        assert!(!transfer.allows("POST", "/accounts/42/../transfer"));
+        assert!(!transfer.allows("POST", "/accounts/42/%2e%2E/transfer"));
+        assert!(!transfer.allows("POST", "/accounts/42%2Fx/transfer"));
+        assert!(!transfer.allows("POST", "/./transfer"));
+
+        let files = Scope {
+            paths: Some(vec!["/files/**".to_string(), "/*.json".to_string()]),
+            ..Default::default()
+        };
+        assert!(files.allows("GET", "/files"));
+        assert!(files.allows("GET", "/files/a/b/c"));
+        assert!(files.allows("GET", "/data.json"));
+        assert!(!files.allows("GET", "/data/x.json"));
+        assert!(!files.allows("GET", "/files/../secret"));
+
+        assert!(Scope::default().allows("DELETE", "/anything"));
+        assert!(Scope {
+            paths: Some(vec!["users".to_string()]),
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+        assert!(Scope {
+            paths: Some(vec!["/files/a**".to_string()]),
+            ..Default::default()
+        }
+        .validate()
+        .is_err());
+    }
+}
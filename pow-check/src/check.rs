@@ -0,0 +1,324 @@
+use std::{collections::HashSet, fmt};
+
+use pow_types::config::{Router, VirtualHost};
+use serde::de::DeserializeOwned;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct Issue {
+    pub severity: Severity,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl Issue {
+    pub(crate) fn error(line: Option<usize>, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Error,
+            line,
+            message: message.into(),
+        }
+    }
+
+    pub(crate) fn warning(line: Option<usize>, message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Warning,
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        match self.line {
+            Some(line) => write!(f, "{}: line {}: {}", severity, line, self.message),
+            None => write!(f, "{}: {}", severity, self.message),
+        }
+    }
+}
+
+pub fn parse<C: DeserializeOwned>(source: &str) -> Result<C, Issue> {
+    serde_yaml::from_str(source).map_err(|e| {
+        let line = e.location().map(|l| l.line());
+        Issue::error(line, e.to_string())
+    })
+}
+
+/// Lines of the `host:` and `path:` keys under `virtual_hosts`, or in the
+/// whole document if it is a bare list of virtual hosts, in document order.
+///
+/// This is a plain text scan, so it gives up on flow style mappings: when the
+/// counts disagree with the parsed config no line is reported at all.
+#[derive(Debug, PartialEq, Eq)]
+struct KeyLines {
+    hosts: Vec<usize>,
+    paths: Vec<usize>,
+}
+
+impl KeyLines {
+    fn scan(source: &str) -> Self {
+        let mut hosts = vec![];
+        let mut paths = vec![];
+        let lines: Vec<&str> = source.lines().collect();
+        // Other sections, such as the chain upstreams, have `path:` keys too.
+        let top_level =
+            |line: &&str| !line.trim().is_empty() && !line.starts_with([' ', '\t', '-', '#']);
+        let range = match lines.iter().position(|l| l.starts_with("virtual_hosts:")) {
+            Some(start) => {
+                let end = lines[start + 1..]
+                    .iter()
+                    .position(top_level)
+                    .map_or(lines.len(), |n| start + 1 + n);
+                start..end
+            }
+            None => 0..lines.len(),
+        };
+        for (i, line) in lines.iter().enumerate().take(range.end).skip(range.start) {
+            let line = line.trim_start();
+            let line = line.strip_prefix("- ").unwrap_or(line).trim_start();
+            if line.starts_with("host:") {
+                hosts.push(i + 1);
+            } else if line.starts_with("path:") {
+                paths.push(i + 1);
+            }
+        }
+        Self { hosts, paths }
+    }
+}
+
+/// Builds the router and reports duplicate, shadowed or unreachable routes.
+pub fn routes<T>(
+    source: &str,
+    virtual_hosts: Vec<VirtualHost<T>>,
+) -> (Option<Router<T>>, Vec<Issue>) {
+    let mut issues = vec![];
+    let key_lines = KeyLines::scan(source);
+
+    let routes: Vec<(usize, String)> = virtual_hosts
+        .iter()
+        .enumerate()
+        .flat_map(|(i, vh)| vh.patterns().into_iter().map(move |p| (i, p)))
+        .collect();
+    let host_line =
+        |i: usize| (key_lines.hosts.len() == virtual_hosts.len()).then(|| key_lines.hosts[i]);
+    let path_line = |i: usize| (key_lines.paths.len() == routes.len()).then(|| key_lines.paths[i]);
+
+    let mut hosts = HashSet::new();
+    for (i, vh) in virtual_hosts.iter().enumerate() {
+        if !hosts.insert(vh.host.as_str()) {
+            issues.push(Issue::error(
+                host_line(i),
+                format!("duplicate virtual host {}", vh.host),
+            ));
+        }
+    }
+    let mut seen = HashSet::new();
+    for (i, (host, pattern)) in routes.iter().enumerate() {
+        if !seen.insert((*host, pattern.as_str())) {
+            issues.push(Issue::error(
+                path_line(i),
+                format!("duplicate route {}{}", virtual_hosts[*host].host, pattern),
+            ));
+        }
+    }
+
+    let hosts: Vec<String> = virtual_hosts.iter().map(|vh| vh.host.clone()).collect();
+    let router: Router<T> = match virtual_hosts.try_into() {
+        Ok(router) => router,
+        Err(e) => {
+            if issues.is_empty() {
+                issues.push(Issue::error(None, e.to_string()));
+            }
+            return (None, issues);
+        }
+    };
+
+    for (i, (host, pattern)) in routes.iter().enumerate() {
+        let host = &hosts[*host];
+        let sample_host = sample_host(host);
+        let Some(sample_path) = sample_path(pattern) else {
+            continue;
+        };
+        match router.matches(&sample_host, &sample_path) {
+            Some(found) if found.virtual_host() == host && found.pattern() == pattern => {}
+            Some(found) => issues.push(Issue::warning(
+                path_line(i),
+                format!(
+                    "route {}{} is shadowed by {}{}, e.g. {}{}",
+                    host,
+                    pattern,
+                    found.virtual_host(),
+                    found.pattern(),
+                    sample_host,
+                    sample_path
+                ),
+            )),
+            None => issues.push(Issue::warning(
+                path_line(i),
+                format!(
+                    "route {}{} is unreachable, e.g. {}{}",
+                    host, pattern, sample_host, sample_path
+                ),
+            )),
+        }
+    }
+
+    (Some(router), issues)
+}
+
+/// Stands in for wildcards, unlikely to collide with a static route.
+const SAMPLE: &str = "pow-check-sample";
+
+/// A concrete host matched by a host pattern.
+fn sample_host(host: &str) -> String {
+    let labels: Vec<&str> = host
+        .split('.')
+        .map(|label| match label {
+            "*" | "+" => SAMPLE,
+            label => label,
+        })
+        .collect();
+    labels.join(".")
+}
+
+/// A concrete path matched by a path pattern, `None` for regex segments.
+fn sample_path(pattern: &str) -> Option<String> {
+    let mut path = String::new();
+    let mut chars = pattern.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '<' => return None,
+            ':' => {
+                while chars.peek().is_some_and(|c| *c != '/') {
+                    if chars.next() == Some('<') {
+                        return None;
+                    }
+                }
+                path.push_str(SAMPLE);
+            }
+            '*' => {
+                // Two segments, so that a parameter in the same place can't take it.
+                path.push_str(SAMPLE);
+                path.push('/');
+                path.push_str(SAMPLE);
+                break;
+            }
+            c => path.push(c),
+        }
+    }
+    Some(path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn check(source: &str) -> Vec<Issue> {
+        let virtual_hosts: Vec<VirtualHost<serde_yaml::Value>> =
+            parse(source).expect("failed to parse config");
+        routes(source, virtual_hosts).1
+    }
+
+    #[test]
+    fn parse_error_has_line() {
+        let issue = parse::<Vec<VirtualHost<serde_yaml::Value>>>(
+            r#"
+- host: example.com
+  routes:
+    - paths: "/"
+"#,
+        )
+        .expect_err("config should be invalid");
+        assert_eq!(issue.severity, Severity::Error);
+        assert_eq!(issue.line, Some(4));
+    }
+
+    #[test]
+    fn key_lines_of_virtual_hosts() {
+        let key_lines = KeyLines::scan(
+            r#"
+chain:
+  http:
+    upstreams:
+      - upstream: beacon
+        path: /eth/v1/head
+virtual_hosts:
+- host: example.com
+  routes:
+    - path: "/api"
+anchors:
+  keep: 2
+"#,
+        );
+        assert_eq!(
+            key_lines,
+            KeyLines {
+                hosts: vec![8],
+                paths: vec![10],
+            }
+        );
+    }
+
+    #[test]
+    fn duplicate_routes() {
+        let issues = check(
+            r#"
+- host: example.com
+  routes:
+    - path: "/api"
+      children:
+        - path: "/users"
+    - path: "/api/users"
+"#,
+        );
+        assert_eq!(
+            issues,
+            vec![Issue::error(
+                Some(7),
+                "duplicate route example.com/api/users"
+            )]
+        );
+    }
+
+    #[test]
+    fn shadowed_routes() {
+        let issues = check(
+            r#"
+- host: "*.example.com"
+  routes:
+    - path: "/"
+- host: "api.example.com"
+  routes:
+    - path: "/*"
+    - path: "/users/:id"
+    - path: "/users/:name"
+    - path: "/users/me"
+"#,
+        );
+        assert_eq!(
+            issues,
+            vec![Issue::warning(
+                Some(9),
+                "route api.example.com/users/:name is shadowed by api.example.com/users/:id, \
+                 e.g. api.example.com/users/pow-check-sample"
+            )]
+        );
+
+        assert_eq!(
+            sample_path("/users/:id/posts/*rest"),
+            Some("/users/pow-check-sample/posts/pow-check-sample/pow-check-sample".to_string())
+        );
+        assert_eq!(sample_path("/users/:id<\\d+>"), None);
+        assert_eq!(sample_host("*.example.com"), "pow-check-sample.example.com");
+    }
+}
@@ -0,0 +1,116 @@
+//! Offline validation of `pow-waf` and `pow-auth` plugin configurations.
+//!
+//! ```sh
+//! pow-check waf config.yaml
+//! pow-check waf config.yaml --host example.com --path /api/users --ip 2001:db8::1
+//! pow-check auth config.yaml --host example.com --path /bank/transfer --method POST
+//! ```
+
+mod check;
+mod simulate;
+
+use std::{net::IpAddr, path::PathBuf, process::ExitCode};
+
+use check::{Issue, Severity};
+use clap::{Args, Parser, ValueEnum};
+use pow_types::config::Router;
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum Filter {
+    Waf,
+    Auth,
+}
+
+#[derive(Debug, Parser)]
+#[command(about = "Validate a PoW filter configuration and simulate its routing")]
+struct Cli {
+    /// Which filter the configuration belongs to.
+    filter: Filter,
+    /// Plugin configuration, the YAML passed to the filter.
+    config: PathBuf,
+    #[command(flatten)]
+    request: Request,
+}
+
+/// A request to run through the router, simulated when `host` and `path`
+/// are both given.
+#[derive(Debug, Args)]
+pub struct Request {
+    #[arg(long)]
+    host: Option<String>,
+    #[arg(long)]
+    path: Option<String>,
+    /// Checked against the scope of every grant of a `pow-auth` route.
+    #[arg(long, default_value = "GET")]
+    method: String,
+    #[arg(long, default_value = "127.0.0.1")]
+    ip: IpAddr,
+    /// Requests the client already sent within the current bucket.
+    #[arg(long, default_value_t = 0)]
+    requests: u64,
+    /// Requests the route already received from all clients within the current bucket.
+    #[arg(long, default_value_t = 0)]
+    route_requests: u64,
+    /// Share of 5xx responses the route's upstream currently returns, in percent.
+    #[arg(long, default_value_t = 0.0)]
+    error_rate_percent: f64,
+    /// Latency the route's upstream currently shows.
+    #[arg(long, default_value_t = 0.0)]
+    latency_ms: f64,
+    /// Country of the client, overrides the GeoIP databases.
+    #[arg(long)]
+    country: Option<String>,
+    /// ASN of the client, overrides the GeoIP databases.
+    #[arg(long)]
+    asn: Option<u32>,
+    /// Raw VM configuration, for GeoIP databases loaded from it.
+    #[arg(long)]
+    vm_configuration: Option<PathBuf>,
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let source = match std::fs::read_to_string(&cli.config) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("failed to read {}: {}", cli.config.display(), e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut issues = vec![];
+    match cli.filter {
+        Filter::Waf => simulate::waf(&source, &cli.request, &mut issues),
+        Filter::Auth => simulate::auth(&source, &cli.request, &mut issues),
+    }
+
+    for issue in &issues {
+        eprintln!("{}: {}", cli.config.display(), issue);
+    }
+    if issues.iter().any(|i| i.severity == Severity::Error) {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+/// Parses the config and builds its router, `None` when it would not load.
+fn load<C, T>(
+    source: &str,
+    virtual_hosts: impl FnOnce(&mut C) -> Vec<pow_types::config::VirtualHost<T>>,
+    issues: &mut Vec<Issue>,
+) -> Option<(C, Router<T>)>
+where
+    C: serde::de::DeserializeOwned,
+{
+    let mut config: C = match check::parse(source) {
+        Ok(config) => config,
+        Err(issue) => {
+            issues.push(issue);
+            return None;
+        }
+    };
+    let (router, route_issues) = check::routes(source, virtual_hosts(&mut config));
+    issues.extend(route_issues);
+    router.map(|router| (config, router))
+}
@@ -0,0 +1,224 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use pow_auth::{
+    config::{Access, Config as AuthConfig, Grant, Setting as AuthSetting},
+    scope::relative_path,
+};
+use pow_waf::{
+    config::{Config as WafConfig, GeoAction, Setting as WafSetting},
+    difficulty::{Difficulty, Load},
+    geo::{GeoInfo, GeoIp},
+    health::Health,
+};
+
+use crate::{check::Issue, load, Request};
+
+fn target(request: &Request) -> Option<(&str, &str)> {
+    let (Some(host), Some(path)) = (&request.host, &request.path) else {
+        return None;
+    };
+    println!("{} {}{} from {}", request.method, host, path, request.ip);
+    Some((host, path))
+}
+
+pub fn waf(source: &str, request: &Request, issues: &mut Vec<Issue>) {
+    let mut invalid_route = None;
+    let Some((mut config, router)) = load::<WafConfig<WafSetting>, _>(
+        source,
+        |c| {
+            invalid_route = c.validate_routes().err();
+            std::mem::take(&mut c.virtual_hosts)
+        },
+        issues,
+    ) else {
+        return;
+    };
+    if let Some(e) = invalid_route {
+        issues.push(Issue::error(None, format!("invalid route {}", e)));
+        return;
+    }
+
+    let client_aggregation = config.client_aggregation.take().unwrap_or_default();
+    if let Err(e) = client_aggregation.validate() {
+        issues.push(Issue::error(
+            None,
+            format!("invalid client_aggregation: {}", e),
+        ));
+        return;
+    }
+    if let Err(e) = config.chain() {
+        issues.push(Issue::error(None, format!("invalid chain source: {}", e)));
+        return;
+    }
+    if let Err(e) = config.anchors.take().unwrap_or_default().validate() {
+        issues.push(Issue::error(None, format!("invalid anchors: {}", e)));
+        return;
+    }
+    let vm_configuration = match request.vm_configuration.as_ref().map(std::fs::read) {
+        Some(Err(e)) => {
+            issues.push(Issue::error(
+                None,
+                format!("failed to read VM configuration: {}", e),
+            ));
+            return;
+        }
+        Some(Ok(bytes)) => Some(bytes),
+        None => None,
+    };
+    let geo = match GeoIp::load(
+        config.geo_databases.take().unwrap_or_default(),
+        vm_configuration.as_deref(),
+    ) {
+        Ok(geo) => geo,
+        Err(e) => {
+            issues.push(Issue::error(
+                None,
+                format!("failed to load GeoIP database: {}", e),
+            ));
+            return;
+        }
+    };
+
+    let Some((host, path)) = target(request) else {
+        return;
+    };
+    let ip = request.ip;
+    if let Some(cidr) = config.whitelist.iter().flatten().find(|c| c.contains(ip)) {
+        println!("  whitelisted by {}", cidr);
+        return;
+    }
+    let Some(found) = router.matches(host, path) else {
+        println!("  no route matched, the request passes unchallenged");
+        return;
+    };
+    println!("  route: {} {}", found.virtual_host(), found.pattern());
+    println!(
+        "  rate limit: {} per {}",
+        found.rate_limit.requests_per_unit,
+        format!("{:?}", found.rate_limit.unit).to_lowercase()
+    );
+
+    let mut info = geo.lookup(ip);
+    info.country = request.country.clone().or(info.country);
+    info.asn = request.asn.or(info.asn);
+    let geo_action = found.geo_action(&info);
+    if info != GeoInfo::default() {
+        println!(
+            "  geo: country {}, asn {} -> {:?}",
+            info.country.as_deref().unwrap_or("-"),
+            info.asn.map_or("-".to_string(), |asn| asn.to_string()),
+            geo_action
+        );
+    }
+    match geo_action {
+        Some(GeoAction::Block) => {
+            println!("  blocked by geo rule");
+            return;
+        }
+        Some(GeoAction::Bypass) => {
+            println!("  bypassed by geo rule");
+            return;
+        }
+        _ => {}
+    }
+
+    let bucket = found.rate_limit.current_bucket();
+    let keys = match client_aggregation.keys(ip, bucket, host, found.pattern()) {
+        Ok(keys) => keys,
+        Err(e) => {
+            issues.push(Issue::error(
+                None,
+                format!("failed to aggregate client address: {}", e),
+            ));
+            return;
+        }
+    };
+    let load = Load {
+        route_counter: request.route_requests,
+        health: Health {
+            error_rate: request.error_rate_percent / 100.0,
+            latency_ms: request.latency_ms,
+        },
+        tiers: keys
+            .iter()
+            .map(|(_, tier)| (*tier, request.requests))
+            .collect(),
+        geo_action,
+    };
+    let computed = Difficulty::compute(&found, config.difficulty, &load);
+    println!(
+        "  load multiplier: {} at {} route requests",
+        computed.load_multiplier, request.route_requests
+    );
+    if found.health.is_some() {
+        println!(
+            "  health multiplier: {} at {}% errors, {}ms latency",
+            computed.health_multiplier, request.error_rate_percent, request.latency_ms
+        );
+    }
+    for ((key, _), tier_difficulty) in keys.iter().zip(&computed.tiers) {
+        println!("  key: {} -> difficulty {}", key, tier_difficulty);
+    }
+    let difficulty = computed.value;
+
+    if difficulty == 0 {
+        println!("  difficulty: 0, the request passes unchallenged");
+    } else {
+        println!("  difficulty: {}", difficulty);
+    }
+}
+
+pub fn auth(source: &str, request: &Request, issues: &mut Vec<Issue>) {
+    let Some((config, router)) = load::<AuthConfig<AuthSetting>, _>(
+        source,
+        |c| std::mem::take(&mut c.virtual_hosts),
+        issues,
+    ) else {
+        return;
+    };
+
+    let Some((host, path)) = target(request) else {
+        return;
+    };
+    if let Some(cidr) = config
+        .whitelist
+        .iter()
+        .flatten()
+        .find(|c| c.contains(request.ip))
+    {
+        println!("  whitelisted by {}", cidr);
+        return;
+    }
+    let Some(found) = router.matches(host, path) else {
+        println!("  no route matched, the request passes unauthenticated");
+        return;
+    };
+    println!("  route: {} {}", found.virtual_host(), found.pattern());
+    let components: Vec<&str> = found.signed_components.iter().map(|c| c.name()).collect();
+    println!("  signed components: {}", components.join(", "));
+    println!(
+        "  timestamp window: {}s past, {}s future",
+        found.timestamp_window.past_secs, found.timestamp_window.future_secs
+    );
+    match &found.access {
+        Access::Public => println!("  access: public"),
+        Access::Grants(grants) => {
+            println!("  access: grants");
+            let relative = relative_path(found.pattern(), path);
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+            let mut grants: Vec<&Grant> = grants.values().collect();
+            grants.sort_by(|a, b| a.name.cmp(&b.name));
+            for grant in grants {
+                // Revocations live in shared data, they can't be known offline.
+                let verdict = match grant.check(now) {
+                    Err(rejection) => rejection.as_str(),
+                    Ok(()) if !grant.scope.allows(&request.method, relative) => "out_of_scope",
+                    Ok(()) => "allowed",
+                };
+                println!("    {}: {}", grant.name, verdict);
+            }
+        }
+    }
+}
@@ -0,0 +1,633 @@
+//! The hostcalls proxy-wasm imports, resolved at link time against these
+//! `#[no_mangle]` definitions instead of a wasm runtime.
+//!
+//! None of them calls back into the plugin: callout responses and queue
+//! notifications are delivered by [`crate::Host::tick`].
+
+use std::{ptr::null_mut, time::Duration};
+
+use proxy_wasm::types::{BufferType, LogLevel, MapType, MetricType, Status, StreamType};
+
+use crate::state::{self, decode_map, encode_map, HttpCall, LocalResponse, Metric, Queue};
+
+unsafe fn slice<'a>(data: *const u8, size: usize) -> &'a [u8] {
+    if data.is_null() || size == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(data, size)
+    }
+}
+
+unsafe fn string(data: *const u8, size: usize) -> String {
+    String::from_utf8_lossy(slice(data, size)).into_owned()
+}
+
+/// Hands `bytes` over to the plugin, which frees them with `Vec::from_raw_parts`.
+unsafe fn give(bytes: Vec<u8>, data: *mut *mut u8, size: *mut usize) {
+    let bytes = bytes.into_boxed_slice();
+    *size = bytes.len();
+    *data = Box::into_raw(bytes) as *mut u8;
+}
+
+unsafe fn give_none(data: *mut *mut u8, size: *mut usize) {
+    *data = null_mut();
+    *size = 0;
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_log(
+    level: LogLevel,
+    message_data: *const u8,
+    message_size: usize,
+) -> Status {
+    let message = string(message_data, message_size);
+    // Captured by the test harness, shown when a test fails.
+    println!("[{:?}] {}", level, message);
+    state::with(|s| s.logs.push((level, message)));
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_log_level(return_level: *mut LogLevel) -> Status {
+    *return_level = LogLevel::Trace;
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_current_time_nanoseconds(return_time: *mut u64) -> Status {
+    *return_time = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .expect("system clock before unix epoch")
+        .as_nanos() as u64;
+    Status::Ok
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_set_tick_period_milliseconds(period: u32) -> Status {
+    state::with(|s| s.tick_periods.insert(s.effective, period));
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_buffer_bytes(
+    buffer_type: BufferType,
+    start: usize,
+    max_size: usize,
+    return_buffer_data: *mut *mut u8,
+    return_buffer_size: *mut usize,
+) -> Status {
+    let buffer = state::with(|s| match buffer_type {
+        BufferType::VmConfiguration => s.vm_configuration.clone(),
+        BufferType::PluginConfiguration => s.plugin_configuration.clone(),
+        BufferType::HttpRequestBody => s.stream().map(|s| s.request_body.clone()),
+        BufferType::HttpResponseBody => s.stream().map(|s| s.response_body.clone()),
+        BufferType::HttpCallResponseBody => s.http_call_response.as_ref().map(|r| r.body.clone()),
+        _ => None,
+    });
+    let Some(buffer) = buffer else {
+        return Status::NotFound;
+    };
+    let start = start.min(buffer.len());
+    let end = start.saturating_add(max_size).min(buffer.len());
+    give(
+        buffer[start..end].to_vec(),
+        return_buffer_data,
+        return_buffer_size,
+    );
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_set_buffer_bytes(
+    buffer_type: BufferType,
+    start: usize,
+    size: usize,
+    buffer_data: *const u8,
+    buffer_size: usize,
+) -> Status {
+    let data = slice(buffer_data, buffer_size);
+    state::with(|s| {
+        let Some(stream) = s.stream() else {
+            return Status::NotFound;
+        };
+        let buffer = match buffer_type {
+            BufferType::HttpRequestBody => &mut stream.request_body,
+            BufferType::HttpResponseBody => &mut stream.response_body,
+            _ => return Status::BadArgument,
+        };
+        let start = start.min(buffer.len());
+        let end = start.saturating_add(size).min(buffer.len());
+        buffer.splice(start..end, data.iter().copied());
+        Status::Ok
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_header_map_pairs(
+    map_type: MapType,
+    return_map_data: *mut *mut u8,
+    return_map_size: *mut usize,
+) -> Status {
+    let Some(map) = state::with(|s| s.read_map(map_type)) else {
+        return Status::NotFound;
+    };
+    give(encode_map(&map), return_map_data, return_map_size);
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_set_header_map_pairs(
+    map_type: MapType,
+    map_data: *const u8,
+    map_size: usize,
+) -> Status {
+    let pairs = decode_map(slice(map_data, map_size));
+    state::with(|s| match s.map(map_type) {
+        Some(map) => {
+            *map = pairs
+                .into_iter()
+                .map(|(k, v)| (k.to_ascii_lowercase(), v))
+                .collect();
+            Status::Ok
+        }
+        None => Status::NotFound,
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_header_map_value(
+    map_type: MapType,
+    key_data: *const u8,
+    key_size: usize,
+    return_value_data: *mut *mut u8,
+    return_value_size: *mut usize,
+) -> Status {
+    let key = string(key_data, key_size);
+    let value = state::with(|s| {
+        s.read_map(map_type)
+            .and_then(|map| state::find(&map, &key).map(str::to_string))
+    });
+    match value {
+        Some(value) => give(value.into_bytes(), return_value_data, return_value_size),
+        None => give_none(return_value_data, return_value_size),
+    }
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_replace_header_map_value(
+    map_type: MapType,
+    key_data: *const u8,
+    key_size: usize,
+    value_data: *const u8,
+    value_size: usize,
+) -> Status {
+    let key = string(key_data, key_size).to_ascii_lowercase();
+    let value = string(value_data, value_size);
+    state::with(|s| {
+        let Some(map) = s.map(map_type) else {
+            return Status::NotFound;
+        };
+        map.retain(|(k, _)| *k != key);
+        map.push((key, value));
+        Status::Ok
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_remove_header_map_value(
+    map_type: MapType,
+    key_data: *const u8,
+    key_size: usize,
+) -> Status {
+    let key = string(key_data, key_size).to_ascii_lowercase();
+    state::with(|s| {
+        let Some(map) = s.map(map_type) else {
+            return Status::NotFound;
+        };
+        map.retain(|(k, _)| *k != key);
+        Status::Ok
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_add_header_map_value(
+    map_type: MapType,
+    key_data: *const u8,
+    key_size: usize,
+    value_data: *const u8,
+    value_size: usize,
+) -> Status {
+    let key = string(key_data, key_size).to_ascii_lowercase();
+    let value = string(value_data, value_size);
+    state::with(|s| {
+        let Some(map) = s.map(map_type) else {
+            return Status::NotFound;
+        };
+        map.push((key, value));
+        Status::Ok
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_property(
+    path_data: *const u8,
+    path_size: usize,
+    return_value_data: *mut *mut u8,
+    return_value_size: *mut usize,
+) -> Status {
+    let path = slice(path_data, path_size);
+    match state::with(|s| s.property(path)) {
+        Some(value) => {
+            give(value, return_value_data, return_value_size);
+            Status::Ok
+        }
+        None => Status::NotFound,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_set_property(
+    path_data: *const u8,
+    path_size: usize,
+    value_data: *const u8,
+    value_size: usize,
+) -> Status {
+    let path = slice(path_data, path_size).to_vec();
+    let value = slice(value_data, value_size).to_vec();
+    state::with(|s| s.set_property(path, value));
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_shared_data(
+    key_data: *const u8,
+    key_size: usize,
+    return_value_data: *mut *mut u8,
+    return_value_size: *mut usize,
+    return_cas: *mut u32,
+) -> Status {
+    let key = string(key_data, key_size);
+    match state::with(|s| s.shared_data.get(&key).cloned()) {
+        // Envoy hands out no data for a removed, that is emptied, value.
+        Some((value, cas)) if value.is_empty() => {
+            *return_value_data = std::ptr::null_mut();
+            *return_value_size = 0;
+            *return_cas = cas;
+            Status::Ok
+        }
+        Some((value, cas)) => {
+            give(value, return_value_data, return_value_size);
+            *return_cas = cas;
+            Status::Ok
+        }
+        None => Status::NotFound,
+    }
+}
+
+/// Like Envoy, a zero `cas` writes unconditionally and a missing key is
+/// always written.
+#[no_mangle]
+pub unsafe extern "C" fn proxy_set_shared_data(
+    key_data: *const u8,
+    key_size: usize,
+    value_data: *const u8,
+    value_size: usize,
+    cas: u32,
+) -> Status {
+    let key = string(key_data, key_size);
+    let value = slice(value_data, value_size).to_vec();
+    state::with(|s| match s.shared_data.get_mut(&key) {
+        Some((_, current)) if cas != 0 && cas != *current => Status::CasMismatch,
+        Some(entry) => {
+            *entry = (value, entry.1 + 1);
+            Status::Ok
+        }
+        None => {
+            s.shared_data.insert(key, (value, 1));
+            Status::Ok
+        }
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_register_shared_queue(
+    name_data: *const u8,
+    name_size: usize,
+    return_id: *mut u32,
+) -> Status {
+    let name = string(name_data, name_size);
+    *return_id = state::with(|s| {
+        let owner = s.effective;
+        if let Some(i) = s.queues.iter().position(|q| q.name == name) {
+            s.queues[i].owner = owner;
+            return i as u32 + 1;
+        }
+        s.queues.push(Queue {
+            name,
+            owner,
+            items: Default::default(),
+        });
+        s.queues.len() as u32
+    });
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_resolve_shared_queue(
+    _vm_id_data: *const u8,
+    _vm_id_size: usize,
+    name_data: *const u8,
+    name_size: usize,
+    return_id: *mut u32,
+) -> Status {
+    let name = string(name_data, name_size);
+    match state::with(|s| s.queues.iter().position(|q| q.name == name)) {
+        Some(i) => {
+            *return_id = i as u32 + 1;
+            Status::Ok
+        }
+        None => Status::NotFound,
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_dequeue_shared_queue(
+    queue_id: u32,
+    return_value_data: *mut *mut u8,
+    return_value_size: *mut usize,
+) -> Status {
+    let item = state::with(|s| {
+        s.queues
+            .get_mut((queue_id as usize).wrapping_sub(1))
+            .map(|q| q.items.pop_front())
+    });
+    match item {
+        None => Status::NotFound,
+        Some(None) => Status::Empty,
+        Some(Some(item)) => {
+            give(item, return_value_data, return_value_size);
+            Status::Ok
+        }
+    }
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_enqueue_shared_queue(
+    queue_id: u32,
+    value_data: *const u8,
+    value_size: usize,
+) -> Status {
+    let value = slice(value_data, value_size).to_vec();
+    state::with(|s| {
+        let Some(queue) = s.queues.get_mut((queue_id as usize).wrapping_sub(1)) else {
+            return Status::NotFound;
+        };
+        queue.items.push_back(value);
+        let ready = (queue.owner, queue_id);
+        if !s.ready_queues.contains(&ready) {
+            s.ready_queues.push(ready);
+        }
+        Status::Ok
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_continue_stream(stream_type: StreamType) -> Status {
+    state::with(|s| {
+        let Some(stream) = s.stream() else {
+            return Status::NotFound;
+        };
+        match stream_type {
+            StreamType::HttpRequest => stream.request_resumed = true,
+            StreamType::HttpResponse => stream.response_resumed = true,
+            _ => return Status::BadArgument,
+        }
+        Status::Ok
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_close_stream(_stream_type: StreamType) -> Status {
+    state::with(|s| match s.stream() {
+        Some(stream) => {
+            stream.closed = true;
+            Status::Ok
+        }
+        None => Status::NotFound,
+    })
+}
+
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn proxy_send_local_response(
+    status_code: u32,
+    status_code_details_data: *const u8,
+    status_code_details_size: usize,
+    body_data: *const u8,
+    body_size: usize,
+    headers_data: *const u8,
+    headers_size: usize,
+    _grpc_status: i32,
+) -> Status {
+    let response = LocalResponse {
+        status: status_code,
+        details: string(status_code_details_data, status_code_details_size),
+        headers: decode_map(slice(headers_data, headers_size)),
+        body: slice(body_data, body_size).to_vec(),
+    };
+    state::with(|s| match s.stream() {
+        Some(stream) => {
+            stream.local_response = Some(response);
+            stream.closed = true;
+            Status::Ok
+        }
+        None => Status::NotFound,
+    })
+}
+
+/// The scripted upstream answers right away, the plugin hears back on the
+/// next tick. Unknown upstreams fail the call like Envoy's missing cluster.
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub unsafe extern "C" fn proxy_http_call(
+    upstream_data: *const u8,
+    upstream_size: usize,
+    headers_data: *const u8,
+    headers_size: usize,
+    body_data: *const u8,
+    body_size: usize,
+    trailers_data: *const u8,
+    trailers_size: usize,
+    timeout: u32,
+    return_token: *mut u32,
+) -> Status {
+    let call = HttpCall {
+        upstream: string(upstream_data, upstream_size),
+        headers: decode_map(slice(headers_data, headers_size)),
+        body: (!body_data.is_null()).then(|| slice(body_data, body_size).to_vec()),
+        trailers: decode_map(slice(trailers_data, trailers_size)),
+        timeout: Duration::from_millis(timeout as u64),
+    };
+    let Some(upstream) = state::with(|s| s.upstreams.get(&call.upstream).cloned()) else {
+        return Status::BadArgument;
+    };
+    let response = upstream(&call);
+    *return_token = state::with(|s| {
+        s.next_token += 1;
+        s.http_calls.push(call);
+        s.pending_calls.push_back((s.next_token, response));
+        s.next_token
+    });
+    Status::Ok
+}
+
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub extern "C" fn proxy_grpc_call(
+    _upstream_data: *const u8,
+    _upstream_size: usize,
+    _service_name_data: *const u8,
+    _service_name_size: usize,
+    _method_name_data: *const u8,
+    _method_name_size: usize,
+    _initial_metadata_data: *const u8,
+    _initial_metadata_size: usize,
+    _message_data_data: *const u8,
+    _message_data_size: usize,
+    _timeout: u32,
+    _return_callout_id: *mut u32,
+) -> Status {
+    Status::BadArgument
+}
+
+#[no_mangle]
+#[allow(clippy::too_many_arguments)]
+pub extern "C" fn proxy_grpc_stream(
+    _upstream_data: *const u8,
+    _upstream_size: usize,
+    _service_name_data: *const u8,
+    _service_name_size: usize,
+    _method_name_data: *const u8,
+    _method_name_size: usize,
+    _initial_metadata_data: *const u8,
+    _initial_metadata_size: usize,
+    _return_stream_id: *mut u32,
+) -> Status {
+    Status::BadArgument
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_grpc_send(
+    _token: u32,
+    _message_ptr: *const u8,
+    _message_len: usize,
+    _end_stream: bool,
+) -> Status {
+    Status::NotFound
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_grpc_cancel(_token_id: u32) -> Status {
+    Status::NotFound
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_grpc_close(_token_id: u32) -> Status {
+    Status::NotFound
+}
+
+/// Reports the HTTP status of the callout response being delivered.
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_status(
+    return_code: *mut u32,
+    return_message_data: *mut *mut u8,
+    return_message_size: *mut usize,
+) -> Status {
+    *return_code = state::with(|s| s.http_call_response.as_ref().map_or(0, |r| r.status));
+    give_none(return_message_data, return_message_size);
+    Status::Ok
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_set_effective_context(context_id: u32) -> Status {
+    state::with(|s| s.effective = context_id);
+    Status::Ok
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_call_foreign_function(
+    _function_name_data: *const u8,
+    _function_name_size: usize,
+    _arguments_data: *const u8,
+    _arguments_size: usize,
+    _results_data: *mut *mut u8,
+    _results_size: *mut usize,
+) -> Status {
+    Status::NotFound
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_done() -> Status {
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_define_metric(
+    metric_type: MetricType,
+    name_data: *const u8,
+    name_size: usize,
+    return_id: *mut u32,
+) -> Status {
+    let name = string(name_data, name_size);
+    *return_id = state::with(|s| {
+        if let Some(i) = s.metrics.iter().position(|m| m.name == name) {
+            return i as u32 + 1;
+        }
+        s.metrics.push(Metric {
+            name,
+            metric_type,
+            value: 0,
+        });
+        s.metrics.len() as u32
+    });
+    Status::Ok
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn proxy_get_metric(metric_id: u32, return_value: *mut u64) -> Status {
+    match state::with(|s| metric(s, metric_id).map(|m| m.value)) {
+        Some(value) => {
+            *return_value = value;
+            Status::Ok
+        }
+        None => Status::NotFound,
+    }
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_record_metric(metric_id: u32, value: u64) -> Status {
+    state::with(|s| match metric(s, metric_id) {
+        Some(metric) => {
+            metric.value = value;
+            Status::Ok
+        }
+        None => Status::NotFound,
+    })
+}
+
+#[no_mangle]
+pub extern "C" fn proxy_increment_metric(metric_id: u32, offset: i64) -> Status {
+    state::with(|s| match metric(s, metric_id) {
+        Some(metric) if metric.metric_type == MetricType::Histogram => Status::BadArgument,
+        Some(metric) => {
+            metric.value = metric.value.wrapping_add_signed(offset);
+            Status::Ok
+        }
+        None => Status::NotFound,
+    })
+}
+
+fn metric(s: &mut state::State, metric_id: u32) -> Option<&mut Metric> {
+    s.metrics.get_mut((metric_id as usize).wrapping_sub(1))
+}
@@ -0,0 +1,357 @@
+//! An in-process proxy-wasm host for end-to-end tests of the filters.
+//!
+//! The plugin is linked into the test binary for the native target and its
+//! hostcalls resolve to this crate, so the dispatcher, `pow-runtime` and the
+//! filter all run unchanged, one thread per host:
+//!
+//! ```ignore
+//! let host = Host::new(|context_id| Box::new(RuntimeBox::new(Plugin::new(context_id))));
+//! host.upstream("mempool", |_| Some(HttpResponse::ok("0000...")));
+//! assert!(host.start(None, config.as_bytes()));
+//! host.ticks(3);
+//!
+//! let stream = host.stream("10.0.0.1:1234");
+//! stream.request_headers(&[(":authority", "example.com"), (":path", "/")], true);
+//! host.tick();
+//! assert!(stream.request_resumed());
+//! ```
+//!
+//! Time is not simulated: a tick runs whatever is ready, timers included,
+//! against the wall clock.
+
+mod abi;
+mod state;
+
+use proxy_wasm::types::{Action, LogLevel, NewRootContext};
+use state::{find, property_path, Headers, StreamState};
+
+pub use state::{HttpCall, HttpResponse, LocalResponse};
+
+extern "C" {
+    fn proxy_on_context_create(context_id: u32, root_context_id: u32);
+    fn proxy_on_vm_start(context_id: u32, vm_configuration_size: usize) -> bool;
+    fn proxy_on_configure(context_id: u32, plugin_configuration_size: usize) -> bool;
+    fn proxy_on_tick(context_id: u32);
+    fn proxy_on_queue_ready(context_id: u32, queue_id: u32);
+    fn proxy_on_request_headers(context_id: u32, num_headers: usize, end_of_stream: bool)
+        -> Action;
+    fn proxy_on_request_body(context_id: u32, body_size: usize, end_of_stream: bool) -> Action;
+    fn proxy_on_request_trailers(context_id: u32, num_trailers: usize) -> Action;
+    fn proxy_on_response_headers(
+        context_id: u32,
+        num_headers: usize,
+        end_of_stream: bool,
+    ) -> Action;
+    fn proxy_on_response_body(context_id: u32, body_size: usize, end_of_stream: bool) -> Action;
+    fn proxy_on_response_trailers(context_id: u32, num_trailers: usize) -> Action;
+    fn proxy_on_http_call_response(
+        context_id: u32,
+        token_id: u32,
+        num_headers: usize,
+        body_size: usize,
+        num_trailers: usize,
+    );
+    fn proxy_on_done(context_id: u32) -> bool;
+    fn proxy_on_log(context_id: u32);
+    fn proxy_on_delete(context_id: u32);
+}
+
+/// Runs `f` with `context_id` as the effective context, like the host does
+/// around every callback.
+fn enter<R>(context_id: u32, f: impl FnOnce() -> R) -> R {
+    state::with(|s| s.effective = context_id);
+    f()
+}
+
+fn lowercase(headers: &[(&str, &str)]) -> Headers {
+    headers
+        .iter()
+        .map(|(k, v)| (k.to_ascii_lowercase(), v.to_string()))
+        .collect()
+}
+
+/// A single plugin root context and the streams it filters.
+///
+/// The host state is thread local, so only one `Host` should be alive per
+/// thread. Each `#[test]` runs on a thread of its own.
+pub struct Host {
+    root_id: u32,
+}
+
+impl Host {
+    pub fn new(root: NewRootContext) -> Self {
+        let root_id = state::with(|s| {
+            s.reset();
+            s.new_context_id()
+        });
+        proxy_wasm::set_root_context(root);
+        enter(root_id, || unsafe { proxy_on_context_create(root_id, 0) });
+        Self { root_id }
+    }
+
+    /// Runs `on_vm_start` then `on_configure`, `false` if either refused.
+    pub fn start(&self, vm_configuration: Option<&[u8]>, plugin_configuration: &[u8]) -> bool {
+        let size = vm_configuration.map_or(0, <[u8]>::len);
+        state::with(|s| s.vm_configuration = vm_configuration.map(<[u8]>::to_vec));
+        enter(self.root_id, || unsafe {
+            proxy_on_vm_start(self.root_id, size)
+        }) && self.configure(plugin_configuration)
+    }
+
+    /// Delivers a new plugin configuration, as Envoy does on a config update.
+    pub fn configure(&self, plugin_configuration: &[u8]) -> bool {
+        state::with(|s| s.plugin_configuration = Some(plugin_configuration.to_vec()));
+        enter(self.root_id, || unsafe {
+            proxy_on_configure(self.root_id, plugin_configuration.len())
+        })
+    }
+
+    /// Scripts the cluster `name`. `None` fails the callout, as a reset or
+    /// timeout would.
+    pub fn upstream(
+        &self,
+        name: &str,
+        handler: impl Fn(&HttpCall) -> Option<HttpResponse> + 'static,
+    ) {
+        state::with(|s| {
+            s.upstreams
+                .insert(name.to_string(), std::rc::Rc::new(handler))
+        });
+    }
+
+    /// Delivers callout responses and queue notifications, then fires the
+    /// timer of the root context once.
+    pub fn tick(&self) {
+        let calls: Vec<_> = state::with(|s| s.pending_calls.drain(..).collect());
+        for (token, response) in calls {
+            let (num_headers, body_size) = response
+                .as_ref()
+                .map_or((0, 0), |r| (r.header_map().len(), r.body.len()));
+            state::with(|s| s.http_call_response = response);
+            enter(self.root_id, || unsafe {
+                proxy_on_http_call_response(self.root_id, token, num_headers, body_size, 0)
+            });
+            state::with(|s| s.http_call_response = None);
+        }
+
+        let ready: Vec<_> = state::with(|s| std::mem::take(&mut s.ready_queues));
+        for (owner, queue_id) in ready {
+            enter(owner, || unsafe { proxy_on_queue_ready(owner, queue_id) });
+        }
+
+        if state::with(|s| s.tick_periods.get(&self.root_id).is_some_and(|p| *p > 0)) {
+            enter(self.root_id, || unsafe { proxy_on_tick(self.root_id) });
+        }
+    }
+
+    pub fn ticks(&self, n: usize) {
+        for _ in 0..n {
+            self.tick();
+        }
+    }
+
+    /// Ticks until `done` holds, `false` if it still doesn't after `max` ticks.
+    pub fn tick_until(&self, max: usize, mut done: impl FnMut() -> bool) -> bool {
+        for _ in 0..max {
+            if done() {
+                return true;
+            }
+            self.tick();
+        }
+        done()
+    }
+
+    /// Every `http_call` dispatched so far, in order.
+    pub fn http_calls(&self) -> Vec<HttpCall> {
+        state::with(|s| s.http_calls.clone())
+    }
+
+    pub fn shared_data(&self, key: &str) -> Option<Vec<u8>> {
+        state::with(|s| s.shared_data.get(key).map(|(value, _)| value.clone()))
+    }
+
+    /// Writes shared data as another plugin of the VM would, bumping its CAS.
+    pub fn set_shared_data(&self, key: &str, value: &[u8]) {
+        state::with(|s| {
+            let cas = s.shared_data.get(key).map_or(1, |(_, cas)| cas + 1);
+            s.shared_data.insert(key.to_string(), (value.to_vec(), cas));
+        })
+    }
+
+    pub fn metric(&self, name: &str) -> Option<u64> {
+        state::with(|s| s.metrics.iter().find(|m| m.name == name).map(|m| m.value))
+    }
+
+    pub fn logs(&self) -> Vec<(LogLevel, String)> {
+        state::with(|s| s.logs.clone())
+    }
+
+    /// Opens a downstream stream from `client_address`, e.g. `10.0.0.1:1234`.
+    pub fn stream(&self, client_address: &str) -> Stream {
+        let id = state::with(|s| {
+            let id = s.new_context_id();
+            s.streams.insert(id, StreamState::default());
+            id
+        });
+        enter(id, || unsafe { proxy_on_context_create(id, self.root_id) });
+        let stream = Stream { id };
+        stream.set_property(&["source", "address"], client_address.as_bytes());
+        stream
+    }
+}
+
+/// A downstream HTTP stream, driven callback by callback.
+pub struct Stream {
+    id: u32,
+}
+
+impl Stream {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    fn with<R>(&self, f: impl FnOnce(&mut StreamState) -> R) -> R {
+        state::with(|s| {
+            f(s.streams
+                .get_mut(&self.id)
+                .expect("stream already finished"))
+        })
+    }
+
+    pub fn set_property(&self, path: &[&str], value: &[u8]) {
+        self.with(|s| s.properties.insert(property_path(path), value.to_vec()));
+    }
+
+    pub fn property(&self, path: &[&str]) -> Option<Vec<u8>> {
+        self.with(|s| s.properties.get(&property_path(path)).cloned())
+    }
+
+    pub fn request_headers(&self, headers: &[(&str, &str)], end_of_stream: bool) -> Action {
+        let headers = lowercase(headers);
+        let num_headers = headers.len();
+        self.with(|s| s.request_headers = headers);
+        enter(self.id, || unsafe {
+            proxy_on_request_headers(self.id, num_headers, end_of_stream)
+        })
+    }
+
+    /// Appends a chunk to the buffered request body.
+    pub fn request_body(&self, chunk: &[u8], end_of_stream: bool) -> Action {
+        let body_size = self.with(|s| {
+            s.request_body.extend_from_slice(chunk);
+            s.request_body.len()
+        });
+        enter(self.id, || unsafe {
+            proxy_on_request_body(self.id, body_size, end_of_stream)
+        })
+    }
+
+    pub fn request_trailers(&self, trailers: &[(&str, &str)]) -> Action {
+        let trailers = lowercase(trailers);
+        let num_trailers = trailers.len();
+        self.with(|s| s.request_trailers = trailers);
+        enter(self.id, || unsafe {
+            proxy_on_request_trailers(self.id, num_trailers)
+        })
+    }
+
+    pub fn response_headers(&self, headers: &[(&str, &str)], end_of_stream: bool) -> Action {
+        let headers = lowercase(headers);
+        let num_headers = headers.len();
+        self.with(|s| s.response_headers = headers);
+        enter(self.id, || unsafe {
+            proxy_on_response_headers(self.id, num_headers, end_of_stream)
+        })
+    }
+
+    /// Appends a chunk to the buffered response body.
+    pub fn response_body(&self, chunk: &[u8], end_of_stream: bool) -> Action {
+        let body_size = self.with(|s| {
+            s.response_body.extend_from_slice(chunk);
+            s.response_body.len()
+        });
+        enter(self.id, || unsafe {
+            proxy_on_response_body(self.id, body_size, end_of_stream)
+        })
+    }
+
+    pub fn response_trailers(&self, trailers: &[(&str, &str)]) -> Action {
+        let trailers = lowercase(trailers);
+        let num_trailers = trailers.len();
+        self.with(|s| s.response_trailers = trailers);
+        enter(self.id, || unsafe {
+            proxy_on_response_trailers(self.id, num_trailers)
+        })
+    }
+
+    /// Request headers as they stand now, i.e. as forwarded once resumed.
+    pub fn request_header(&self, name: &str) -> Option<String> {
+        self.with(|s| find(&s.request_headers, name).map(str::to_string))
+    }
+
+    pub fn response_header(&self, name: &str) -> Option<String> {
+        self.with(|s| find(&s.response_headers, name).map(str::to_string))
+    }
+
+    pub fn request_body_bytes(&self) -> Vec<u8> {
+        self.with(|s| s.request_body.clone())
+    }
+
+    pub fn response_body_bytes(&self) -> Vec<u8> {
+        self.with(|s| s.response_body.clone())
+    }
+
+    pub fn request_resumed(&self) -> bool {
+        self.with(|s| s.request_resumed)
+    }
+
+    pub fn response_resumed(&self) -> bool {
+        self.with(|s| s.response_resumed)
+    }
+
+    pub fn local_response(&self) -> Option<LocalResponse> {
+        self.with(|s| s.local_response.clone())
+    }
+
+    /// Ends the stream the way Envoy does: `on_done`, `on_log`, `on_delete`.
+    pub fn finish(self) {
+        enter(self.id, || unsafe {
+            proxy_on_done(self.id);
+            proxy_on_log(self.id);
+            proxy_on_delete(self.id);
+        });
+        state::with(|s| s.streams.remove(&self.id));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn maps_round_trip() {
+        let headers: Headers = vec![
+            (":path".to_string(), "/".to_string()),
+            ("x-empty".to_string(), String::new()),
+        ];
+        let mut native = (headers.len()).to_le_bytes().to_vec();
+        for (k, v) in &headers {
+            native.extend(k.len().to_le_bytes());
+            native.extend(v.len().to_le_bytes());
+        }
+        for (k, v) in &headers {
+            native.extend(k.as_bytes());
+            native.push(0);
+            native.extend(v.as_bytes());
+            native.push(0);
+        }
+        assert_eq!(state::decode_map(&native), headers);
+
+        let encoded = state::encode_map(&headers);
+        assert_eq!(&encoded[..4], &2u32.to_le_bytes());
+        assert_eq!(
+            encoded.len(),
+            4 + 2 * 8 + ":path".len() + 1 + "/".len() + 1 + 1 + "x-empty".len() + 1
+        );
+    }
+}
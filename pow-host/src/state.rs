@@ -0,0 +1,255 @@
+use std::{
+    cell::RefCell,
+    collections::{HashMap, VecDeque},
+    rc::Rc,
+    time::Duration,
+};
+
+use proxy_wasm::types::{LogLevel, MapType, MetricType};
+
+pub(crate) type Headers = Vec<(String, String)>;
+pub(crate) type Upstream = Rc<dyn Fn(&HttpCall) -> Option<HttpResponse>>;
+
+/// An `http_call` the plugin dispatched.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpCall {
+    pub upstream: String,
+    pub headers: Headers,
+    pub body: Option<Vec<u8>>,
+    pub trailers: Headers,
+    pub timeout: Duration,
+}
+
+impl HttpCall {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find(&self.headers, name)
+    }
+}
+
+/// What a scripted upstream answers to an `http_call`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpResponse {
+    pub status: u32,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+}
+
+impl HttpResponse {
+    pub fn new(status: u32, body: impl Into<Vec<u8>>) -> Self {
+        Self {
+            status,
+            headers: vec![],
+            body: body.into(),
+        }
+    }
+
+    pub fn ok(body: impl Into<Vec<u8>>) -> Self {
+        Self::new(200, body)
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers
+            .push((name.to_ascii_lowercase(), value.to_string()));
+        self
+    }
+
+    /// Headers as the plugin sees them, with the `:status` pseudo header.
+    pub(crate) fn header_map(&self) -> Headers {
+        let mut headers = vec![(":status".to_string(), self.status.to_string())];
+        headers.extend(self.headers.iter().cloned());
+        headers
+    }
+}
+
+/// A response the plugin sent instead of forwarding the stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalResponse {
+    pub status: u32,
+    pub details: String,
+    pub headers: Headers,
+    pub body: Vec<u8>,
+}
+
+impl LocalResponse {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find(&self.headers, name)
+    }
+
+    pub fn body_str(&self) -> &str {
+        std::str::from_utf8(&self.body).expect("local response body is not utf8")
+    }
+}
+
+pub(crate) fn find<'a>(headers: &'a Headers, name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+#[derive(Default)]
+pub(crate) struct StreamState {
+    pub request_headers: Headers,
+    pub request_body: Vec<u8>,
+    pub request_trailers: Headers,
+    pub response_headers: Headers,
+    pub response_body: Vec<u8>,
+    pub response_trailers: Headers,
+    pub properties: HashMap<Vec<u8>, Vec<u8>>,
+    pub request_resumed: bool,
+    pub response_resumed: bool,
+    pub closed: bool,
+    pub local_response: Option<LocalResponse>,
+}
+
+pub(crate) struct Queue {
+    pub name: String,
+    pub owner: u32,
+    pub items: VecDeque<Vec<u8>>,
+}
+
+pub(crate) struct Metric {
+    pub name: String,
+    pub metric_type: MetricType,
+    pub value: u64,
+}
+
+/// Everything the host keeps between hostcalls. Thread local, so every test
+/// thread gets a host of its own, the same way proxy-wasm keeps its dispatcher.
+#[derive(Default)]
+pub(crate) struct State {
+    /// Context the hostcalls act on, as set by `set_effective_context`.
+    pub effective: u32,
+    pub next_context_id: u32,
+    pub logs: Vec<(LogLevel, String)>,
+    pub tick_periods: HashMap<u32, u32>,
+    pub vm_configuration: Option<Vec<u8>>,
+    pub plugin_configuration: Option<Vec<u8>>,
+    pub streams: HashMap<u32, StreamState>,
+    pub properties: HashMap<Vec<u8>, Vec<u8>>,
+    pub shared_data: HashMap<String, (Vec<u8>, u32)>,
+    /// Indexed by queue id minus one.
+    pub queues: Vec<Queue>,
+    /// Queues that got data since the last tick, as (owner, queue id).
+    pub ready_queues: Vec<(u32, u32)>,
+    pub upstreams: HashMap<String, Upstream>,
+    pub http_calls: Vec<HttpCall>,
+    pub next_token: u32,
+    /// Answers delivered on the next tick, `None` for a failed callout.
+    pub pending_calls: VecDeque<(u32, Option<HttpResponse>)>,
+    /// The callout response being delivered right now.
+    pub http_call_response: Option<HttpResponse>,
+    /// Indexed by metric id minus one.
+    pub metrics: Vec<Metric>,
+}
+
+thread_local! {
+    static STATE: RefCell<State> = RefCell::new(State::default());
+}
+
+pub(crate) fn with<R>(f: impl FnOnce(&mut State) -> R) -> R {
+    STATE.with(|state| f(&mut state.borrow_mut()))
+}
+
+impl State {
+    /// Starts over, except for context ids: the proxy-wasm dispatcher of this
+    /// thread still remembers the contexts of an earlier host.
+    pub fn reset(&mut self) {
+        let next_context_id = self.next_context_id;
+        *self = State {
+            next_context_id,
+            ..State::default()
+        };
+    }
+
+    pub fn new_context_id(&mut self) -> u32 {
+        self.next_context_id += 1;
+        self.next_context_id
+    }
+
+    pub fn stream(&mut self) -> Option<&mut StreamState> {
+        self.streams.get_mut(&self.effective)
+    }
+
+    pub fn map(&mut self, map_type: MapType) -> Option<&mut Headers> {
+        match map_type {
+            MapType::HttpRequestHeaders => self.stream().map(|s| &mut s.request_headers),
+            MapType::HttpRequestTrailers => self.stream().map(|s| &mut s.request_trailers),
+            MapType::HttpResponseHeaders => self.stream().map(|s| &mut s.response_headers),
+            MapType::HttpResponseTrailers => self.stream().map(|s| &mut s.response_trailers),
+            _ => None,
+        }
+    }
+
+    /// Read only view of a map, including the ones of a callout response.
+    pub fn read_map(&mut self, map_type: MapType) -> Option<Headers> {
+        match map_type {
+            MapType::HttpCallResponseHeaders => self
+                .http_call_response
+                .as_ref()
+                .map(HttpResponse::header_map),
+            MapType::HttpCallResponseTrailers => self.http_call_response.as_ref().map(|_| vec![]),
+            map_type => self.map(map_type).cloned(),
+        }
+    }
+
+    pub fn property(&mut self, path: &[u8]) -> Option<Vec<u8>> {
+        self.stream()
+            .and_then(|s| s.properties.get(path).cloned())
+            .or_else(|| self.properties.get(path).cloned())
+    }
+
+    pub fn set_property(&mut self, path: Vec<u8>, value: Vec<u8>) {
+        match self.streams.get_mut(&self.effective) {
+            Some(stream) => stream.properties.insert(path, value),
+            None => self.properties.insert(path, value),
+        };
+    }
+}
+
+/// Property paths are their segments joined by NUL.
+pub(crate) fn property_path(path: &[&str]) -> Vec<u8> {
+    path.join("\0").into_bytes()
+}
+
+/// Maps sent to the plugin use 32-bit lengths, as proxy-wasm reads them.
+pub(crate) fn encode_map(map: &Headers) -> Vec<u8> {
+    let mut bytes = (map.len() as u32).to_le_bytes().to_vec();
+    for (k, v) in map {
+        bytes.extend((k.len() as u32).to_le_bytes());
+        bytes.extend((v.len() as u32).to_le_bytes());
+    }
+    for (k, v) in map {
+        bytes.extend(k.as_bytes());
+        bytes.push(0);
+        bytes.extend(v.as_bytes());
+        bytes.push(0);
+    }
+    bytes
+}
+
+/// Maps from the plugin use `usize` lengths, which natively are 64-bit.
+pub(crate) fn decode_map(bytes: &[u8]) -> Headers {
+    const WIDTH: usize = std::mem::size_of::<usize>();
+    let read = |at: usize| {
+        let mut raw = [0; WIDTH];
+        raw.copy_from_slice(&bytes[at..at + WIDTH]);
+        usize::from_le_bytes(raw)
+    };
+    if bytes.is_empty() {
+        return vec![];
+    }
+    let count = read(0);
+    let mut data = WIDTH + count * 2 * WIDTH;
+    let mut map = Vec::with_capacity(count);
+    for i in 0..count {
+        let key_len = read(WIDTH + i * 2 * WIDTH);
+        let value_len = read(WIDTH + i * 2 * WIDTH + WIDTH);
+        let key = String::from_utf8_lossy(&bytes[data..data + key_len]).into_owned();
+        data += key_len + 1;
+        let value = String::from_utf8_lossy(&bytes[data..data + value_len]).into_owned();
+        data += value_len + 1;
+        map.push((key, value));
+    }
+    map
+}
@@ -7,8 +7,9 @@ async function main() {
 		const difficulty = document.getElementById('difficulty').value
 		const path = document.getElementById('path').value
 		const current = document.getElementById('current').value
+		const server_time = parseInt(document.getElementById('server_time').value) || undefined
 		const timestamp = new Date().getTime() / 1000 | 0
-		worker.postMessage({ difficulty, path, current, timestamp })
+		worker.postMessage({ difficulty, path, current, timestamp, server_time })
 
 		worker.onmessage = event => {
 			mineButton.disabled = false
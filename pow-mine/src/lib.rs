@@ -21,7 +21,10 @@ struct MineArgs {
     path: String,
     current: ByteArray32,
     difficulty: ByteArray32,
-    timestamp: u64,
+    /// Local clock, only used when the challenge carries no `server_time`.
+    timestamp: Option<u64>,
+    /// `server_time` from the 429 challenge body.
+    server_time: Option<u64>,
 }
 
 #[derive(Debug, serde::Serialize)]
@@ -41,28 +44,34 @@ pub fn mine(args: JsValue) -> Result<JsValue, JsError> {
         Err(err) => return Err(JsError::new(&format!("{}", err))),
     };
 
-    let result = mine_impl(args);
-    
+    let result = mine_impl(args).map_err(JsError::new)?;
+
     match to_value(&result) {
         Ok(value) => Ok(value),
         Err(err) => Err(JsError::new(&format!("{}", err))),
     }
 }
 
-fn mine_impl(args: MineArgs) -> MineResult {
+fn mine_impl(args: MineArgs) -> Result<MineResult, &'static str> {
+    // Prefer the server clock, so a skewed client clock can't push the proof
+    // outside the accepted timestamp window.
+    let timestamp = args
+        .server_time
+        .or(args.timestamp)
+        .ok_or("either server_time or timestamp is required")?;
     let mut data = args.current.as_bytes().to_vec();
-    data.extend(args.timestamp.to_be_bytes());
+    data.extend(timestamp.to_be_bytes());
     data.extend(args.path.as_bytes());
     loop {
         let nonce = rand::random::<[u8; 8]>();
         if valid_nonce(&data, args.difficulty, &nonce) {
             let hex_nonce = format!("{:x}", LowerHexSlice(&nonce));
             log::debug!("found nonce: {}", hex_nonce);
-            return MineResult {
+            return Ok(MineResult {
                 nonce: hex_nonce,
-                timestamp: args.timestamp.to_string(),
+                timestamp: timestamp.to_string(),
                 base: format!("{:x}", LowerHexSlice(args.current.as_bytes())),
-            }
+            })
         }
     }
 }
@@ -0,0 +1,82 @@
+use proxy_wasm::{
+    hostcalls,
+    types::{MapType, Status},
+};
+
+/// Header changes a hook asks for, applied to the headers of the paused
+/// direction right before the stream continues. Removals are applied first,
+/// then replacements, then additions.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct HeaderMutations {
+    pub remove: Vec<String>,
+    pub set: Vec<(String, String)>,
+    pub add: Vec<(String, String)>,
+}
+
+impl HeaderMutations {
+    pub fn remove(mut self, name: impl Into<String>) -> Self {
+        self.remove.push(name.into());
+        self
+    }
+
+    pub fn set(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.set.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn add(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.add.push((name.into(), value.into()));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.remove.is_empty() && self.set.is_empty() && self.add.is_empty()
+    }
+
+    /// Applies the changes to `map` of the current effective context. Values
+    /// that could split the header block are refused before anything is
+    /// applied, since the host would otherwise abort the plugin.
+    pub(crate) fn apply(&self, map: MapType) -> Result<(), Status> {
+        let mut values = self.set.iter().chain(&self.add).map(|(_, value)| value);
+        if values.any(|value| value.contains(['\r', '\n', '\0'])) {
+            return Err(Status::BadArgument);
+        }
+        for name in &self.remove {
+            hostcalls::set_map_value(map, name, None)?;
+        }
+        for (name, value) in &self.set {
+            hostcalls::set_map_value(map, name, Some(value.as_str()))?;
+        }
+        for (name, value) in &self.add {
+            hostcalls::add_map_value(map, name, value)?;
+        }
+        Ok(())
+    }
+}
+
+impl From<()> for HeaderMutations {
+    fn from(_: ()) -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::HeaderMutations;
+
+    #[test]
+    fn builder() {
+        let mutations = HeaderMutations::default()
+            .remove("X-PoW-Nonce")
+            .set("X-PoW-Verified-Difficulty", "100")
+            .add("Via", "pow");
+        assert_eq!(mutations.remove, vec!["X-PoW-Nonce".to_string()]);
+        assert_eq!(
+            mutations.set,
+            vec![("X-PoW-Verified-Difficulty".to_string(), "100".to_string())]
+        );
+        assert_eq!(mutations.add, vec![("Via".to_string(), "pow".to_string())]);
+        assert!(!mutations.is_empty());
+        assert!(HeaderMutations::from(()).is_empty());
+    }
+}
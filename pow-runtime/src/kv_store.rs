@@ -1,7 +1,6 @@
-use std::{collections::VecDeque, marker::PhantomData, time::Duration};
+use std::{marker::PhantomData, time::Duration};
 
 use proxy_wasm::{hostcalls, types::Status};
-use serde::{Deserialize, Serialize};
 
 use super::codec::Codec;
 
@@ -141,48 +140,24 @@ where
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-struct Expirations {
-    list: VecDeque<(u64, String)>,
-}
-
-impl Expirations {
-    fn new() -> Self {
-        Self {
-            list: VecDeque::new(),
-        }
-    }
-
-    fn push(&mut self, key: String, ttl: Duration) {
-        let expiration = Self::now() + ttl.as_secs();
-        self.list.push_back((expiration, key));
-        self.list.make_contiguous().sort();
-    }
-
-    fn now() -> u64 {
-        std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
-    }
+/// Seconds a single `gc` collects at most, a store left alone for long
+/// catches up over several calls.
+const GC_MAX_SECS: u64 = 60;
 
-    fn pop_expired(&mut self) -> Vec<String> {
-        let now = Self::now();
-        let mut expired = Vec::new();
-        while let Some((expiration, key)) = self.list.front() {
-            if *expiration > now {
-                break;
-            }
-            expired.push(key.clone());
-            self.list.pop_front();
-        }
-        expired
-    }
+fn now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
 }
 
 pub struct ExpiringKVStore<V> {
     store: KVStore<V>,
-    expirations: KVStore<Expirations>
+    /// Keys to remove, sharded by the second they expire at so that writers
+    /// only contend with keys expiring at the same time.
+    expirations: KVStore<Vec<String>>,
+    /// The last second whose keys were removed.
+    collected: KVStore<u64>,
 }
 
 impl <V> ExpiringKVStore<V>
@@ -193,7 +168,8 @@ where
     pub fn new(context_id: u32, prefix: &str) -> Self {
         Self {
             store: KVStore::new(context_id, prefix),
-            expirations: KVStore::new(context_id, &format!("{}:expirations", prefix)),
+            expirations: KVStore::new(context_id, &format!("{}:expirations:", prefix)),
+            collected: KVStore::new(context_id, &format!("{}:collected", prefix)),
         }
     }
 
@@ -217,29 +193,90 @@ where
         self.store.update(key, f)
     }
 
+    /// Removes `key` once `ttl` passed, within a second or so.
     pub fn enqueue_expires(&self, key: &str, ttl: Duration) -> Result<(), Error> {
-        let _ = self.expirations.update("", |expirations| {
-            let mut expirations = expirations.unwrap_or_else(Expirations::new);
-            expirations.push(key.to_string(), ttl);
-            expirations
+        let second = now() + ttl.as_secs();
+        self.expirations.update(&second.to_string(), |keys| {
+            let mut keys = keys.unwrap_or_default();
+            keys.push(key.to_string());
+            keys
         })?;
         self.gc()
     }
 
     pub fn gc(&self) -> Result<(), Error> {
-        let mut expired = vec![];
-        let _ = self.expirations.update("", |expirations| {
-            let Some(mut expirations) = expirations else {
-                return Expirations::new();
-            };
-            expired = expirations.pop_expired();
-            expirations
-        })?;
+        self.collect(now())
+    }
 
-        for key in expired {
-            self.store.remove(&key)?;
+    /// Removes the keys of the full seconds before `now` that no worker
+    /// collected yet.
+    fn collect(&self, now: u64) -> Result<(), Error> {
+        let until = now.saturating_sub(1);
+        if self.collected.get("")?.is_some_and(|last| last >= until) {
+            return Ok(());
         }
+        // Claims the seconds, so that only one worker walks each of them.
+        let mut claimed = 0..0;
+        self.collected.update("", |last| {
+            let start = last.map_or(until, |last| last + 1);
+            let end = until.min(start.saturating_add(GC_MAX_SECS - 1));
+            claimed = start..end + 1;
+            last.map_or(end, |last| last.max(end))
+        })?;
 
+        for second in claimed {
+            let shard = second.to_string();
+            let Some(keys) = self.expirations.get(&shard)? else {
+                continue;
+            };
+            for key in keys {
+                self.store.remove(&key)?;
+            }
+            self.expirations.remove(&shard)?;
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use pow_host::Host;
+    use proxy_wasm::traits::{Context, RootContext};
+
+    use super::*;
+
+    struct Root;
+
+    impl Context for Root {}
+
+    impl RootContext for Root {}
+
+    #[test]
+    fn expires_keys_by_second() {
+        let host = Host::new(|_| Box::new(Root));
+        let store = ExpiringKVStore::<u64>::new(0, "test:");
+        let now = now();
+        store.store.put("a", &1).unwrap();
+        store.store.put("b", &2).unwrap();
+        store.enqueue_expires("a", Duration::from_secs(5)).unwrap();
+        store.enqueue_expires("b", Duration::from_secs(600)).unwrap();
+
+        store.collect(now + 2).unwrap();
+        assert_eq!(store.get("a").unwrap(), Some(1));
+        store.collect(now + 10).unwrap();
+        assert_eq!(store.get("a").unwrap(), None);
+        assert_eq!(store.get("b").unwrap(), Some(2));
+        assert_eq!(
+            host.shared_data("test::collected"),
+            Some((now + 9).encode().unwrap())
+        );
+
+        // A long idle store catches up a minute per call.
+        store.collect(now + 700).unwrap();
+        assert_eq!(store.get("b").unwrap(), Some(2));
+        for _ in 0..12 {
+            store.collect(now + 700).unwrap();
+        }
+        assert_eq!(store.get("b").unwrap(), None);
+    }
+}
@@ -4,22 +4,30 @@ pub mod task {
 }
 pub mod codec;
 pub mod counter_bucket;
+pub mod headers;
 pub mod kv_store;
 pub mod lock;
 pub mod log_level;
+pub mod metrics;
 pub mod promise;
 pub mod queue;
 pub mod response;
 pub mod timeout;
 
-use std::{future::Future, rc::Rc, time::Duration};
+use std::{
+    cell::RefCell,
+    future::Future,
+    rc::Rc,
+    time::{Duration, Instant},
+};
 
+use headers::HeaderMutations;
 use lock::{wake_tasks, QueueId};
 use promise::{Promise, PENDINGS};
 use proxy_wasm::{
     hostcalls,
     traits::{Context, HttpContext, RootContext},
-    types::{Action, Status},
+    types::{Action, MapType, Status},
 };
 use response::Response;
 
@@ -57,7 +65,7 @@ pub fn http_call(
 
 pub trait Runtime: Context {
     type Hook: HttpHook + 'static;
-    fn on_vm_start(&mut self, _vm_configuration_size: usize) -> bool {
+    fn on_vm_start(&mut self, _vm_configuration: Option<Vec<u8>>) -> bool {
         true
     }
 
@@ -109,7 +117,8 @@ impl<R: Runtime> Context for RuntimeBox<R> {
 impl<R: Runtime> RootContext for RuntimeBox<R> {
     fn on_vm_start(&mut self, _vm_configuration_size: usize) -> bool {
         self.set_tick_period(Duration::from_millis(1));
-        self.inner.on_vm_start(_vm_configuration_size)
+        let content = self.get_vm_configuration();
+        self.inner.on_vm_start(content)
     }
 
     fn on_configure(&mut self, _plugin_configuration_size: usize) -> bool {
@@ -160,6 +169,11 @@ impl Ctx {
         })?;
         Ok(Some(addr))
     }
+    pub fn set_property(&self, path: Vec<&str>, value: Option<&[u8]>) -> Result<(), Status> {
+        hostcalls::set_effective_context(self.id)?;
+        hostcalls::set_property(path, value)
+    }
+
     pub fn get_http_request_headers(&self) -> Result<Vec<(String, String)>, Status> {
         hostcalls::set_effective_context(self.id)?;
         Ok(HttpContext::get_http_request_headers(self))
@@ -180,6 +194,19 @@ impl Ctx {
         hostcalls::resume_http_request()
     }
 
+    fn apply_headers(&self, map: MapType, mutations: &HeaderMutations) -> Result<(), Status> {
+        if mutations.is_empty() {
+            return Ok(());
+        }
+        hostcalls::set_effective_context(self.id)?;
+        mutations.apply(map)
+    }
+
+    fn continue_response(&self) -> Result<(), Status> {
+        hostcalls::set_effective_context(self.id)?;
+        hostcalls::resume_http_response()
+    }
+
     fn reject_request(
         &self,
         status: u32,
@@ -196,21 +223,212 @@ impl Ctx {
     }
 }
 
+/// Optional stages of an HTTP stream a hook takes part in. The request
+/// headers stage is always driven.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Interest {
+    /// Buffer the request body up to this many bytes and call `on_request_body`.
+    /// Larger bodies are rejected with 413.
+    pub request_body: Option<usize>,
+    /// Pause the response headers until `on_response_headers` resolves.
+    pub response_headers: bool,
+    /// Buffer the response body up to this many bytes and call `on_response_body`.
+    /// Larger bodies are rejected with 502.
+    pub response_body: Option<usize>,
+}
+
 pub trait HttpHook {
     fn filter_name() -> Option<&'static str> {
         None
     }
 
+    fn interest(&self) -> Interest {
+        Interest::default()
+    }
+
+    /// Decides whether the request continues. The returned `HeaderMutations`
+    /// are applied to the request headers right before the request continues,
+    /// together with those returned by `on_request_body`.
     fn on_request_headers(
         &self,
         _num_headers: usize,
         _end_of_stream: bool,
-    ) -> impl Future<Output = Result<(), impl Into<Response>>> + Send;
+    ) -> impl Future<Output = Result<impl Into<HeaderMutations>, impl Into<Response>>> + Send;
+
+    /// Called once with the complete request body, after `on_request_headers`
+    /// let the request through. The body is empty if the request has none.
+    fn on_request_body(
+        &self,
+        _body: Vec<u8>,
+    ) -> impl Future<Output = Result<impl Into<HeaderMutations>, impl Into<Response>>> + Send {
+        async { Ok::<(), Response>(()) }
+    }
+
+    fn on_response_headers(
+        &self,
+        _num_headers: usize,
+        _end_of_stream: bool,
+    ) -> impl Future<Output = Result<impl Into<HeaderMutations>, impl Into<Response>>> + Send {
+        async { Ok::<(), Response>(()) }
+    }
+
+    /// Called once with the complete response body, after `on_response_headers`
+    /// let the response through. The body is empty if the response has none.
+    fn on_response_body(
+        &self,
+        _body: Vec<u8>,
+    ) -> impl Future<Output = Result<impl Into<HeaderMutations>, impl Into<Response>>> + Send {
+        async { Ok::<(), Response>(()) }
+    }
+
+    /// Called when the stream is done, after the response was sent downstream.
+    fn on_log(&self) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+
+    /// Called with the upstream status code and the latency from request start
+    /// to response headers, only for requests this hook let through.
+    fn on_upstream_response(&self, _status: u32, _latency: Duration) {}
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Direction {
+    Request,
+    Response,
+}
+
+/// Progress of one direction of the stream.
+#[derive(Default)]
+struct Half {
+    /// Set once the headers hook let this direction through.
+    headers_passed: bool,
+    /// Header changes to apply once this direction continues.
+    mutations: HeaderMutations,
+    /// Size of the body buffered by the host so far.
+    body_size: usize,
+    /// Complete body, kept until the headers hook finished.
+    body: Option<Vec<u8>>,
+}
+
+#[derive(Default)]
+struct Stream {
+    request: Half,
+    response: Half,
+    /// Set once a local response was sent, no further stages are driven.
+    rejected: bool,
+    started_at: Option<Instant>,
+    forwarded_at: Option<Instant>,
+}
+
+impl Stream {
+    fn half(&mut self, direction: Direction) -> &mut Half {
+        match direction {
+            Direction::Request => &mut self.request,
+            Direction::Response => &mut self.response,
+        }
+    }
+}
+
+fn reply(ctx: &Ctx, resp: Response) -> Result<(), Status> {
+    let headers: Vec<(&str, &str)> = resp
+        .headers
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.as_str()))
+        .collect();
+    ctx.reject_request(resp.code, headers, resp.body.as_deref())
+}
+
+fn too_large(code: u32) -> Response {
+    Response {
+        code,
+        headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
+        body: Some(b"body exceeds the buffer limit".to_vec()),
+        trailers: vec![],
+    }
+}
+
+fn internal_error() -> Response {
+    Response {
+        code: 500,
+        headers: vec![("Content-Type".to_string(), "text/plain".to_string())],
+        body: Some(b"failed to apply header changes".to_vec()),
+        trailers: vec![],
+    }
+}
+
+/// Continues the stream in `direction` with the pending header changes, or
+/// sends `resp` as a local response.
+fn resume(
+    ctx: &Ctx,
+    stream: &RefCell<Stream>,
+    direction: Direction,
+    res: Result<HeaderMutations, Response>,
+) -> Result<(), Status> {
+    let mutations = match res {
+        Ok(mutations) => mutations,
+        Err(resp) => {
+            stream.borrow_mut().rejected = true;
+            log::debug!("reject http {:?}", direction);
+            return reply(ctx, resp);
+        }
+    };
+    let mut stream = stream.borrow_mut();
+    let pending = &mut stream.half(direction).mutations;
+    pending.remove.extend(mutations.remove);
+    pending.set.extend(mutations.set);
+    pending.add.extend(mutations.add);
+    let pending = std::mem::take(pending);
+    let map = match direction {
+        Direction::Request => MapType::HttpRequestHeaders,
+        Direction::Response => MapType::HttpResponseHeaders,
+    };
+    if let Err(e) = ctx.apply_headers(map, &pending) {
+        // A paused stream is never resumed by the host, so answer it here.
+        log::warn!(
+            "failed to apply http {:?} header changes: {:?}",
+            direction,
+            e
+        );
+        stream.rejected = true;
+        return reply(ctx, internal_error());
+    }
+    match direction {
+        Direction::Request => {
+            stream.forwarded_at = stream.started_at;
+            ctx.continue_request()
+        }
+        Direction::Response => ctx.continue_response(),
+    }
+}
+
+async fn run_body<H: HttpHook>(
+    hook: Rc<H>,
+    ctx: Ctx,
+    stream: Rc<RefCell<Stream>>,
+    direction: Direction,
+    body: Vec<u8>,
+) {
+    let res = match direction {
+        Direction::Request => hook
+            .on_request_body(body)
+            .await
+            .map(Into::into)
+            .map_err(Into::into),
+        Direction::Response => hook
+            .on_response_body(body)
+            .await
+            .map(Into::into)
+            .map_err(Into::into),
+    };
+    if let Err(e) = resume(&ctx, &stream, direction, res) {
+        log::warn!("failed to resume http {:?}: {:?}", direction, e);
+    }
 }
 
 pub struct HookHolder<H: HttpHook + 'static> {
     context: Ctx,
     inner: Rc<H>,
+    stream: Rc<RefCell<Stream>>,
 }
 
 impl<H: HttpHook> HookHolder<H> {
@@ -218,47 +436,160 @@ impl<H: HttpHook> HookHolder<H> {
         Self {
             context: Ctx::new(context_id),
             inner: Rc::new(inner),
+            stream: Rc::new(RefCell::new(Stream::default())),
+        }
+    }
+
+    fn body_limit(&self, direction: Direction) -> Option<usize> {
+        let interest = self.inner.interest();
+        match direction {
+            Direction::Request => interest.request_body,
+            Direction::Response => interest.response_body,
+        }
+    }
+
+    /// Runs after the headers hook of `direction` let the stream through:
+    /// either continues it, or hands over to the body hook if the body is
+    /// complete already.
+    fn headers_passed(
+        hook: Rc<H>,
+        ctx: Ctx,
+        stream: Rc<RefCell<Stream>>,
+        direction: Direction,
+        mutations: HeaderMutations,
+        wants_body: bool,
+    ) -> Option<impl Future<Output = ()>> {
+        if !wants_body {
+            if let Err(e) = resume(&ctx, &stream, direction, Ok(mutations)) {
+                log::warn!("failed to resume http {:?}: {:?}", direction, e);
+            }
+            return None;
+        }
+        let body = {
+            let mut stream = stream.borrow_mut();
+            let half = stream.half(direction);
+            half.headers_passed = true;
+            half.mutations = mutations;
+            half.body.take()
+        };
+        // Without a complete body yet, the body callback drives the stream from here.
+        body.map(|body| run_body(hook, ctx, stream, direction, body))
+    }
+
+    fn on_body(&mut self, direction: Direction, body_size: usize, end_of_stream: bool) -> Action {
+        let Some(limit) = self.body_limit(direction) else {
+            return Action::Continue;
+        };
+        if self.stream.borrow().rejected {
+            return Action::Pause;
+        }
+        self.stream.borrow_mut().half(direction).body_size = body_size;
+        if body_size > limit {
+            log::debug!("http {:?} body exceeds {} bytes", direction, limit);
+            let code = match direction {
+                Direction::Request => 413,
+                Direction::Response => 502,
+            };
+            self.stream.borrow_mut().rejected = true;
+            if let Err(e) = reply(&self.context, too_large(code)) {
+                log::warn!("failed to reject http {:?}: {:?}", direction, e);
+            }
+            return Action::Pause;
+        }
+        if end_of_stream {
+            self.body_complete(direction);
+        }
+        Action::Pause
+    }
+
+    fn body_complete(&mut self, direction: Direction) {
+        let body_size = self.stream.borrow_mut().half(direction).body_size;
+        let body = match direction {
+            Direction::Request => self.get_http_request_body(0, body_size),
+            Direction::Response => self.get_http_response_body(0, body_size),
+        }
+        .unwrap_or_default();
+
+        let mut stream = self.stream.borrow_mut();
+        let half = stream.half(direction);
+        if !half.headers_passed {
+            half.body = Some(body);
+            return;
         }
+        drop(stream);
+        spawn_local(run_body(
+            self.inner.clone(),
+            self.context,
+            self.stream.clone(),
+            direction,
+            body,
+        ));
     }
 }
 
 impl<H: HttpHook> Context for HookHolder<H> {}
 
 impl<H: HttpHook> HttpContext for HookHolder<H> {
-    fn on_http_request_trailers(&mut self, _num_trailers: usize) -> Action {
-        let all = self.get_http_request_trailers();
-        log::info!("all trailers: {:?}", all);
-        Action::Continue
-    }
     fn on_http_request_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Action {
         log::debug!("on_http_request_headers");
         let hook = self.inner.clone();
         let ctx = self.context;
+        let stream = self.stream.clone();
+        stream.borrow_mut().started_at = Some(Instant::now());
+        let wants_body = self.body_limit(Direction::Request).is_some();
+        if wants_body && _end_of_stream {
+            stream.borrow_mut().request.body = Some(Vec::new());
+        }
         spawn_local(async move {
             let res = hook.on_request_headers(_num_headers, _end_of_stream).await;
-            let ret = match res {
-                Ok(()) => ctx.continue_request(),
+            match res.map(Into::into).map_err(Into::into) {
+                Ok(mutations) => {
+                    let next = Self::headers_passed(
+                        hook,
+                        ctx,
+                        stream,
+                        Direction::Request,
+                        mutations,
+                        wants_body,
+                    );
+                    if let Some(next) = next {
+                        next.await;
+                    }
+                }
                 Err(resp) => {
-                    let resp = resp.into();
-                    let code = resp.code;
-                    let headers: Vec<(&str, &str)> = resp
-                        .headers
-                        .iter()
-                        .map(|(k, v)| (k.as_str(), v.as_str()))
-                        .collect();
-                    log::debug!("reject http request");
-                    ctx.reject_request(code, headers, resp.body.as_deref())
+                    if let Err(e) = resume(&ctx, &stream, Direction::Request, Err(resp)) {
+                        log::warn!("failed to resume http request: {:?}", e);
+                    }
                 }
-            };
-            if let Err(e) = ret {
-                log::warn!("failed to resume http request: {:?}", e);
             }
         });
         Action::Pause
     }
 
+    fn on_http_request_body(&mut self, body_size: usize, end_of_stream: bool) -> Action {
+        self.on_body(Direction::Request, body_size, end_of_stream)
+    }
+
+    fn on_http_request_trailers(&mut self, _num_trailers: usize) -> Action {
+        log::debug!("on_http_request_trailers");
+        // Trailers end the stream without a final body callback.
+        if self.body_limit(Direction::Request).is_some() && !self.stream.borrow().rejected {
+            self.body_complete(Direction::Request);
+            return Action::Pause;
+        }
+        Action::Continue
+    }
+
     fn on_http_response_headers(&mut self, _num_headers: usize, _end_of_stream: bool) -> Action {
         log::debug!("on_http_response_headers");
+        let forwarded_at = self.stream.borrow_mut().forwarded_at.take();
+        if let Some(start) = forwarded_at {
+            let status = self
+                .get_http_response_header(":status")
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(0);
+            self.inner.on_upstream_response(status, start.elapsed());
+        }
         if let Some(name) = H::filter_name() {
             match self.get_http_response_header("X-Filter-Name") {
                 Some(previous) => {
@@ -268,6 +599,406 @@ impl<H: HttpHook> HttpContext for HookHolder<H> {
                 None => self.set_http_response_header("X-Filter-Name", Some(name)),
             }
         }
+
+        if self.stream.borrow().rejected {
+            return Action::Continue;
+        }
+        let wants_body = self.body_limit(Direction::Response).is_some();
+        if wants_body && _end_of_stream {
+            self.stream.borrow_mut().response.body = Some(Vec::new());
+        }
+        if !self.inner.interest().response_headers {
+            if !wants_body {
+                return Action::Continue;
+            }
+            // Hold the headers back, so the body hook can still replace the response.
+            if let Some(next) = Self::headers_passed(
+                self.inner.clone(),
+                self.context,
+                self.stream.clone(),
+                Direction::Response,
+                HeaderMutations::default(),
+                wants_body,
+            ) {
+                spawn_local(next);
+            }
+            return Action::Pause;
+        }
+
+        let hook = self.inner.clone();
+        let ctx = self.context;
+        let stream = self.stream.clone();
+        spawn_local(async move {
+            let res = hook.on_response_headers(_num_headers, _end_of_stream).await;
+            match res.map(Into::into).map_err(Into::into) {
+                Ok(mutations) => {
+                    let next = Self::headers_passed(
+                        hook,
+                        ctx,
+                        stream,
+                        Direction::Response,
+                        mutations,
+                        wants_body,
+                    );
+                    if let Some(next) = next {
+                        next.await;
+                    }
+                }
+                Err(resp) => {
+                    if let Err(e) = resume(&ctx, &stream, Direction::Response, Err(resp)) {
+                        log::warn!("failed to resume http response: {:?}", e);
+                    }
+                }
+            }
+        });
+        Action::Pause
+    }
+
+    fn on_http_response_body(&mut self, body_size: usize, end_of_stream: bool) -> Action {
+        self.on_body(Direction::Response, body_size, end_of_stream)
+    }
+
+    fn on_http_response_trailers(&mut self, _num_trailers: usize) -> Action {
+        log::debug!("on_http_response_trailers");
+        if self.body_limit(Direction::Response).is_some() && !self.stream.borrow().rejected {
+            self.body_complete(Direction::Response);
+            return Action::Pause;
+        }
         Action::Continue
     }
+
+    fn on_log(&mut self) {
+        let hook = self.inner.clone();
+        spawn_local(async move { hook.on_log().await });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        cell::{Cell, RefCell},
+        pin::Pin,
+        task::{Context as TaskContext, Poll},
+    };
+
+    use pow_host::Host;
+
+    use super::*;
+
+    thread_local! {
+        static INTEREST: Cell<Interest> = Cell::new(Interest::default());
+        /// Whether `on_request_headers` may resolve.
+        static OPEN: Cell<bool> = const { Cell::new(true) };
+        /// Hook calls, in order.
+        static CALLS: RefCell<Vec<String>> = const { RefCell::new(Vec::new()) };
+    }
+
+    fn record(call: String) {
+        CALLS.with(|calls| calls.borrow_mut().push(call));
+    }
+
+    fn calls() -> Vec<String> {
+        CALLS.with(|calls| calls.borrow().clone())
+    }
+
+    /// Stays pending while [`OPEN`] is unset, like a hook waiting on a callout.
+    struct Gate;
+
+    impl Future for Gate {
+        type Output = ();
+
+        fn poll(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<()> {
+            if OPEN.with(Cell::get) {
+                Poll::Ready(())
+            } else {
+                cx.waker().wake_by_ref();
+                Poll::Pending
+            }
+        }
+    }
+
+    fn reply(code: u32) -> Response {
+        Response {
+            code,
+            headers: vec![],
+            body: None,
+            trailers: vec![],
+        }
+    }
+
+    struct Plugin;
+
+    impl Context for Plugin {}
+
+    impl Runtime for Plugin {
+        type Hook = Hook;
+
+        fn create_http_context(&self, context_id: u32) -> Option<Hook> {
+            Some(Hook {
+                ctx: Ctx::new(context_id),
+            })
+        }
+    }
+
+    /// Denies bodies reading `deny`, and reports what it saw in headers.
+    struct Hook {
+        ctx: Ctx,
+    }
+
+    impl HttpHook for Hook {
+        fn interest(&self) -> Interest {
+            INTEREST.with(Cell::get)
+        }
+
+        async fn on_request_headers(
+            &self,
+            _num_headers: usize,
+            end_of_stream: bool,
+        ) -> Result<impl Into<HeaderMutations>, impl Into<Response>> {
+            record(format!("request_headers {}", end_of_stream));
+            Gate.await;
+            if let Ok(Some(_)) = self.ctx.get_http_request_header("x-deny") {
+                return Err(reply(403));
+            }
+            match self.ctx.get_http_request_header("x-invalid") {
+                Ok(Some(_)) => Ok(HeaderMutations::default().set("x-headers", "a\nb")),
+                _ => Ok(HeaderMutations::default().set("x-headers", "passed")),
+            }
+        }
+
+        async fn on_request_body(
+            &self,
+            body: Vec<u8>,
+        ) -> Result<impl Into<HeaderMutations>, impl Into<Response>> {
+            record(format!("request_body {}", String::from_utf8_lossy(&body)));
+            if body == b"deny" {
+                return Err(reply(403));
+            }
+            Ok(HeaderMutations::default().set("x-body-size", body.len().to_string()))
+        }
+
+        async fn on_response_headers(
+            &self,
+            _num_headers: usize,
+            end_of_stream: bool,
+        ) -> Result<impl Into<HeaderMutations>, impl Into<Response>> {
+            record(format!("response_headers {}", end_of_stream));
+            Ok::<_, Response>(HeaderMutations::default().set("x-response", "passed"))
+        }
+
+        async fn on_response_body(
+            &self,
+            body: Vec<u8>,
+        ) -> Result<impl Into<HeaderMutations>, impl Into<Response>> {
+            record(format!("response_body {}", String::from_utf8_lossy(&body)));
+            if body == b"deny" {
+                return Err(reply(500));
+            }
+            Ok(HeaderMutations::default().set("x-response-body-size", body.len().to_string()))
+        }
+
+        async fn on_log(&self) {
+            record("log".to_string());
+        }
+    }
+
+    fn host(interest: Interest) -> Host {
+        INTEREST.with(|i| i.set(interest));
+        OPEN.with(|open| open.set(true));
+        CALLS.with(|calls| calls.borrow_mut().clear());
+        let host = Host::new(|_| Box::new(RuntimeBox::new(Plugin)));
+        assert!(host.start(None, b""));
+        host
+    }
+
+    #[test]
+    fn passes_request_headers() {
+        let host = host(Interest::default());
+        let stream = host.stream("10.0.0.1:1234");
+        assert_eq!(
+            stream.request_headers(&[(":path", "/")], true),
+            Action::Pause
+        );
+        assert!(host.tick_until(10, || stream.request_resumed()));
+        assert_eq!(
+            stream.request_header("x-headers").as_deref(),
+            Some("passed")
+        );
+
+        let stream = host.stream("10.0.0.1:1234");
+        stream.request_headers(&[(":path", "/"), ("x-deny", "1")], true);
+        assert!(host.tick_until(10, || stream.local_response().is_some()));
+        assert_eq!(stream.local_response().unwrap().status, 403);
+        assert!(!stream.request_resumed());
+    }
+
+    #[test]
+    fn fails_on_invalid_header_changes() {
+        let host = host(Interest::default());
+        let stream = host.stream("10.0.0.1:1234");
+        stream.request_headers(&[(":path", "/"), ("x-invalid", "1")], true);
+        assert!(host.tick_until(10, || stream.local_response().is_some()));
+        assert_eq!(stream.local_response().unwrap().status, 500);
+        assert!(!stream.request_resumed());
+    }
+
+    #[test]
+    fn waits_for_headers_before_body() {
+        let host = host(Interest {
+            request_body: Some(16),
+            ..Interest::default()
+        });
+        OPEN.with(|open| open.set(false));
+        let stream = host.stream("10.0.0.1:1234");
+        assert_eq!(
+            stream.request_headers(&[(":path", "/")], false),
+            Action::Pause
+        );
+        assert_eq!(stream.request_body(b"hello", true), Action::Pause);
+        host.ticks(3);
+        assert!(!stream.request_resumed());
+        assert_eq!(calls(), ["request_headers false"]);
+
+        OPEN.with(|open| open.set(true));
+        assert!(host.tick_until(10, || stream.request_resumed()));
+        assert_eq!(calls(), ["request_headers false", "request_body hello"]);
+        assert_eq!(
+            stream.request_header("x-headers").as_deref(),
+            Some("passed")
+        );
+        assert_eq!(stream.request_header("x-body-size").as_deref(), Some("5"));
+    }
+
+    #[test]
+    fn denies_request_body() {
+        let host = host(Interest {
+            request_body: Some(16),
+            ..Interest::default()
+        });
+        let stream = host.stream("10.0.0.1:1234");
+        stream.request_headers(&[(":path", "/")], false);
+        host.ticks(3);
+        assert_eq!(stream.request_body(b"deny", true), Action::Pause);
+        assert!(host.tick_until(10, || stream.local_response().is_some()));
+        assert_eq!(stream.local_response().unwrap().status, 403);
+        assert!(!stream.request_resumed());
+    }
+
+    #[test]
+    fn trailers_end_the_body() {
+        let host = host(Interest {
+            request_body: Some(16),
+            ..Interest::default()
+        });
+        let stream = host.stream("10.0.0.1:1234");
+        stream.request_headers(&[(":path", "/")], false);
+        host.ticks(3);
+        assert_eq!(stream.request_body(b"hel", false), Action::Pause);
+        assert_eq!(stream.request_body(b"lo", false), Action::Pause);
+        host.ticks(3);
+        assert!(!stream.request_resumed());
+        assert_eq!(
+            stream.request_trailers(&[("x-checksum", "1")]),
+            Action::Pause
+        );
+        assert!(host.tick_until(10, || stream.request_resumed()));
+        assert_eq!(calls(), ["request_headers false", "request_body hello"]);
+    }
+
+    #[test]
+    fn rejects_too_large_bodies() {
+        let host = host(Interest {
+            request_body: Some(4),
+            response_body: Some(4),
+            ..Interest::default()
+        });
+        let stream = host.stream("10.0.0.1:1234");
+        stream.request_headers(&[(":path", "/")], false);
+        stream.request_body(b"hello", false);
+        assert!(host.tick_until(10, || stream.local_response().is_some()));
+        assert_eq!(stream.local_response().unwrap().status, 413);
+        assert!(!stream.request_resumed());
+
+        let stream = host.stream("10.0.0.1:1234");
+        stream.request_headers(&[(":path", "/")], true);
+        assert!(host.tick_until(10, || stream.request_resumed()));
+        stream.response_headers(&[(":status", "200")], false);
+        stream.response_body(b"hello", true);
+        assert!(host.tick_until(10, || stream.local_response().is_some()));
+        assert_eq!(stream.local_response().unwrap().status, 502);
+        assert!(!stream.response_resumed());
+    }
+
+    #[test]
+    fn drives_response_hooks() {
+        let host = host(Interest {
+            response_headers: true,
+            ..Interest::default()
+        });
+        let stream = host.stream("10.0.0.1:1234");
+        stream.request_headers(&[(":path", "/")], true);
+        assert!(host.tick_until(10, || stream.request_resumed()));
+        assert_eq!(
+            stream.response_headers(&[(":status", "200")], true),
+            Action::Pause
+        );
+        assert!(host.tick_until(10, || stream.response_resumed()));
+        assert_eq!(
+            stream.response_header("x-response").as_deref(),
+            Some("passed")
+        );
+
+        let host = self::host(Interest {
+            response_headers: true,
+            response_body: Some(16),
+            ..Interest::default()
+        });
+        let stream = host.stream("10.0.0.1:1234");
+        stream.request_headers(&[(":path", "/")], true);
+        assert!(host.tick_until(10, || stream.request_resumed()));
+        assert_eq!(
+            stream.response_headers(&[(":status", "200")], false),
+            Action::Pause
+        );
+        host.ticks(3);
+        assert!(!stream.response_resumed());
+        assert_eq!(stream.response_body(b"ok", true), Action::Pause);
+        assert!(host.tick_until(10, || stream.response_resumed()));
+        assert_eq!(
+            stream.response_header("x-response").as_deref(),
+            Some("passed")
+        );
+        assert_eq!(
+            stream.response_header("x-response-body-size").as_deref(),
+            Some("2")
+        );
+        assert_eq!(
+            calls(),
+            [
+                "request_headers true",
+                "response_headers false",
+                "response_body ok"
+            ]
+        );
+
+        let stream = host.stream("10.0.0.1:1234");
+        stream.request_headers(&[(":path", "/")], true);
+        assert!(host.tick_until(10, || stream.request_resumed()));
+        stream.response_headers(&[(":status", "200")], false);
+        stream.response_body(b"deny", true);
+        assert!(host.tick_until(10, || stream.local_response().is_some()));
+        assert_eq!(stream.local_response().unwrap().status, 500);
+        assert!(!stream.response_resumed());
+    }
+
+    #[test]
+    fn logs_when_done() {
+        let host = host(Interest::default());
+        let stream = host.stream("10.0.0.1:1234");
+        stream.request_headers(&[(":path", "/")], true);
+        assert!(host.tick_until(10, || stream.request_resumed()));
+        stream.finish();
+        host.ticks(3);
+        assert_eq!(calls(), ["request_headers true", "log"]);
+    }
 }
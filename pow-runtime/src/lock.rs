@@ -244,7 +244,7 @@ impl<S: 'static> SharedDataLock<S> {
     }
 
     /// Acquire a lock on the shared data.
-    pub fn lock(&self) -> TryLock<S> {
+    pub fn lock(&self) -> TryLock<'_, S> {
         TryLock { lock: self, gone: false }
     }
 
@@ -386,6 +386,7 @@ fn current_timestamp() -> u64 {
 mod test {
     use super::*;
 
+    #[allow(dead_code)]
     #[derive(Debug, Serialize, Deserialize)]
     struct Wukong {
         name: String
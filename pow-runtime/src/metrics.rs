@@ -0,0 +1,88 @@
+use std::{cell::RefCell, collections::HashMap};
+
+use proxy_wasm::{hostcalls, types::{MetricType, Status}};
+
+/// A metric defined on the host, see `counter`, `gauge` and `histogram`.
+#[derive(Debug, Clone, Copy)]
+pub struct Metric {
+    id: u32,
+}
+
+impl Metric {
+    pub fn increment(&self, offset: i64) -> Result<(), Status> {
+        hostcalls::increment_metric(self.id, offset)
+    }
+
+    pub fn record(&self, value: u64) -> Result<(), Status> {
+        hostcalls::record_metric(self.id, value)
+    }
+}
+
+/// Metric ids already defined on the host, keyed by the full metric name.
+struct Registry {
+    ids: RefCell<HashMap<String, u32>>,
+}
+
+impl Registry {
+    fn new() -> Self {
+        Self {
+            ids: RefCell::new(HashMap::new()),
+        }
+    }
+
+    fn define(&self, metric_type: MetricType, name: String) -> Result<Metric, Status> {
+        if let Some(&id) = self.ids.borrow().get(&name) {
+            return Ok(Metric { id });
+        }
+        let id = hostcalls::define_metric(metric_type, &name)?;
+        self.ids.borrow_mut().insert(name, id);
+        Ok(Metric { id })
+    }
+}
+
+thread_local! {
+    static REGISTRY: Registry = Registry::new();
+}
+
+/// Builds the full metric name, encoding each tag as `.<key>.<value>`, so
+/// Envoy can extract the tags with `stats_tags` regexes such as
+/// `(\.route\.([^.]+))`.
+pub fn metric_name(name: &str, tags: &[(&str, &str)]) -> String {
+    let mut full = name.to_string();
+    for (key, value) in tags {
+        full.push('.');
+        full.push_str(key);
+        full.push('.');
+        full.extend(value.chars().map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '_' | '-' | '/' | '*' | ':' => c,
+            _ => '_',
+        }));
+    }
+    full
+}
+
+pub fn counter(name: &str, tags: &[(&str, &str)]) -> Result<Metric, Status> {
+    REGISTRY.with(|r| r.define(MetricType::Counter, metric_name(name, tags)))
+}
+
+pub fn gauge(name: &str, tags: &[(&str, &str)]) -> Result<Metric, Status> {
+    REGISTRY.with(|r| r.define(MetricType::Gauge, metric_name(name, tags)))
+}
+
+pub fn histogram(name: &str, tags: &[(&str, &str)]) -> Result<Metric, Status> {
+    REGISTRY.with(|r| r.define(MetricType::Histogram, metric_name(name, tags)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::metric_name;
+
+    #[test]
+    fn tags_are_sanitized() {
+        assert_eq!(
+            metric_name("pow_waf.requests_seen", &[("vhost", "example.com"), ("route", "/api/posts/*")]),
+            "pow_waf.requests_seen.vhost.example_com.route./api/posts/*"
+        );
+        assert_eq!(metric_name("pow_waf.chain_hash_age", &[]), "pow_waf.chain_hash_age");
+    }
+}
@@ -36,7 +36,10 @@ impl Promise {
     }
 
     pub fn reject(&self) {
-        self.inner.replace(InnerPromise::Rejected);
+        let old = self.inner.replace(InnerPromise::Rejected);
+        if let InnerPromise::Pending(Some(waker)) = old {
+            waker.wake();
+        }
     }
 }
 
@@ -51,12 +54,12 @@ impl Future for Promise {
             }
             Poll::Pending
         } else if let InnerPromise::Rejected = *inner {
-            return Poll::Ready(Err(()));
+            Poll::Ready(Err(()))
         } else if let InnerPromise::Gone(()) = *inner {
             panic!("polling a resolved promise");
         } else {
             match std::mem::replace(&mut *inner, InnerPromise::Gone(())) {
-                InnerPromise::Resolved(response) => return Poll::Ready(Ok(response)),
+                InnerPromise::Resolved(response) => Poll::Ready(Ok(response)),
                 _ => unreachable!(),
             }
         }
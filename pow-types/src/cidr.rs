@@ -1,4 +1,8 @@
-use std::{fmt::Display, net::IpAddr, str::FromStr};
+use std::{
+    fmt::Display,
+    net::{IpAddr, Ipv6Addr},
+    str::FromStr,
+};
 
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
@@ -146,6 +150,28 @@ impl<'de> Deserialize<'de> for CIDR {
 }
 
 impl CIDR {
+    /// The network of `prefix_len` leading bits that `ip` belongs to.
+    pub fn network(ip: IpAddr, prefix_len: u8) -> Result<Self, ParseCIDRError> {
+        match ip {
+            IpAddr::V4(ip) => {
+                if prefix_len > 32 {
+                    return Err(ParseCIDRError::InvalidPrefix(prefix_len.to_string()));
+                }
+                let mask = u32::MAX.checked_shl(32 - prefix_len as u32).unwrap_or(0);
+                let network = u32::from(ip) & mask;
+                Ok(CIDR::V4(network.to_be_bytes(), prefix_len))
+            }
+            IpAddr::V6(ip) => {
+                if prefix_len > 128 {
+                    return Err(ParseCIDRError::InvalidPrefix(prefix_len.to_string()));
+                }
+                let mask = u128::MAX.checked_shl(128 - prefix_len as u32).unwrap_or(0);
+                let network = Ipv6Addr::from(u128::from(ip) & mask);
+                Ok(CIDR::V6(network.segments(), prefix_len))
+            }
+        }
+    }
+
     pub fn contains(&self, ip: IpAddr) -> bool {
         match (self, ip) {
             (CIDR::V4(cidr, prefix), IpAddr::V4(ip)) => {
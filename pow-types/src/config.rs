@@ -1,4 +1,4 @@
-use std::ops::Deref;
+use std::{ops::Deref, sync::Arc};
 
 use regex::Regex;
 use serde::{Deserialize, Serialize};
@@ -23,6 +23,31 @@ pub struct Route<T> {
     pub children: Option<Vec<Route<T>>>,
 }
 
+impl<T> VirtualHost<T> {
+    /// Full path patterns of every route, children included, in the order
+    /// they are declared.
+    pub fn patterns(&self) -> Vec<String> {
+        self.settings().into_iter().map(|(path, _)| path).collect()
+    }
+
+    /// Like [`VirtualHost::patterns`], each with the setting of its route.
+    pub fn settings(&self) -> Vec<(String, &T)> {
+        fn collect<'a, T>(path: &str, route: &'a Route<T>, out: &mut Vec<(String, &'a T)>) {
+            out.push((path.to_string(), &route.config));
+            for child in route.children.iter().flatten() {
+                let path = normalize_path(&format!("{}/{}", path, child.path));
+                collect(&path, child, out);
+            }
+        }
+
+        let mut settings = vec![];
+        for route in &self.routes {
+            collect(&route.path, route, &mut settings);
+        }
+        settings
+    }
+}
+
 impl<T> TryFrom<Vec<VirtualHost<T>>> for Router<T> {
     type Error = RouteError;
 
@@ -33,7 +58,8 @@ impl<T> TryFrom<Vec<VirtualHost<T>>> for Router<T> {
             for route in virtual_host.routes {
                 radix_add_all(&mut radix, &route.path, route.config, route.children)?;
             }
-            trie.add(&virtual_host.host, radix)?;
+            let host: Arc<str> = virtual_host.host.into();
+            trie.add(&host, (host.clone(), radix))?;
         }
         Ok(Router(trie))
     }
@@ -66,13 +92,21 @@ fn normalize_path(path: &str) -> String {
     path
 }
 
-pub struct Router<T>(Trie<RadixTree<T>>);
+pub struct Router<T>(Trie<(Arc<str>, RadixTree<T>)>);
 
-pub struct Found<'a, T>(Matches<'a, T>);
+pub struct Found<'a, T> {
+    virtual_host: &'a str,
+    matches: Matches<'a, T>,
+}
 
 impl<'a, T> Found<'a, T> {
+    /// The `host` pattern of the matched virtual host, as configured.
+    pub fn virtual_host(&self) -> &str {
+        self.virtual_host
+    }
+
     pub fn pattern(&self) -> &str {
-        &self.0.data.pattern
+        &self.matches.data.pattern
     }
 }
 
@@ -80,14 +114,17 @@ impl<T> Deref for Found<'_, T> {
     type Target = T;
 
     fn deref(&self) -> &Self::Target {
-        &self.0.data.data
+        &self.matches.data.data
     }
 }
 
 impl<T> Router<T> {
-    pub fn matches(&self, domain: &str, path: &str) -> Option<Found<T>> {
-        let route = self.0.matches(domain)?;
-        route.matches(path).map(|matches| Found(matches))
+    pub fn matches(&self, domain: &str, path: &str) -> Option<Found<'_, T>> {
+        let (virtual_host, route) = self.0.matches(domain)?;
+        route.matches(path).map(|matches| Found {
+            virtual_host,
+            matches,
+        })
     }
 }
 
@@ -133,12 +170,21 @@ mod test {
 
         let config: Vec<VirtualHost<serde_yaml::Value>> =
             serde_yaml::from_str(config_str).expect("failed to parse config");
+        assert_eq!(
+            config[0].patterns(),
+            vec!["/", "/api", "/api/users", "/api/posts/*"]
+        );
+        let (pattern, setting) = &config[1].settings()[1];
+        assert_eq!(pattern, "/about");
+        assert_eq!(setting["rate_limit"]["requests_per_unit"], 100);
         let route: Router<serde_yaml::Value> = config.try_into().expect("failed to convert config");
 
         let found = route
             .matches("example.com", "/api/posts/114514")
             .expect("route not found");
         println!("{:?}", found.clone());
+        assert_eq!(found.virtual_host(), "example.com");
+        assert_eq!(found.pattern(), "/api/posts/*");
     }
 
     #[test]
@@ -152,6 +198,33 @@ mod test {
         assert!(cidr.contains("2001:db8::ffff".parse().unwrap()));
     }
 
+    #[test]
+    fn cidr_network() {
+        let ip = "192.168.10.250".parse().unwrap();
+        assert_eq!(
+            CIDR::network(ip, 32).unwrap().to_string(),
+            "192.168.10.250/32"
+        );
+        assert_eq!(
+            CIDR::network(ip, 24).unwrap().to_string(),
+            "192.168.10.0/24"
+        );
+        assert_eq!(CIDR::network(ip, 0).unwrap().to_string(), "0.0.0.0/0");
+        assert!(CIDR::network(ip, 33).is_err());
+
+        let ip = "2001:db8:1234:5678:9abc:def0:1234:5678".parse().unwrap();
+        assert_eq!(
+            CIDR::network(ip, 64).unwrap().to_string(),
+            "2001:db8:1234:5678::/64"
+        );
+        assert_eq!(
+            CIDR::network(ip, 48).unwrap().to_string(),
+            "2001:db8:1234::/48"
+        );
+        assert_eq!(CIDR::network(ip, 0).unwrap().to_string(), "::/0");
+        assert!(CIDR::network(ip, 129).is_err());
+    }
+
     #[test]
     fn print_v6_cidr() {
         let cidr: CIDR = "2001:db8::/32".parse().unwrap();
@@ -2,3 +2,4 @@ pub mod bytearray32;
 pub mod cidr;
 pub mod config;
 pub mod route;
+pub mod timestamp;
@@ -0,0 +1,88 @@
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+fn default_past_secs() -> u64 {
+    60
+}
+
+fn default_future_secs() -> u64 {
+    10
+}
+
+/// How far a client-supplied timestamp may lie in the past or in the future
+/// relative to the server clock.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub struct TimestampWindow {
+    #[serde(default = "default_past_secs")]
+    pub past_secs: u64,
+    #[serde(default = "default_future_secs")]
+    pub future_secs: u64,
+}
+
+impl Default for TimestampWindow {
+    fn default() -> Self {
+        Self {
+            past_secs: default_past_secs(),
+            future_secs: default_future_secs(),
+        }
+    }
+}
+
+#[derive(Debug, Error, Eq, PartialEq)]
+pub enum TimestampError {
+    #[error("timestamp expired")]
+    Expired,
+    #[error("timestamp is in the future")]
+    InFuture,
+}
+
+impl TimestampWindow {
+    pub fn check(&self, timestamp: u64, now: u64) -> Result<(), TimestampError> {
+        if timestamp.saturating_add(self.past_secs) < now {
+            return Err(TimestampError::Expired);
+        }
+        if timestamp > now.saturating_add(self.future_secs) {
+            return Err(TimestampError::InFuture);
+        }
+        Ok(())
+    }
+
+    /// First second at which `timestamp` is rejected as expired.
+    pub fn expires_at(&self, timestamp: u64) -> u64 {
+        timestamp.saturating_add(self.past_secs).saturating_add(1)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn check() {
+        let window = TimestampWindow::default();
+        let now = 1_700_000_000;
+        assert_eq!(window.check(now, now), Ok(()));
+        assert_eq!(window.check(now - 60, now), Ok(()));
+        assert_eq!(window.check(now - 61, now), Err(TimestampError::Expired));
+        assert_eq!(window.check(now + 10, now), Ok(()));
+        assert_eq!(window.check(now + 11, now), Err(TimestampError::InFuture));
+        assert_eq!(window.check(u64::MAX, now), Err(TimestampError::InFuture));
+
+        let expires_at = window.expires_at(now);
+        assert_eq!(window.check(now, expires_at - 1), Ok(()));
+        assert_eq!(window.check(now, expires_at), Err(TimestampError::Expired));
+    }
+
+    #[test]
+    fn partial_config() {
+        let window: TimestampWindow =
+            serde_yaml::from_str("past_secs: 300").expect("failed to parse window");
+        assert_eq!(
+            window,
+            TimestampWindow {
+                past_secs: 300,
+                future_secs: 10
+            }
+        );
+    }
+}
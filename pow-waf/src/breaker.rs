@@ -0,0 +1,103 @@
+use std::{fmt::Debug, sync::Mutex};
+
+/// Seconds an open circuit waits before letting a request probe the
+/// dependency again.
+const COOLDOWN_SECS: u64 = 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Closed,
+    /// Tripped, `since` is the failure or probe that last kept it open.
+    Open {
+        since: u64,
+    },
+}
+
+/// Guards one dependency of the rate limit, the chain anchor or the shared
+/// counters. The first failure trips it and is logged once; while open,
+/// requests that need the dependency skip it and get the `on_error` policy of
+/// their route, and every `COOLDOWN_SECS` a single request probes whether it
+/// is back.
+pub struct CircuitBreaker {
+    dependency: &'static str,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    pub fn new(dependency: &'static str) -> Self {
+        Self {
+            dependency,
+            state: Mutex::new(State::Closed),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, State> {
+        self.state.lock().expect("failed to lock circuit breaker")
+    }
+
+    /// Whether a request at `now` may use the dependency.
+    pub fn allow(&self, now: u64) -> bool {
+        let mut state = self.state();
+        match *state {
+            State::Closed => true,
+            State::Open { since } if now.saturating_sub(since) >= COOLDOWN_SECS => {
+                log::debug!("circuit open, probing {}", self.dependency);
+                *state = State::Open { since: now };
+                true
+            }
+            State::Open { .. } => false,
+        }
+    }
+
+    pub fn failure(&self, error: impl Debug, now: u64) {
+        let mut state = self.state();
+        match *state {
+            State::Closed => log::warn!(
+                "{} unavailable, falling back to on_error policies: {:?}",
+                self.dependency,
+                error
+            ),
+            State::Open { .. } => {
+                log::debug!("{} still unavailable: {:?}", self.dependency, error)
+            }
+        }
+        *state = State::Open { since: now };
+    }
+
+    pub fn success(&self) {
+        let mut state = self.state();
+        if *state != State::Closed {
+            log::info!("{} recovered, closing circuit", self.dependency);
+            *state = State::Closed;
+        }
+    }
+
+    pub fn is_open(&self) -> bool {
+        *self.state() != State::Closed
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn trips_and_recovers() {
+        let breaker = CircuitBreaker::new("chain anchor");
+        assert!(breaker.allow(100));
+
+        breaker.failure("no anchor", 100);
+        assert!(breaker.is_open());
+        assert!(!breaker.allow(100 + COOLDOWN_SECS - 1));
+        // A single probe per cooldown.
+        assert!(breaker.allow(100 + COOLDOWN_SECS));
+        assert!(!breaker.allow(100 + COOLDOWN_SECS));
+
+        breaker.failure("no anchor", 100 + COOLDOWN_SECS);
+        assert!(!breaker.allow(100 + 2 * COOLDOWN_SECS - 1));
+        assert!(breaker.allow(100 + 2 * COOLDOWN_SECS));
+        breaker.success();
+        assert!(!breaker.is_open());
+        assert!(breaker.allow(100 + 2 * COOLDOWN_SECS));
+    }
+}
@@ -1,164 +1,341 @@
-use std::{collections::VecDeque, time::Duration};
-use std::sync::{Arc, RwLock};
-
-use log::{debug, warn};
 use proxy_wasm::types::Status;
+use sha2::{Digest, Sha256};
 
-use pow_runtime::lock::SharedDataLock;
-use pow_runtime::{http_call, spawn_local};
-use pow_runtime::timeout::sleep;
+use super::{call, parse_hash, Anchors, ChainSource, Upstreams};
+use crate::config::ChainUpstream;
 
-pub struct BTC {
-    inner: Arc<Inner>
-}
+const TIP_PATH: &str = "/api/blocks/tip/hash";
 
-pub struct Inner {
-    upstream_name: String,
-    recent_hash_list: SharedDataLock<VecDeque<String>>,
-    state: RwLock<State>,
-}
+/// Headers walked back from a new tip looking for an accepted one, more
+/// blocks than are usually found between two polls.
+const MAX_LINK_DEPTH: usize = 6;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum State {
-    Initial,
-    Running,
-    Stopped,
-}
+/// Headers walked back at most, about a day of blocks. A tip further ahead of
+/// the newest accepted header only has to carry the minimum work, like the
+/// first one.
+const MAX_CATCH_UP_DEPTH: usize = 144;
 
-impl BTC {
-    pub fn new(upstream_name: String) -> Self 
-    {
-        let recent_hash_list = SharedDataLock::new(0);
-        if let Err(e) = recent_hash_list.initial(VecDeque::new()) {
-            log::info!("failed to initialize shared data: {:?}", e);
-        }
+/// Average time between two blocks.
+const BLOCK_INTERVAL_SECS: u64 = 600;
 
-        let ret = Self {
-            inner: Arc::new(Inner {
-                upstream_name,
-                recent_hash_list,
-                state: RwLock::new(State::Initial),
-            })
-        };
+/// Sixteen times the target of mainnet block 840000: the difficulty may fall a
+/// long way before tips are refused, yet a forged one still costs real work.
+const DEFAULT_MIN_WORK_BITS: u32 = 0x1734_2190;
 
-        let ret_clone = ret.clone();
-        spawn_local(async move {
-            ret_clone.start().await;
-        });
+/// Bitcoin tip hash from a mempool.space compatible API. The tip is only
+/// trusted once its header hashes to it, meets its own target as well as the
+/// minimum work, and links to an accepted header through headers whose
+/// targets move no more than a retarget allows. Headers are fetched from
+/// `block/<hash>/header` next to the tip path, e.g. `/api/block/<hash>/header`.
+pub struct Mempool {
+    upstreams: Upstreams,
+    anchors: Anchors,
+    min_target: [u8; 32],
+}
+
+impl Mempool {
+    pub fn new(upstreams: Upstreams, anchors: Anchors, min_work_bits: Option<u32>) -> Self {
+        let min_target = compact_target(min_work_bits.unwrap_or(DEFAULT_MIN_WORK_BITS))
+            .expect("min_work_bits checked by the config");
+        Self {
+            upstreams,
+            anchors,
+            min_target,
+        }
+    }
 
-        ret
+    // curl -sSL "https://mempool.space/api/blocks/tip/hash"
+    // 0000000000000000000624d76f52661d0f35a0da8b93a87cb93cf08fd9140209
+    async fn tip(&self, upstream: &ChainUpstream) -> Result<String, Status> {
+        let body = call(upstream, "GET", tip_path(upstream), None).await?;
+        parse_hash(&text(body)?)
     }
 
-    fn clone(&self) -> Self {
-        Self {
-            inner: self.inner.clone()
+    // curl -sSL "https://mempool.space/api/block/0000000000000000000624d76f52661d0f35a0da8b93a87cb93cf08fd9140209/header"
+    async fn header(&self, upstream: &ChainUpstream, hash: &str) -> Result<BlockHeader, Status> {
+        let api = tip_path(upstream)
+            .strip_suffix("/blocks/tip/hash")
+            .unwrap_or("/api");
+        let path = format!("{}/block/{}/header", api, hash);
+        let body = call(upstream, "GET", &path, None).await?;
+        let header = BlockHeader::parse(&text(body)?)?;
+        header.verify(hash)?;
+        if header
+            .target()
+            .is_none_or(|target| target > self.min_target)
+        {
+            log::warn!(
+                "block {} carries less work than the minimum, its target is {:08x}",
+                hash,
+                header.bits()
+            );
+            return Err(Status::BadArgument);
         }
+        Ok(header)
     }
+}
+
+/// Headers to walk back from a tip to find the newest accepted one, seen
+/// `since_secs` ago. `None` once that is more than a day of blocks.
+fn link_depth(since_secs: u64) -> Option<usize> {
+    // Blocks come in bursts, allow three times the expected count.
+    let expected = usize::try_from(since_secs / BLOCK_INTERVAL_SECS * 3).unwrap_or(usize::MAX);
+    let depth = MAX_LINK_DEPTH.saturating_add(expected);
+    (depth <= MAX_CATCH_UP_DEPTH).then_some(depth)
+}
 
-    pub fn check_in_list(&self, hash: &str) -> bool {
-        self.inner
-            .recent_hash_list
-            .read()
-            .expect("failed to read recent hash list")
-            .contains(&hash.to_string())
+fn tip_path(upstream: &ChainUpstream) -> &str {
+    upstream.path.as_deref().unwrap_or(TIP_PATH)
+}
+
+fn text(body: Vec<u8>) -> Result<String, Status> {
+    String::from_utf8(body).map_err(|e| {
+        log::warn!("invalid response body: {}", e);
+        Status::InternalFailure
+    })
+}
+
+impl ChainSource for Mempool {
+    fn anchor_store(&self) -> &Anchors {
+        &self.anchors
     }
 
-    pub fn get_latest_hash(&self) -> Option<String> {
-        self.inner
-            .recent_hash_list
-            .read()
-            .expect("failed to read recent hash list")
-            .front()
-            .cloned()
+    fn upstreams(&self) -> &Upstreams {
+        &self.upstreams
     }
 
-    // curl -sSL "https://mempool.space/api/blocks/tip/hash"
-    // 0000000000000000000624d76f52661d0f35a0da8b93a87cb93cf08fd9140209
-    pub async fn start(&self)
-    {
-        self.turn(State::Running);
-        loop {
-            { 
-                let state = *self.inner.state.read().expect("failed to read state");
-                if State::Running != state { 
-                    log::info!("exit polling loop");
-                    break; 
+    async fn fetch(&self, upstream: &ChainUpstream) -> Result<String, Status> {
+        let tip = self.tip(upstream).await?;
+        let accepted = self.anchors.list()?;
+        if accepted.iter().any(|anchor| anchor.hash == tip) {
+            return Ok(tip);
+        }
+
+        let depth = accepted
+            .first()
+            .and_then(|newest| link_depth(crate::now().saturating_sub(newest.seen_at)));
+        let Some(depth) = depth else {
+            // Nothing recent to link to, the tip only has to carry its work.
+            if !accepted.is_empty() {
+                log::warn!("no recent accepted header, taking tip {} on its work", tip);
+            }
+            self.header(upstream, &tip).await?;
+            return Ok(tip);
+        };
+
+        let mut hash = tip.clone();
+        let mut child: Option<BlockHeader> = None;
+        for _ in 0..depth {
+            let header = self.header(upstream, &hash).await?;
+            if let Some(child) = &child {
+                if !child.retargets_from(&header) {
+                    log::warn!(
+                        "block {} moves the target from {:08x} to {:08x}",
+                        child.hash(),
+                        header.bits(),
+                        child.bits()
+                    );
+                    return Err(Status::BadArgument);
                 }
             }
-            log::debug!("poll for new block hash");
-            if let Err(e) = self.update_latest_hash().await {
-                warn!("failed to update latest hash: {:?}", e);
+            let prev = header.prev_hash();
+            if accepted.iter().any(|anchor| anchor.hash == prev) {
+                return Ok(tip);
             }
-
-            let lock = self.inner.recent_hash_list.lock().await
-                .expect("failed to acquire lock");
-            sleep(Duration::from_secs(10)).await;
-            debug!("data: {:?}", *lock);
+            hash = prev;
+            child = Some(header);
         }
+        log::warn!(
+            "tip {} does not link to an accepted header within {} blocks",
+            tip,
+            depth
+        );
+        Err(Status::BadArgument)
     }
+}
 
-    fn turn(&self, state: State) {
-        *self.inner.state.write().expect("failed to write state") = state;
-    }
-
-    async fn update_latest_hash(&self) -> Result<(), Status>
-    {
-        debug!("fetching latest block hash from mempool.space");
-        let response = http_call(
-            &self.inner.upstream_name,
-            vec![
-                (":method", "GET"),
-                (":path", "/api/blocks/tip/hash"),
-                (":authority", "mempool.space"),
-                (":schema", "https"),
-                ("accept", "application/json"),
-            ],
-            None,
-            Vec::with_capacity(0),
-            Duration::from_secs(10),
-        )
-        .inspect_err(|&e| {
-            log::error!("failed to make http call: {:?}, please check the upstream {} exists", e, "mempool.space");
-        })?
-        .await
-        .map_err(|_| Status::InternalFailure)?;
-        
-        debug!("receive mempool.space response");
-
-        let Some(body) = response.body else {
-            warn!("empty response body");
-            return Err(Status::InternalFailure);
-        };
+/// A raw 80-byte Bitcoin block header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockHeader([u8; 80]);
+
+impl BlockHeader {
+    pub fn parse(raw: &str) -> Result<Self, Status> {
+        let bytes = hex::decode(raw.trim()).map_err(|e| {
+            log::warn!("invalid block header: {}", e);
+            Status::ParseFailure
+        })?;
+        let header = bytes.try_into().map_err(|bytes: Vec<u8>| {
+            log::warn!("block header of {} bytes", bytes.len());
+            Status::ParseFailure
+        })?;
+        Ok(Self(header))
+    }
 
-        let body_str = String::from_utf8(body)
-            .map_err(|e| {
-                warn!("invalid response body: {}", e);
-                Status::InternalFailure
-            })?;
+    /// Double SHA-256 of the header, as the usual reversed hex.
+    pub fn hash(&self) -> String {
+        let mut hash: [u8; 32] = Sha256::digest(Sha256::digest(self.0)).into();
+        hash.reverse();
+        hex::encode(hash)
+    }
 
-        if body_str.len() != 64 {
-            warn!("invalid block hash: {}", body_str);
-            return Ok(())
+    pub fn prev_hash(&self) -> String {
+        let mut prev = [0; 32];
+        prev.copy_from_slice(&self.0[4..36]);
+        prev.reverse();
+        hex::encode(prev)
+    }
+
+    pub fn bits(&self) -> u32 {
+        u32::from_le_bytes(self.0[72..76].try_into().expect("4 bytes"))
+    }
+
+    /// Big-endian target the compact `bits` encode, `None` if negative or
+    /// over 256 bits.
+    pub fn target(&self) -> Option<[u8; 32]> {
+        compact_target(self.bits())
+    }
+
+    /// Whether the target is within the factor of four a retarget may move it
+    /// from the one of `parent`.
+    pub fn retargets_from(&self, parent: &BlockHeader) -> bool {
+        let (Some(target), Some(parent)) = (self.target(), parent.target()) else {
+            return false;
+        };
+        widen(&target) <= times_four(&parent) && widen(&parent) <= times_four(&target)
+    }
+
+    /// Whether the header is the block `hash` and carries the work its
+    /// target asks for.
+    pub fn verify(&self, hash: &str) -> Result<(), Status> {
+        let actual = self.hash();
+        if actual != hash {
+            log::warn!("header of {} hashes to {}", hash, actual);
+            return Err(Status::BadArgument);
         }
+        let meets_target = self.target().is_some_and(|target| {
+            let actual = hex::decode(&actual).expect("hex of a hash");
+            actual.as_slice() <= target.as_slice()
+        });
+        if !meets_target {
+            log::warn!("block {} misses its target {:08x}", hash, self.bits());
+            return Err(Status::BadArgument);
+        }
+        Ok(())
+    }
+}
 
-        let mut recent_hash_list = self.inner.recent_hash_list.lock().await.expect("failed to write recent hash list");
-        debug!("response body: {}", body_str);
-        if recent_hash_list.contains(&body_str) {
-            return Ok(());
+/// Big-endian target the compact `bits` encode, `None` if negative or over
+/// 256 bits.
+pub fn compact_target(bits: u32) -> Option<[u8; 32]> {
+    let exponent = (bits >> 24) as usize;
+    let mantissa = bits & 0x007f_ffff;
+    if bits & 0x0080_0000 != 0 && mantissa != 0 {
+        return None;
+    }
+    let mut target = [0; 32];
+    if exponent <= 3 {
+        let value = mantissa >> (8 * (3 - exponent));
+        target[29..].copy_from_slice(&value.to_be_bytes()[1..]);
+        return Some(target);
+    }
+    let bytes = &mantissa.to_be_bytes()[1..];
+    for (i, byte) in bytes.iter().enumerate() {
+        match (32 + i).checked_sub(exponent) {
+            Some(at) if at < 32 => target[at] = *byte,
+            _ if *byte == 0 => {}
+            _ => return None,
         }
+    }
+    Some(target)
+}
 
-        debug!("New block hash: {}", body_str);
+fn widen(target: &[u8; 32]) -> [u8; 33] {
+    let mut wide = [0; 33];
+    wide[1..].copy_from_slice(target);
+    wide
+}
 
-        recent_hash_list.push_front(body_str);
+/// `target` times four, a byte wider so it can't overflow.
+fn times_four(target: &[u8; 32]) -> [u8; 33] {
+    let mut out = [0; 33];
+    let mut carry = 0;
+    for (i, byte) in target.iter().enumerate().rev() {
+        out[i + 1] = byte << 2 | carry;
+        carry = byte >> 6;
+    }
+    out[0] = carry;
+    out
+}
 
-        if recent_hash_list.len() > 2 {
-            let _: Vec<_> = recent_hash_list.drain(2..).collect();
-        }
+#[cfg(test)]
+mod test {
+    use super::*;
 
-        Ok(())
+    const GENESIS: &str = "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c";
+    const GENESIS_HASH: &str = "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f";
+    const BLOCK_1: &str = "010000006fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000982051fd1e4ba744bbbe680e1fee14677ba1a3c3540bf7b1cdb606e857233e0e61bc6649ffff001d01e36299";
+    const BLOCK_1_HASH: &str = "00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048";
+
+    #[test]
+    fn verify_headers() {
+        let genesis = BlockHeader::parse(GENESIS).unwrap();
+        assert_eq!(genesis.hash(), GENESIS_HASH);
+        assert_eq!(genesis.bits(), 0x1d00ffff);
+        assert_eq!(genesis.verify(GENESIS_HASH), Ok(()));
+
+        let block = BlockHeader::parse(BLOCK_1).unwrap();
+        assert_eq!(block.prev_hash(), GENESIS_HASH);
+        assert_eq!(block.verify(BLOCK_1_HASH), Ok(()));
+        assert_eq!(block.verify(GENESIS_HASH), Err(Status::BadArgument));
+
+        // Same header, a target it doesn't meet.
+        let mut raw = hex::decode(BLOCK_1).unwrap();
+        raw[72..76].copy_from_slice(&0x1c00ffffu32.to_le_bytes());
+        let harder = BlockHeader::parse(&hex::encode(&raw)).unwrap();
+        assert_eq!(harder.verify(&harder.hash()), Err(Status::BadArgument));
+
+        assert_eq!(BlockHeader::parse(&BLOCK_1[2..]), Err(Status::ParseFailure));
+    }
+
+    #[test]
+    fn compact_target() {
+        let header = |bits: u32| {
+            let mut raw = [0; 80];
+            raw[72..76].copy_from_slice(&bits.to_le_bytes());
+            BlockHeader(raw)
+        };
+        let mut expected = [0; 32];
+        expected[4..6].copy_from_slice(&[0xff, 0xff]);
+        assert_eq!(header(0x1d00ffff).target(), Some(expected));
+
+        let mut expected = [0; 32];
+        expected[31] = 0x12;
+        assert_eq!(header(0x01123456).target(), Some(expected));
+        assert_eq!(header(0x04923456).target(), None);
+        assert_eq!(header(0x23123456).target(), None);
+    }
+
+    #[test]
+    fn retarget_bound() {
+        let header = |bits: u32| {
+            let mut raw = [0; 80];
+            raw[72..76].copy_from_slice(&bits.to_le_bytes());
+            BlockHeader(raw)
+        };
+        let parent = header(0x1d00ffff);
+        assert!(header(0x1d00ffff).retargets_from(&parent));
+        // A quarter and four times the target of the parent.
+        assert!(header(0x1c3fffc0).retargets_from(&parent));
+        assert!(!header(0x1c3fffbf).retargets_from(&parent));
+        assert!(header(0x1d03fffc).retargets_from(&parent));
+        assert!(!header(0x1d03fffd).retargets_from(&parent));
+        assert!(!header(0x04923456).retargets_from(&parent));
     }
 
-    pub fn stop(&mut self) {
-        self.turn(State::Stopped);
+    #[test]
+    fn catch_up_depth() {
+        assert_eq!(link_depth(10), Some(MAX_LINK_DEPTH));
+        assert_eq!(link_depth(3600), Some(MAX_LINK_DEPTH + 18));
+        assert_eq!(link_depth(86_400), None);
     }
 }
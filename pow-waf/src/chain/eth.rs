@@ -0,0 +1,83 @@
+use proxy_wasm::types::Status;
+use serde::Deserialize;
+
+use super::{fetch, parse_hash, Anchors, ChainSource, Upstreams};
+use crate::config::ChainUpstream;
+
+const REQUEST: &[u8] =
+    br#"{"jsonrpc":"2.0","method":"eth_getBlockByNumber","params":["latest",false],"id":1}"#;
+
+/// Latest block hash over Ethereum JSON-RPC.
+pub struct Ethereum {
+    upstreams: Upstreams,
+    anchors: Anchors,
+}
+
+#[derive(Deserialize)]
+struct RpcResponse {
+    result: Option<Block>,
+    error: Option<serde_json::Value>,
+}
+
+#[derive(Deserialize)]
+struct Block {
+    hash: String,
+}
+
+impl Ethereum {
+    pub fn new(upstreams: Upstreams, anchors: Anchors) -> Self {
+        Self { upstreams, anchors }
+    }
+}
+
+/// Block hash out of an `eth_getBlockByNumber` response.
+fn parse_response(body: &[u8]) -> Result<String, Status> {
+    let response: RpcResponse = serde_json::from_slice(body).map_err(|e| {
+        log::warn!("invalid JSON-RPC response: {}", e);
+        Status::ParseFailure
+    })?;
+    match response {
+        RpcResponse {
+            result: Some(block),
+            ..
+        } => parse_hash(&block.hash),
+        RpcResponse { error, .. } => {
+            log::warn!("JSON-RPC call failed: {:?}", error);
+            Err(Status::InternalFailure)
+        }
+    }
+}
+
+impl ChainSource for Ethereum {
+    fn anchor_store(&self) -> &Anchors {
+        &self.anchors
+    }
+
+    fn upstreams(&self) -> &Upstreams {
+        &self.upstreams
+    }
+
+    async fn fetch(&self, upstream: &ChainUpstream) -> Result<String, Status> {
+        let body = fetch(upstream, "POST", "/", Some(REQUEST)).await?;
+        parse_response(&body)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn block_hash() {
+        let body = br#"{"jsonrpc":"2.0","id":1,"result":{"number":"0x1312d00","hash":"0xA4E3B1ADA1D83C1C2B6F4E4F2C8DCC2C1E7D2B0E3F1C1B6F0B6A4E8D1F1E2D3C","transactions":[]}}"#;
+        assert_eq!(
+            parse_response(body),
+            Ok("a4e3b1ada1d83c1c2b6f4e4f2c8dcc2c1e7d2b0e3f1c1b6f0b6a4e8d1f1e2d3c".to_string())
+        );
+
+        let error =
+            br#"{"jsonrpc":"2.0","id":1,"error":{"code":-32601,"message":"method not found"}}"#;
+        assert_eq!(parse_response(error), Err(Status::InternalFailure));
+        assert_eq!(parse_response(b"<html>"), Err(Status::ParseFailure));
+    }
+}
@@ -0,0 +1,68 @@
+use proxy_wasm::types::Status;
+
+use super::{fetch, parse_hash, Anchors, ChainSource, Upstreams};
+use crate::config::ChainUpstream;
+
+/// Any JSON API, e.g. a drand beacon: the anchor is the string found at a
+/// JSON pointer (RFC 6901) in the response.
+pub struct JsonPointer {
+    upstreams: Upstreams,
+    pointer: String,
+    anchors: Anchors,
+}
+
+impl JsonPointer {
+    pub fn new(upstreams: Upstreams, pointer: String, anchors: Anchors) -> Self {
+        Self {
+            upstreams,
+            pointer,
+            anchors,
+        }
+    }
+}
+
+fn parse_response(body: &[u8], pointer: &str) -> Result<String, Status> {
+    let value: serde_json::Value = serde_json::from_slice(body).map_err(|e| {
+        log::warn!("invalid JSON response: {}", e);
+        Status::ParseFailure
+    })?;
+    let Some(anchor) = value.pointer(pointer).and_then(|v| v.as_str()) else {
+        log::warn!("no string at {} in response", pointer);
+        return Err(Status::ParseFailure);
+    };
+    parse_hash(anchor)
+}
+
+impl ChainSource for JsonPointer {
+    fn anchor_store(&self) -> &Anchors {
+        &self.anchors
+    }
+
+    fn upstreams(&self) -> &Upstreams {
+        &self.upstreams
+    }
+
+    async fn fetch(&self, upstream: &ChainUpstream) -> Result<String, Status> {
+        let body = fetch(upstream, "GET", "/", None).await?;
+        parse_response(&body, &self.pointer)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn pointer() {
+        let body = br#"{"round":4242,"randomness":"ff2b3c4d5e6f708192a3b4c5d6e7f8091a2b3c4d5e6f708192a3b4c5d6e7f809","signature":"00"}"#;
+        assert_eq!(
+            parse_response(body, "/randomness"),
+            Ok("ff2b3c4d5e6f708192a3b4c5d6e7f8091a2b3c4d5e6f708192a3b4c5d6e7f809".to_string())
+        );
+        assert_eq!(parse_response(body, "/round"), Err(Status::ParseFailure));
+        assert_eq!(
+            parse_response(body, "/signature"),
+            Err(Status::ParseFailure)
+        );
+    }
+}
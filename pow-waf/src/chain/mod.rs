@@ -1 +1,583 @@
-pub mod btc;
\ No newline at end of file
+pub mod btc;
+pub mod eth;
+pub mod http;
+
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    mem,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, RwLock,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, warn};
+use pow_runtime::{
+    http_call,
+    lock::{self, SharedDataLock},
+    spawn_local,
+    timeout::sleep,
+};
+use proxy_wasm::types::Status;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::config::{AnchorPolicy, ChainConfig, ChainUpstream, ChainUpstreams};
+
+/// A block hash together with the time this filter first saw it.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Anchor {
+    pub hash: String,
+    pub seen_at: u64,
+}
+
+/// Accepted anchors, newest first, shared by every worker of the VM.
+pub struct Anchors {
+    list: SharedDataLock<VecDeque<Anchor>>,
+    keep: usize,
+}
+
+impl Anchors {
+    /// Keeps the `keep` newest anchors, so that a challenge handed out right
+    /// before a new block can still be solved.
+    pub fn new(keep: usize) -> Self {
+        let list = SharedDataLock::new(0);
+        // A source replaced on reload leaves its anchors behind, challenges
+        // already handed out stay valid.
+        if list.read().is_err() {
+            if let Err(e) = list.initial(VecDeque::new()) {
+                log::info!("failed to initialize shared data: {:?}", e);
+            }
+        }
+        Self { list, keep }
+    }
+
+    pub fn list(&self) -> Result<Vec<Anchor>, Status> {
+        self.list.read().map(Into::into).map_err(status)
+    }
+
+    pub fn latest(&self) -> Result<Option<Anchor>, Status> {
+        Ok(self.list()?.into_iter().next())
+    }
+
+    /// Makes `hash` the newest anchor, unless it is known already.
+    pub async fn record(&self, hash: String) -> Result<(), Status> {
+        let mut list = self.list.lock().await.map_err(status)?;
+        if list.iter().any(|anchor| anchor.hash == hash) {
+            return Ok(());
+        }
+
+        debug!("New block hash: {}", hash);
+        list.push_front(Anchor {
+            hash,
+            seen_at: crate::now(),
+        });
+        list.truncate(self.keep);
+        Ok(())
+    }
+
+    /// Forgets every anchor.
+    pub async fn clear(&self) {
+        match self.list.lock().await {
+            Ok(mut list) => list.clear(),
+            Err(e) => warn!("failed to clear recent hash list: {}", e),
+        }
+    }
+
+    /// Holds the list for `duration`, so the pollers of all workers take turns.
+    async fn hold(&self, duration: Duration) {
+        match self.list.lock().await {
+            Ok(list) => {
+                sleep(duration).await;
+                debug!("data: {:?}", *list);
+            }
+            Err(e) => {
+                warn!("failed to hold recent hash list: {}", e);
+                sleep(duration).await;
+            }
+        }
+    }
+}
+
+/// Status of a failed shared data access, so callers can treat it like any
+/// other host failure.
+fn status(e: lock::Error) -> Status {
+    match e {
+        lock::Error::Status { status, .. } => status,
+        _ => Status::InternalFailure,
+    }
+}
+
+/// Stands in for the anchor while the chain has none, so `on_error:
+/// challenge` routes can still hand out challenges. Not a secret, clients
+/// just can't know it before the VM starts.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LocalSeed(pub String);
+
+impl LocalSeed {
+    /// The seed of the VM, created by the first worker to ask.
+    fn shared() -> SharedDataLock<LocalSeed> {
+        let seed = SharedDataLock::new(0);
+        if seed.read().is_err() {
+            let nanos = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .expect("failed to get timestamp")
+                .as_nanos();
+            let random = LocalSeed(hex::encode(Sha256::digest(nanos.to_le_bytes())));
+            if let Err(e) = seed.initial(random) {
+                log::info!("failed to initialize shared data: {:?}", e);
+            }
+        }
+        seed
+    }
+}
+
+/// The upstreams of a source and the one to try first.
+pub struct Upstreams {
+    config: ChainUpstreams,
+    next: AtomicUsize,
+}
+
+impl Upstreams {
+    pub fn new(config: ChainUpstreams) -> Self {
+        Self {
+            config,
+            next: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn quorum(&self) -> Option<usize> {
+        self.config.quorum
+    }
+
+    pub fn all(&self) -> &[ChainUpstream] {
+        &self.config.upstreams
+    }
+
+    /// Upstreams in the order to try them, the last one that answered first.
+    fn rotation(&self) -> impl Iterator<Item = (usize, &ChainUpstream)> {
+        let len = self.config.upstreams.len();
+        let start = self.next.load(Ordering::Relaxed);
+        (0..len)
+            .map(move |offset| (start + offset) % len)
+            .map(|i| (i, &self.config.upstreams[i]))
+    }
+
+    fn stay_at(&self, index: usize) {
+        self.next.store(index, Ordering::Relaxed);
+    }
+}
+
+/// The anchor reported by at least `quorum` upstreams, if any.
+fn agreed(hashes: Vec<String>, quorum: usize) -> Option<String> {
+    let mut votes: HashMap<String, usize> = HashMap::new();
+    for hash in hashes {
+        *votes.entry(hash).or_default() += 1;
+    }
+    votes
+        .into_iter()
+        .filter(|(_, count)| *count >= quorum)
+        .max_by_key(|(_, count)| *count)
+        .map(|(hash, _)| hash)
+}
+
+/// Where challenge bases come from. Implementations only fetch the newest
+/// anchor from one upstream, failover, quorum, recording and validating
+/// anchors are shared.
+pub trait ChainSource {
+    fn anchor_store(&self) -> &Anchors;
+
+    fn upstreams(&self) -> &Upstreams;
+
+    /// Fetches the newest anchor from `upstream` as 64 lowercase hex digits.
+    fn fetch(&self, upstream: &ChainUpstream) -> impl Future<Output = Result<String, Status>>;
+
+    /// Polls the upstreams for the latest anchor and records it.
+    fn poll(&self) -> impl Future<Output = Result<(), Status>> {
+        async {
+            let hash = match self.upstreams().quorum() {
+                Some(quorum) => self.poll_quorum(quorum).await?,
+                None => self.poll_failover().await?,
+            };
+            self.anchor_store().record(hash).await
+        }
+    }
+
+    /// Asks one upstream after the other until one answers.
+    fn poll_failover(&self) -> impl Future<Output = Result<String, Status>> {
+        async {
+            let mut last_error = Status::InternalFailure;
+            for (index, upstream) in self.upstreams().rotation() {
+                match self.fetch(upstream).await {
+                    Ok(hash) => {
+                        self.upstreams().stay_at(index);
+                        return Ok(hash);
+                    }
+                    Err(e) => {
+                        warn!("upstream {} failed: {:?}", upstream.upstream, e);
+                        last_error = e;
+                    }
+                }
+            }
+            Err(last_error)
+        }
+    }
+
+    /// Asks every upstream at once and takes the anchor `quorum` of them agree on.
+    fn poll_quorum(&self, quorum: usize) -> impl Future<Output = Result<String, Status>> {
+        async move {
+            let results = futures::future::join_all(
+                self.upstreams()
+                    .all()
+                    .iter()
+                    .map(|upstream| self.fetch(upstream)),
+            )
+            .await;
+            let hashes: Vec<String> = results.into_iter().filter_map(Result::ok).collect();
+            debug!("upstreams answered: {:?}", hashes);
+            agreed(hashes, quorum).ok_or_else(|| {
+                warn!("no anchor reported by {} upstreams", quorum);
+                Status::NotFound
+            })
+        }
+    }
+
+    /// Whether a client-supplied base is one of the accepted anchors.
+    fn validate(&self, base: &str) -> bool {
+        self.anchors()
+            .is_ok_and(|anchors| anchors.iter().any(|anchor| anchor.hash == base))
+    }
+
+    /// Accepted anchors, newest first.
+    fn anchors(&self) -> Result<Vec<Anchor>, Status> {
+        self.anchor_store().list()
+    }
+}
+
+/// The source selected by the configuration.
+pub enum Source {
+    Btc(btc::Mempool),
+    Ethereum(eth::Ethereum),
+    Http(http::JsonPointer),
+}
+
+impl Source {
+    fn new(config: &ChainConfig, keep: usize) -> Self {
+        let anchors = Anchors::new(keep);
+        let upstreams = Upstreams::new(config.upstreams().clone());
+        match config {
+            ChainConfig::Btc { min_work_bits, .. } => {
+                Self::Btc(btc::Mempool::new(upstreams, anchors, *min_work_bits))
+            }
+            ChainConfig::Ethereum(_) => Self::Ethereum(eth::Ethereum::new(upstreams, anchors)),
+            ChainConfig::Http { pointer, .. } => {
+                Self::Http(http::JsonPointer::new(upstreams, pointer.clone(), anchors))
+            }
+        }
+    }
+}
+
+impl ChainSource for Source {
+    fn anchor_store(&self) -> &Anchors {
+        match self {
+            Self::Btc(source) => source.anchor_store(),
+            Self::Ethereum(source) => source.anchor_store(),
+            Self::Http(source) => source.anchor_store(),
+        }
+    }
+
+    fn upstreams(&self) -> &Upstreams {
+        match self {
+            Self::Btc(source) => source.upstreams(),
+            Self::Ethereum(source) => source.upstreams(),
+            Self::Http(source) => source.upstreams(),
+        }
+    }
+
+    async fn fetch(&self, upstream: &ChainUpstream) -> Result<String, Status> {
+        match self {
+            Self::Btc(source) => source.fetch(upstream).await,
+            Self::Ethereum(source) => source.fetch(upstream).await,
+            Self::Http(source) => source.fetch(upstream).await,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Initial,
+    Running,
+    Stopped,
+}
+
+/// Polls the configured source in the background.
+#[derive(Clone)]
+pub struct Chain {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    config: ChainConfig,
+    policy: AnchorPolicy,
+    source: Source,
+    seed: SharedDataLock<LocalSeed>,
+    state: RwLock<State>,
+}
+
+impl Chain {
+    pub fn new(config: ChainConfig, policy: AnchorPolicy) -> Self {
+        Self::spawn(config, policy, false)
+    }
+
+    /// Stops this poller and starts one for `config`. Anchors carry over,
+    /// unless they come from another kind of chain and would never link up
+    /// with the new one.
+    pub fn replace(&self, config: ChainConfig, policy: AnchorPolicy) -> Self {
+        self.stop();
+        let same_kind = mem::discriminant(self.config()) == mem::discriminant(&config);
+        Self::spawn(config, policy, !same_kind)
+    }
+
+    fn spawn(config: ChainConfig, policy: AnchorPolicy, clear: bool) -> Self {
+        let ret = Self {
+            inner: Arc::new(Inner {
+                source: Source::new(&config, policy.keep),
+                config,
+                policy,
+                seed: LocalSeed::shared(),
+                state: RwLock::new(State::Initial),
+            }),
+        };
+
+        let ret_clone = ret.clone();
+        spawn_local(async move {
+            if clear {
+                ret_clone.source().anchor_store().clear().await;
+            }
+            ret_clone.start().await;
+        });
+
+        ret
+    }
+
+    pub fn config(&self) -> &ChainConfig {
+        &self.inner.config
+    }
+
+    pub fn policy(&self) -> &AnchorPolicy {
+        &self.inner.policy
+    }
+
+    /// The local seed, while there is no anchor to challenge against.
+    pub fn fallback_seed(&self) -> Option<String> {
+        if let Ok(Some(_)) = self.latest_anchor() {
+            return None;
+        }
+        self.inner.seed.read().ok().map(|seed| seed.0)
+    }
+
+    /// How many anchors `base` is behind the newest one, `None` if it isn't
+    /// accepted (anymore) or the anchors can't be read.
+    pub fn anchor_depth(&self, base: &str) -> Option<usize> {
+        anchor_depth(
+            &self.source().anchors().ok()?,
+            base,
+            self.policy().max_age_secs,
+            crate::now(),
+        )
+    }
+
+    pub fn source(&self) -> &Source {
+        &self.inner.source
+    }
+
+    pub fn latest_anchor(&self) -> Result<Option<Anchor>, Status> {
+        self.source().anchor_store().latest()
+    }
+
+    async fn start(&self) {
+        self.turn(State::Running);
+        loop {
+            {
+                let state = *self.inner.state.read().expect("failed to read state");
+                if State::Running != state {
+                    log::info!("exit polling loop");
+                    break;
+                }
+            }
+            log::debug!("poll for new block hash");
+            if let Err(e) = self.source().poll().await {
+                warn!("failed to update latest hash: {:?}", e);
+            }
+            if let Ok(Some(anchor)) = self.latest_anchor() {
+                crate::metrics::chain_hash_age(crate::now().saturating_sub(anchor.seen_at));
+            }
+
+            self.source()
+                .anchor_store()
+                .hold(Duration::from_secs(self.policy().poll_interval_secs))
+                .await;
+        }
+    }
+
+    fn turn(&self, state: State) {
+        *self.inner.state.write().expect("failed to write state") = state;
+    }
+
+    /// The polling loop exits before its next poll.
+    pub fn stop(&self) {
+        self.turn(State::Stopped);
+    }
+}
+
+/// Position of `base` in `anchors`, unless a newer anchor superseded it more
+/// than `max_age_secs` ago.
+fn anchor_depth(
+    anchors: &[Anchor],
+    base: &str,
+    max_age_secs: Option<u64>,
+    now: u64,
+) -> Option<usize> {
+    let depth = anchors.iter().position(|anchor| anchor.hash == base)?;
+    let superseded_at = depth.checked_sub(1).map(|newer| anchors[newer].seen_at);
+    match (superseded_at, max_age_secs) {
+        (Some(at), Some(max_age)) if now.saturating_sub(at) > max_age => None,
+        _ => Some(depth),
+    }
+}
+
+/// Calls `upstream` at its configured path, `default_path` if it has none.
+async fn fetch(
+    upstream: &ChainUpstream,
+    method: &str,
+    default_path: &str,
+    body: Option<&[u8]>,
+) -> Result<Vec<u8>, Status> {
+    let path = upstream.path.as_deref().unwrap_or(default_path);
+    call(upstream, method, path, body).await
+}
+
+/// Calls `upstream` and returns the body of a successful response.
+async fn call(
+    upstream: &ChainUpstream,
+    method: &str,
+    path: &str,
+    body: Option<&[u8]>,
+) -> Result<Vec<u8>, Status> {
+    let authority = upstream.authority.as_deref().unwrap_or(&upstream.upstream);
+    let mut headers = vec![
+        (":method", method),
+        (":path", path),
+        (":authority", authority),
+        (":scheme", "https"),
+        ("accept", "application/json"),
+    ];
+    if body.is_some() {
+        headers.push(("content-type", "application/json"));
+    }
+
+    debug!("fetching latest anchor from {}{}", authority, path);
+    let response = http_call(
+        &upstream.upstream,
+        headers,
+        body,
+        vec![],
+        Duration::from_secs(10),
+    )
+    .inspect_err(|&e| {
+        log::error!(
+            "failed to make http call: {:?}, please check the upstream {} exists",
+            e,
+            upstream.upstream
+        );
+    })?
+    .await
+    .map_err(|_| Status::InternalFailure)?;
+
+    let status = response
+        .headers
+        .iter()
+        .find(|(k, _)| k == ":status")
+        .map(|(_, v)| v.as_str());
+    if let Some(status) = status.filter(|s| !s.starts_with('2')) {
+        warn!("{} responded with status {}", authority, status);
+        return Err(Status::InternalFailure);
+    }
+    response.body.ok_or_else(|| {
+        warn!("empty response body");
+        Status::InternalFailure
+    })
+}
+
+/// Normalizes a 32-byte hash, with or without `0x`, to lowercase hex.
+pub fn normalize_hash(raw: &str) -> Option<String> {
+    let raw = raw.trim();
+    let hex = raw.strip_prefix("0x").unwrap_or(raw);
+    (hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit())).then(|| hex.to_lowercase())
+}
+
+fn parse_hash(raw: &str) -> Result<String, Status> {
+    normalize_hash(raw).ok_or_else(|| {
+        warn!("invalid block hash: {}", raw);
+        Status::ParseFailure
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn normalize() {
+        let hash = "00000000000000000001A2B3C4D5E6F708192A3B4C5D6E7F8091A2B3C4D5E6F7";
+        assert_eq!(normalize_hash(hash), Some(hash.to_lowercase()));
+        assert_eq!(
+            normalize_hash(&format!("0x{}\n", hash)),
+            Some(hash.to_lowercase())
+        );
+        assert_eq!(normalize_hash(&hash[1..]), None);
+        assert_eq!(normalize_hash(&hash.replace('A', "g")), None);
+    }
+
+    #[test]
+    fn quorum() {
+        let hashes = |list: &[&str]| list.iter().map(|h| h.to_string()).collect::<Vec<_>>();
+        assert_eq!(agreed(hashes(&["a", "b", "a"]), 2), Some("a".to_string()));
+        assert_eq!(agreed(hashes(&["a", "b", "c"]), 2), None);
+        assert_eq!(agreed(hashes(&["a"]), 2), None);
+        assert_eq!(agreed(hashes(&["b"]), 1), Some("b".to_string()));
+    }
+
+    #[test]
+    fn depth() {
+        let anchors = [("c", 300), ("b", 200), ("a", 100)].map(|(hash, seen_at)| Anchor {
+            hash: hash.to_string(),
+            seen_at,
+        });
+        assert_eq!(anchor_depth(&anchors, "c", Some(10), 1000), Some(0));
+        assert_eq!(anchor_depth(&anchors, "a", None, 1000), Some(2));
+        // `b` was superseded at 300, `a` at 200.
+        assert_eq!(anchor_depth(&anchors, "b", Some(60), 350), Some(1));
+        assert_eq!(anchor_depth(&anchors, "a", Some(60), 350), None);
+        assert_eq!(anchor_depth(&anchors, "d", None, 350), None);
+    }
+
+    #[test]
+    fn rotation() {
+        let upstreams = Upstreams::new(ChainUpstreams {
+            upstreams: ["a", "b", "c"].map(ChainUpstream::new).to_vec(),
+            quorum: None,
+        });
+        let order = |upstreams: &Upstreams| {
+            upstreams
+                .rotation()
+                .map(|(_, u)| u.upstream.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(order(&upstreams), ["a", "b", "c"]);
+        upstreams.stay_at(2);
+        assert_eq!(order(&upstreams), ["c", "a", "b"]);
+    }
+}
@@ -1,9 +1,15 @@
+use std::net::IpAddr;
+
 use pow_runtime::log_level::LogLevel;
-use pow_types::cidr::CIDR;
+use pow_types::cidr::{ParseCIDRError, CIDR};
 use pow_types::config::VirtualHost;
+use pow_types::timestamp::TimestampWindow;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+use crate::chain::btc::compact_target;
+use crate::geo::GeoInfo;
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum TimeUnit {
     Second,
@@ -23,7 +29,7 @@ impl TimeUnit {
     }
 }
 
-#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct RateLimit {
     pub unit: TimeUnit,
     pub requests_per_unit: u32,
@@ -40,9 +46,414 @@ impl RateLimit {
     }
 }
 
-#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+/// Aggregate load level of a route, counted across all clients within the
+/// current `rate_limit` bucket.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct LoadThreshold {
+    pub requests_per_unit: u64,
+    /// Baseline difficulty for every client while the threshold is reached,
+    /// as a multiple of the global `difficulty`.
+    pub multiplier: u64,
+}
+
+/// Service level objectives of the upstream behind a route. While the
+/// upstream misses them, every client gets a baseline difficulty that grows
+/// with the size of the miss.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct HealthSlo {
+    /// Maximum share of 5xx responses, in percent.
+    pub error_rate_percent: Option<u64>,
+    /// Maximum latency from request start to response headers.
+    pub latency_ms: Option<u64>,
+    /// Upper bound of the baseline, as a multiple of the global `difficulty`.
+    pub max_multiplier: u64,
+}
+
+/// What a route does with requests that need a dependency of the filter, the
+/// chain anchor or the shared counters, while it is unavailable. Requests
+/// under their quota don't need the anchor and pass as usual.
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OnError {
+    /// Lets the request through unchallenged.
+    Allow,
+    /// Rejects the request with 503.
+    Deny,
+    /// Challenges every client at the global `difficulty`, against a local
+    /// seed while no anchor is available. Keeps the route up and still costs
+    /// clients work.
+    #[default]
+    Challenge,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
 pub struct Setting {
     pub rate_limit: RateLimit,
+    pub load_thresholds: Option<Vec<LoadThreshold>>,
+    pub health: Option<HealthSlo>,
+    #[serde(default)]
+    pub timestamp_window: TimestampWindow,
+    /// Evaluated in order, the first rule matching the client wins.
+    pub geo: Option<Vec<GeoRule>>,
+    pub on_error: Option<OnError>,
+}
+
+impl Setting {
+    /// Returns the highest multiplier among the thresholds reached by `route_counter`.
+    pub fn load_multiplier(&self, route_counter: u64) -> u64 {
+        self.load_thresholds
+            .iter()
+            .flatten()
+            .filter(|t| route_counter >= t.requests_per_unit)
+            .map(|t| t.multiplier)
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        let zero = self
+            .geo
+            .iter()
+            .flatten()
+            .any(|rule| rule.action == GeoAction::Multiplier(0));
+        match zero {
+            // It would price every request at 0 and lift the rate limit.
+            true => Err("geo multiplier must be at least 1".to_string()),
+            false => Ok(()),
+        }
+    }
+
+    pub fn geo_action(&self, info: &GeoInfo) -> Option<&GeoAction> {
+        self.geo
+            .iter()
+            .flatten()
+            .find(|rule| rule.matches(info))
+            .map(|rule| &rule.action)
+    }
+}
+
+/// What to do with a client matched by a [`GeoRule`].
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GeoAction {
+    /// Scales the difficulty of the request, and challenges the client at no
+    /// less than `multiplier` times the global `difficulty` even when idle.
+    Multiplier(u64),
+    /// Rejects the request with 403.
+    Block,
+    /// Lets the request through without a challenge.
+    Bypass,
+}
+
+/// Matches a client whose country is in `countries` or whose ASN is in `asns`.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct GeoRule {
+    /// ISO 3166-1 alpha-2 country codes.
+    pub countries: Option<Vec<String>>,
+    pub asns: Option<Vec<u32>>,
+    #[serde(with = "serde_yaml::with::singleton_map")]
+    pub action: GeoAction,
+}
+
+impl GeoRule {
+    fn matches(&self, info: &GeoInfo) -> bool {
+        let country = info.country.as_deref().is_some_and(|country| {
+            self.countries
+                .iter()
+                .flatten()
+                .any(|c| c.eq_ignore_ascii_case(country))
+        });
+        let asn = info
+            .asn
+            .is_some_and(|asn| self.asns.iter().flatten().any(|a| *a == asn));
+        country || asn
+    }
+}
+
+/// Where a MaxMind DB (MMDB) database is loaded from.
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GeoDatabase {
+    /// The raw bytes of the VM configuration.
+    VmConfiguration,
+    /// Base64 encoded database.
+    Inline(String),
+}
+
+/// An HTTP upstream a chain source polls.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ChainUpstream {
+    /// Envoy cluster the request is sent to.
+    pub upstream: String,
+    /// `:authority` of the request, the cluster name when unset.
+    pub authority: Option<String>,
+    /// Overrides the default path of the source.
+    pub path: Option<String>,
+}
+
+impl ChainUpstream {
+    pub fn new(upstream: impl Into<String>) -> Self {
+        Self {
+            upstream: upstream.into(),
+            authority: None,
+            path: None,
+        }
+    }
+}
+
+/// Upstreams serving the same chain. Without a quorum they are tried in
+/// turn, moving on to the next one on error.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ChainUpstreams {
+    pub upstreams: Vec<ChainUpstream>,
+    /// Upstreams that must report the same anchor before it is accepted,
+    /// more than half of them so two anchors can never both reach it.
+    pub quorum: Option<usize>,
+}
+
+impl ChainUpstreams {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.upstreams.is_empty() {
+            return Err("chain source without upstreams".to_string());
+        }
+        match self.quorum {
+            Some(quorum) if quorum * 2 <= self.upstreams.len() => Err(format!(
+                "quorum {} is not a majority of {} upstreams",
+                quorum,
+                self.upstreams.len()
+            )),
+            Some(quorum) if quorum > self.upstreams.len() => Err(format!(
+                "quorum {} out of range for {} upstreams",
+                quorum,
+                self.upstreams.len()
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl From<ChainUpstream> for ChainUpstreams {
+    fn from(upstream: ChainUpstream) -> Self {
+        Self {
+            upstreams: vec![upstream],
+            quorum: None,
+        }
+    }
+}
+
+/// Where challenge bases come from.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChainConfig {
+    /// Bitcoin tip hash from a mempool.space compatible API.
+    Btc {
+        #[serde(flatten)]
+        upstreams: ChainUpstreams,
+        /// Easiest target a header may have, in the compact `bits` form.
+        /// Defaults to a mainnet checkpoint, test networks have to lower it.
+        min_work_bits: Option<u32>,
+    },
+    /// Latest block hash over Ethereum JSON-RPC.
+    Ethereum(ChainUpstreams),
+    /// A 32-byte hex string found at a JSON `pointer` in any JSON response.
+    Http {
+        #[serde(flatten)]
+        upstreams: ChainUpstreams,
+        pointer: String,
+    },
+}
+
+impl ChainConfig {
+    pub fn upstreams(&self) -> &ChainUpstreams {
+        match self {
+            Self::Ethereum(upstreams) => upstreams,
+            Self::Btc { upstreams, .. } | Self::Http { upstreams, .. } => upstreams,
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        self.upstreams().validate()?;
+        match self {
+            Self::Btc {
+                min_work_bits: Some(bits),
+                ..
+            } if compact_target(*bits).is_none() => {
+                Err(format!("min_work_bits {:08x} is not a valid target", bits))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// How the chain poller keeps anchors around block transitions.
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize)]
+pub struct AnchorPolicy {
+    #[serde(default = "default_poll_interval_secs")]
+    pub poll_interval_secs: u64,
+    /// Anchors accepted at once, the newest included.
+    #[serde(default = "default_keep")]
+    pub keep: usize,
+    /// How long an anchor stays accepted once a newer one arrived.
+    pub max_age_secs: Option<u64>,
+    /// Difficulty multiplier for every newer anchor a proof is behind, e.g.
+    /// 2 doubles the work against the previous anchor and quadruples it
+    /// against the one before.
+    pub stale_multiplier: Option<u64>,
+}
+
+fn default_poll_interval_secs() -> u64 {
+    10
+}
+
+fn default_keep() -> usize {
+    2
+}
+
+impl Default for AnchorPolicy {
+    fn default() -> Self {
+        Self {
+            poll_interval_secs: default_poll_interval_secs(),
+            keep: default_keep(),
+            max_age_secs: None,
+            stale_multiplier: None,
+        }
+    }
+}
+
+impl AnchorPolicy {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.poll_interval_secs == 0 {
+            return Err("poll_interval_secs must be positive".to_string());
+        }
+        if self.keep == 0 {
+            return Err("keep must be positive".to_string());
+        }
+        if self.stale_multiplier == Some(0) {
+            return Err("stale_multiplier must be positive".to_string());
+        }
+        Ok(())
+    }
+
+    /// Difficulty of a proof against the anchor `depth` blocks behind the newest.
+    pub fn difficulty(&self, difficulty: u64, depth: usize) -> u64 {
+        let multiplier = self.stale_multiplier.unwrap_or(1);
+        let depth = u32::try_from(depth).unwrap_or(u32::MAX);
+        difficulty.saturating_mul(multiplier.saturating_pow(depth))
+    }
+}
+
+fn default_quota_multiplier() -> u32 {
+    1
+}
+
+/// Clients are counted per network of `prefix_len` bits rather than per address.
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct AggregationTier {
+    pub prefix_len: u8,
+    /// Quota of the whole network, as a multiple of the route `requests_per_unit`.
+    #[serde(default = "default_quota_multiplier")]
+    pub quota_multiplier: u32,
+}
+
+impl AggregationTier {
+    fn new(prefix_len: u8) -> Self {
+        Self {
+            prefix_len,
+            quota_multiplier: default_quota_multiplier(),
+        }
+    }
+
+    /// Difficulty owed by a network that already sent `counter` requests
+    /// within the current bucket.
+    pub fn difficulty(&self, counter: u64, rate_limit: &RateLimit, difficulty: u64) -> u64 {
+        let quota = rate_limit.requests_per_unit as u64 * self.quota_multiplier as u64;
+        counter / quota * difficulty
+    }
+}
+
+fn default_ipv4_tiers() -> Vec<AggregationTier> {
+    vec![AggregationTier::new(32)]
+}
+
+fn default_ipv6_tiers() -> Vec<AggregationTier> {
+    vec![AggregationTier::new(128)]
+}
+
+/// Rate-limit tiers per address family. Every tier is enforced, the client
+/// pays the highest difficulty among them.
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct ClientAggregation {
+    #[serde(default = "default_ipv4_tiers")]
+    pub ipv4: Vec<AggregationTier>,
+    #[serde(default = "default_ipv6_tiers")]
+    pub ipv6: Vec<AggregationTier>,
+}
+
+impl Default for ClientAggregation {
+    fn default() -> Self {
+        Self {
+            ipv4: default_ipv4_tiers(),
+            ipv6: default_ipv6_tiers(),
+        }
+    }
+}
+
+impl ClientAggregation {
+    pub fn tiers(&self, ip: IpAddr) -> &[AggregationTier] {
+        match ip {
+            IpAddr::V4(_) => &self.ipv4,
+            IpAddr::V6(_) => &self.ipv6,
+        }
+    }
+
+    /// Rate-limit counter keys of `ip` on a route, one per tier.
+    pub fn keys(
+        &self,
+        ip: IpAddr,
+        bucket: u64,
+        host: &str,
+        pattern: &str,
+    ) -> Result<Vec<(String, &AggregationTier)>, ParseCIDRError> {
+        self.tiers(ip)
+            .iter()
+            .map(|tier| {
+                let network = CIDR::network(ip, tier.prefix_len)?;
+                Ok((format!("{}:{}:{}{}", network, bucket, host, pattern), tier))
+            })
+            .collect()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for (family, tiers, max) in [("ipv4", &self.ipv4, 32), ("ipv6", &self.ipv6, 128)] {
+            if tiers.is_empty() {
+                // No tier would leave the family without any rate limit.
+                return Err(format!("{} needs at least one tier", family));
+            }
+            for tier in tiers {
+                if tier.prefix_len > max {
+                    return Err(format!(
+                        "prefix_len {} exceeds {} bits",
+                        tier.prefix_len, max
+                    ));
+                }
+                if tier.quota_multiplier == 0 {
+                    return Err("quota_multiplier must be greater than 0".to_string());
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where the per-request decision records are written.
+#[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DecisionLog {
+    /// Log each record as JSON at the given level, regardless of `log_level`.
+    Log(LogLevel),
+    /// Store each record as JSON in the `wasm.pow_waf.decision` filter state,
+    /// so access logs can pick it up with `%FILTER_STATE(wasm.pow_waf.decision:PLAIN)%`.
+    FilterState,
 }
 
 #[derive(Debug, Eq, PartialEq, Serialize, Deserialize)]
@@ -51,5 +462,380 @@ pub struct Config<T> {
     pub whitelist: Option<Vec<CIDR>>,
     pub difficulty: u64,
     pub log_level: Option<LogLevel>,
-    pub mempool_upstream_name: String,
+    #[serde(default, with = "serde_yaml::with::singleton_map")]
+    pub decision_log: Option<DecisionLog>,
+    pub client_aggregation: Option<ClientAggregation>,
+    #[serde(default, with = "serde_yaml::with::singleton_map_recursive")]
+    pub geo_databases: Option<Vec<GeoDatabase>>,
+    /// Shorthand for a `btc` chain source polling this cluster.
+    pub mempool_upstream_name: Option<String>,
+    #[serde(default, with = "serde_yaml::with::singleton_map")]
+    pub chain: Option<ChainConfig>,
+    pub anchors: Option<AnchorPolicy>,
+}
+
+impl Config<Setting> {
+    /// Validates the setting of every route, children included.
+    pub fn validate_routes(&self) -> Result<(), String> {
+        for virtual_host in &self.virtual_hosts {
+            for (path, setting) in virtual_host.settings() {
+                setting
+                    .validate()
+                    .map_err(|e| format!("{}{}: {}", virtual_host.host, path, e))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<T> Config<T> {
+    pub fn chain(&self) -> Result<ChainConfig, String> {
+        match (&self.chain, &self.mempool_upstream_name) {
+            (Some(_), Some(_)) => Err("set either chain or mempool_upstream_name".to_string()),
+            (Some(chain), None) => chain.validate().map(|()| chain.clone()),
+            (None, Some(name)) => Ok(ChainConfig::Btc {
+                upstreams: ChainUpstream {
+                    // The API used to be hardcoded to mempool.space.
+                    authority: Some("mempool.space".to_string()),
+                    ..ChainUpstream::new(name.as_str())
+                }
+                .into(),
+                min_work_bits: None,
+            }),
+            (None, None) => Err("missing chain source".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn load_multiplier() {
+        let setting: Setting = serde_yaml::from_str(
+            r#"
+rate_limit:
+  unit: minute
+  requests_per_unit: 10
+load_thresholds:
+  - requests_per_unit: 1000
+    multiplier: 1
+  - requests_per_unit: 5000
+    multiplier: 4
+"#,
+        )
+        .expect("failed to parse setting");
+
+        assert_eq!(setting.load_multiplier(0), 0);
+        assert_eq!(setting.load_multiplier(1000), 1);
+        assert_eq!(setting.load_multiplier(4999), 1);
+        assert_eq!(setting.load_multiplier(12000), 4);
+        assert_eq!(setting.timestamp_window, TimestampWindow::default());
+    }
+
+    #[test]
+    fn client_aggregation() {
+        let aggregation: ClientAggregation = serde_yaml::from_str(
+            r#"
+ipv6:
+  - prefix_len: 64
+  - prefix_len: 48
+    quota_multiplier: 16
+"#,
+        )
+        .expect("failed to parse client aggregation");
+
+        assert_eq!(aggregation.ipv4, vec![AggregationTier::new(32)]);
+        assert_eq!(
+            aggregation.tiers("2001:db8::1".parse().unwrap()),
+            &[
+                AggregationTier::new(64),
+                AggregationTier {
+                    prefix_len: 48,
+                    quota_multiplier: 16
+                }
+            ]
+        );
+        assert_eq!(aggregation.validate(), Ok(()));
+
+        let invalid = ClientAggregation {
+            ipv4: vec![AggregationTier::new(64)],
+            ..Default::default()
+        };
+        assert!(invalid.validate().is_err());
+
+        let empty: ClientAggregation =
+            serde_yaml::from_str("ipv6: []").expect("failed to parse client aggregation");
+        assert_eq!(
+            empty.validate(),
+            Err("ipv6 needs at least one tier".to_string())
+        );
+    }
+
+    #[test]
+    fn geo_rules() {
+        let setting: Setting = serde_yaml::from_str(
+            r#"
+rate_limit:
+  unit: minute
+  requests_per_unit: 10
+geo:
+  - countries: [US]
+    action: bypass
+  - asns: [16509, 14061]
+    action: block
+  - countries: [us, cn]
+    action:
+      multiplier: 4
+"#,
+        )
+        .expect("failed to parse setting");
+
+        let info = |country: Option<&str>, asn: Option<u32>| GeoInfo {
+            country: country.map(str::to_string),
+            asn,
+        };
+        assert_eq!(
+            setting.geo_action(&info(Some("US"), Some(16509))),
+            Some(&GeoAction::Bypass)
+        );
+        assert_eq!(
+            setting.geo_action(&info(Some("DE"), Some(14061))),
+            Some(&GeoAction::Block)
+        );
+        assert_eq!(
+            setting.geo_action(&info(Some("CN"), None)),
+            Some(&GeoAction::Multiplier(4))
+        );
+        assert_eq!(setting.geo_action(&info(None, None)), None);
+        assert_eq!(setting.validate(), Ok(()));
+
+        let config: Config<Setting> = serde_yaml::from_str(
+            r#"
+virtual_hosts:
+  - host: example.com
+    routes:
+      - path: /api
+        rate_limit:
+          unit: minute
+          requests_per_unit: 10
+        children:
+          - path: /users
+            rate_limit:
+              unit: minute
+              requests_per_unit: 10
+            geo:
+              - countries: [CN]
+                action:
+                  multiplier: 0
+difficulty: 1000
+"#,
+        )
+        .expect("failed to parse config");
+        assert_eq!(
+            config.validate_routes(),
+            Err("example.com/api/users: geo multiplier must be at least 1".to_string())
+        );
+    }
+
+    #[test]
+    fn enum_options() {
+        let config: Config<Setting> = serde_yaml::from_str(
+            r#"
+virtual_hosts: []
+difficulty: 1000
+mempool_upstream_name: mempool
+decision_log:
+  log: info
+geo_databases:
+  - vm_configuration
+  - inline: AAAA
+"#,
+        )
+        .expect("failed to parse config");
+
+        assert_eq!(config.decision_log, Some(DecisionLog::Log(LogLevel::Info)));
+        assert_eq!(
+            config.geo_databases,
+            Some(vec![
+                GeoDatabase::VmConfiguration,
+                GeoDatabase::Inline("AAAA".to_string())
+            ])
+        );
+    }
+
+    fn chain(source: &str) -> Result<ChainConfig, String> {
+        let config: Config<Setting> =
+            serde_yaml::from_str(&format!("virtual_hosts: []\ndifficulty: 1000\n{}", source))
+                .expect("failed to parse config");
+        config.chain()
+    }
+
+    #[test]
+    fn chain_sources() {
+        assert_eq!(
+            chain(
+                r#"
+chain:
+  http:
+    upstreams:
+      - upstream: drand
+        authority: api.drand.sh
+        path: /public/latest
+    pointer: /randomness
+"#
+            ),
+            Ok(ChainConfig::Http {
+                upstreams: ChainUpstream {
+                    upstream: "drand".to_string(),
+                    authority: Some("api.drand.sh".to_string()),
+                    path: Some("/public/latest".to_string()),
+                }
+                .into(),
+                pointer: "/randomness".to_string(),
+            })
+        );
+
+        assert_eq!(
+            chain(
+                r#"
+chain:
+  ethereum:
+    upstreams:
+      - upstream: geth
+      - upstream: erigon
+    quorum: 2
+"#
+            ),
+            Ok(ChainConfig::Ethereum(ChainUpstreams {
+                upstreams: vec![ChainUpstream::new("geth"), ChainUpstream::new("erigon")],
+                quorum: Some(2),
+            }))
+        );
+
+        let Ok(ChainConfig::Btc { upstreams, .. }) = chain("mempool_upstream_name: mempool") else {
+            panic!("expected a btc source");
+        };
+        assert_eq!(upstreams.upstreams[0].upstream, "mempool");
+        assert_eq!(
+            upstreams.upstreams[0].authority.as_deref(),
+            Some("mempool.space")
+        );
+
+        let btc = |bits: &str| {
+            chain(&format!(
+                "chain:\n  btc:\n    upstreams:\n      - upstream: bitcoind\n    min_work_bits: {}",
+                bits
+            ))
+        };
+        assert_eq!(
+            btc("0x1d00ffff"),
+            Ok(ChainConfig::Btc {
+                upstreams: ChainUpstream::new("bitcoind").into(),
+                min_work_bits: Some(0x1d00ffff),
+            })
+        );
+        assert!(btc("0x04923456").is_err());
+
+        assert!(chain("").is_err());
+        assert!(chain("chain:\n  btc:\n    upstreams: []").is_err());
+        assert!(chain(
+            r#"
+chain:
+  btc:
+    upstreams:
+      - upstream: mempool
+    quorum: 2
+"#
+        )
+        .is_err());
+        assert_eq!(
+            chain(
+                r#"
+chain:
+  btc:
+    upstreams:
+      - upstream: mempool
+      - upstream: blockstream
+      - upstream: bitcoind
+      - upstream: electrs
+    quorum: 2
+"#
+            ),
+            Err("quorum 2 is not a majority of 4 upstreams".to_string())
+        );
+    }
+
+    #[test]
+    fn anchor_policy() {
+        let policy: AnchorPolicy = serde_yaml::from_str("max_age_secs: 600").unwrap();
+        assert_eq!(
+            policy,
+            AnchorPolicy {
+                max_age_secs: Some(600),
+                ..AnchorPolicy::default()
+            }
+        );
+        assert_eq!(policy.difficulty(1000, 3), 1000);
+
+        let policy = AnchorPolicy {
+            stale_multiplier: Some(2),
+            ..policy
+        };
+        assert_eq!(policy.difficulty(1000, 0), 1000);
+        assert_eq!(policy.difficulty(1000, 2), 4000);
+        assert_eq!(policy.difficulty(1000, 64), u64::MAX);
+
+        assert!(AnchorPolicy { keep: 0, ..policy }.validate().is_err());
+        assert!(policy.validate().is_ok());
+    }
+
+    #[test]
+    fn load_thresholds_are_optional() {
+        let setting: Setting = serde_yaml::from_str(
+            r#"
+rate_limit:
+  unit: minute
+  requests_per_unit: 10
+"#,
+        )
+        .expect("failed to parse setting");
+
+        assert_eq!(setting.load_thresholds, None);
+        assert_eq!(setting.load_multiplier(u64::MAX), 0);
+    }
+
+    /// The `configuration` string of the plugin called `name` in `value`.
+    fn plugin_configuration<'a>(value: &'a serde_yaml::Value, name: &str) -> Option<&'a str> {
+        match value {
+            serde_yaml::Value::Mapping(map) => {
+                let configuration = map
+                    .get("configuration")
+                    .and_then(|configuration| configuration.get("value"))
+                    .and_then(serde_yaml::Value::as_str);
+                match map.get("name").and_then(serde_yaml::Value::as_str) {
+                    Some(found) if found == name && configuration.is_some() => configuration,
+                    _ => map
+                        .values()
+                        .find_map(|value| plugin_configuration(value, name)),
+                }
+            }
+            serde_yaml::Value::Sequence(values) => values
+                .iter()
+                .find_map(|value| plugin_configuration(value, name)),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn parse_shipped_config() {
+        let envoy: serde_yaml::Value = serde_yaml::from_str(include_str!("../../envoy.yaml"))
+            .expect("failed to parse envoy.yaml");
+        let configuration =
+            plugin_configuration(&envoy, "PoW").expect("missing PoW plugin in envoy.yaml");
+        let config: Config<Setting> =
+            serde_yaml::from_str(configuration).expect("failed to parse PoW configuration");
+        assert_eq!(config.decision_log, Some(DecisionLog::Log(LogLevel::Info)));
+    }
 }
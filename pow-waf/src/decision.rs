@@ -0,0 +1,69 @@
+use serde::Serialize;
+
+/// Final outcome of the filter for a single request.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Outcome {
+    /// The request failed before a decision was made, e.g. a hostcall error.
+    #[default]
+    Error,
+    Unmatched,
+    Whitelisted,
+    /// Let through by a `bypass` geo rule.
+    Bypassed,
+    Allowed,
+    Solved,
+    Rejected,
+}
+
+/// Machine-readable record of how the filter handled a request.
+#[derive(Debug, Default, Serialize)]
+pub struct Decision {
+    pub client: Option<String>,
+    pub host: Option<String>,
+    pub route: Option<String>,
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+    pub key: Option<String>,
+    pub counter: Option<u64>,
+    pub difficulty: Option<u64>,
+    pub outcome: Outcome,
+    pub reason: Option<&'static str>,
+    /// The `on_error` policy that decided, while dependencies were unavailable.
+    pub degraded: Option<&'static str>,
+    pub latency_us: u64,
+}
+
+impl Decision {
+    pub fn reject(&mut self, reason: &'static str) {
+        self.outcome = Outcome::Rejected;
+        self.reason = Some(reason);
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("failed to serialize decision")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn serialize_rejection() {
+        let mut decision = Decision {
+            client: Some("10.0.0.1".to_string()),
+            host: Some("example.com".to_string()),
+            route: Some("/api".to_string()),
+            difficulty: Some(100000),
+            ..Default::default()
+        };
+        decision.reject("invalid_nonce");
+
+        let value: serde_json::Value = serde_json::from_str(&decision.to_json()).unwrap();
+        assert_eq!(value["outcome"], "rejected");
+        assert_eq!(value["reason"], "invalid_nonce");
+        assert_eq!(value["difficulty"], 100000);
+        assert_eq!(value["key"], serde_json::Value::Null);
+    }
+}
@@ -0,0 +1,122 @@
+//! Difficulty of a single request, computed the same way by the filter and
+//! by `pow-check` from the counters it would read.
+
+use crate::config::{AggregationTier, GeoAction, Setting};
+use crate::health::Health;
+
+/// What the difficulty of a request depends on besides its route.
+pub struct Load<'a> {
+    /// Requests the route received from all clients within the current bucket.
+    pub route_counter: u64,
+    /// Health of the route's upstream, only read if the route sets an SLO.
+    pub health: Health,
+    /// Requests the client sent within the current bucket, per aggregation tier.
+    pub tiers: Vec<(&'a AggregationTier, u64)>,
+    pub geo_action: Option<&'a GeoAction>,
+}
+
+/// Difficulty of a request along with the parts it was derived from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Difficulty {
+    pub load_multiplier: u64,
+    pub health_multiplier: u64,
+    /// Difficulty of each aggregation tier, in the order of `Load::tiers`.
+    pub tiers: Vec<u64>,
+    /// Index of the tier that decided the difficulty.
+    pub busiest: Option<usize>,
+    pub value: u64,
+}
+
+impl Difficulty {
+    /// Every aggregation tier is enforced, the busiest one decides the
+    /// difficulty. `difficulty` is the global difficulty of the filter.
+    pub fn compute(setting: &Setting, difficulty: u64, load: &Load<'_>) -> Self {
+        let load_multiplier = setting.load_multiplier(load.route_counter);
+        let health_multiplier = setting
+            .health
+            .as_ref()
+            .map_or(0, |slo| load.health.multiplier(slo));
+        let mut value = load_multiplier.max(health_multiplier) * difficulty;
+
+        let mut tiers = Vec::with_capacity(load.tiers.len());
+        let mut busiest = None;
+        for (i, (tier, counter)) in load.tiers.iter().enumerate() {
+            let tier_difficulty = tier.difficulty(*counter, &setting.rate_limit, difficulty);
+            if busiest.is_none() || tier_difficulty > value {
+                busiest = Some(i);
+            }
+            value = value.max(tier_difficulty);
+            tiers.push(tier_difficulty);
+        }
+        if let Some(GeoAction::Multiplier(multiplier)) = load.geo_action {
+            value = value.max(difficulty).saturating_mul(*multiplier);
+        }
+
+        Self {
+            load_multiplier,
+            health_multiplier,
+            tiers,
+            busiest,
+            value,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn compute() {
+        let setting: Setting = serde_yaml::from_str(
+            r#"
+rate_limit:
+  unit: minute
+  requests_per_unit: 10
+load_thresholds:
+  - requests_per_unit: 100
+    multiplier: 2
+health:
+  latency_ms: 100
+  max_multiplier: 8
+"#,
+        )
+        .expect("failed to parse setting");
+        let host = AggregationTier {
+            prefix_len: 32,
+            quota_multiplier: 1,
+        };
+        let network = AggregationTier {
+            prefix_len: 24,
+            quota_multiplier: 4,
+        };
+        let mut load = Load {
+            route_counter: 0,
+            health: Health::default(),
+            tiers: vec![(&host, 0), (&network, 0)],
+            geo_action: None,
+        };
+
+        let quiet = Difficulty::compute(&setting, 1000, &load);
+        assert_eq!(quiet.value, 0);
+        assert_eq!(quiet.busiest, Some(0));
+
+        load.tiers = vec![(&host, 25), (&network, 120)];
+        let busy = Difficulty::compute(&setting, 1000, &load);
+        assert_eq!(busy.tiers, vec![2000, 3000]);
+        assert_eq!(busy.busiest, Some(1));
+        assert_eq!(busy.value, 3000);
+
+        load.route_counter = 150;
+        load.health = Health {
+            error_rate: 0.0,
+            latency_ms: 500.0,
+        };
+        load.geo_action = Some(&GeoAction::Multiplier(2));
+        let degraded = Difficulty::compute(&setting, 1000, &load);
+        assert_eq!(degraded.load_multiplier, 2);
+        assert_eq!(degraded.health_multiplier, 5);
+        assert_eq!(degraded.busiest, Some(0));
+        assert_eq!(degraded.value, 10000);
+    }
+}
@@ -0,0 +1,543 @@
+//! Minimal reader for the MaxMind DB format, see
+//! <https://maxmind.github.io/MaxMind-DB/>.
+//!
+//! The whole database is kept in memory, no mmap or file access is involved so
+//! it runs inside the wasm sandbox.
+
+use std::net::IpAddr;
+
+use thiserror::Error;
+
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+const DATA_SECTION_SEPARATOR: usize = 16;
+/// Nesting limit of maps, arrays and pointers, real databases stay far below.
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Error, Eq, PartialEq)]
+pub enum MmdbError {
+    #[error("metadata marker not found")]
+    MissingMetadata,
+    #[error("invalid metadata: {0}")]
+    InvalidMetadata(&'static str),
+    #[error("unsupported record size: {0}")]
+    UnsupportedRecordSize(u64),
+    #[error("unexpected end of data at offset {0}")]
+    UnexpectedEof(usize),
+    #[error("invalid data at offset {0}")]
+    InvalidData(usize),
+    #[error("corrupted search tree")]
+    InvalidTree,
+}
+
+/// Decoded value of the data section.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    String(String),
+    Double(f64),
+    Bytes(Vec<u8>),
+    Uint(u128),
+    Int(i32),
+    Map(Vec<(String, Value)>),
+    Array(Vec<Value>),
+    Bool(bool),
+    Float(f32),
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    /// Walks nested maps, e.g. `["country", "iso_code"]`.
+    pub fn path(&self, keys: &[&str]) -> Option<&Value> {
+        keys.iter().try_fold(self, |value, key| value.get(key))
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Value::Uint(n) => u64::try_from(*n).ok(),
+            Value::Int(n) => u64::try_from(*n).ok(),
+            _ => None,
+        }
+    }
+}
+
+pub struct Reader {
+    buf: Vec<u8>,
+    node_count: u32,
+    record_size: u16,
+    ip_version: u16,
+    data_section: usize,
+    ipv4_start: u32,
+}
+
+impl Reader {
+    pub fn from_bytes(buf: Vec<u8>) -> Result<Self, MmdbError> {
+        let start = buf
+            .windows(METADATA_MARKER.len())
+            .rposition(|w| w == METADATA_MARKER)
+            .ok_or(MmdbError::MissingMetadata)?
+            + METADATA_MARKER.len();
+        let (metadata, _) = Decoder {
+            buf: &buf[start..],
+            base: 0,
+        }
+        .decode(0)?;
+
+        let field = |name: &'static str| {
+            metadata
+                .get(name)
+                .and_then(Value::as_u64)
+                .ok_or(MmdbError::InvalidMetadata(name))
+        };
+        let node_count = u32::try_from(field("node_count")?)
+            .map_err(|_| MmdbError::InvalidMetadata("node_count"))?;
+        let record_size = match field("record_size")? {
+            size @ (24 | 28 | 32) => size as u16,
+            size => return Err(MmdbError::UnsupportedRecordSize(size)),
+        };
+        let ip_version = match field("ip_version")? {
+            version @ (4 | 6) => version as u16,
+            _ => return Err(MmdbError::InvalidMetadata("ip_version")),
+        };
+
+        let tree_size = node_count as usize * record_size as usize / 4;
+        let data_section = tree_size + DATA_SECTION_SEPARATOR;
+        if data_section > start {
+            return Err(MmdbError::InvalidMetadata("node_count"));
+        }
+
+        let mut reader = Reader {
+            buf,
+            node_count,
+            record_size,
+            ip_version,
+            data_section,
+            ipv4_start: 0,
+        };
+        if ip_version == 6 {
+            // IPv4 addresses live under ::/96 in an IPv6 tree.
+            let mut node = 0;
+            for _ in 0..96 {
+                if node >= node_count {
+                    break;
+                }
+                node = reader.read_record(node, false)?;
+            }
+            reader.ipv4_start = node;
+        }
+        Ok(reader)
+    }
+
+    /// Returns the record of the most specific network containing `ip`.
+    pub fn lookup(&self, ip: IpAddr) -> Result<Option<Value>, MmdbError> {
+        let (bits, len, mut node) = match ip {
+            IpAddr::V4(ip) => (u32::from(ip) as u128, 32, self.ipv4_start),
+            IpAddr::V6(ip) if self.ip_version == 6 => (u128::from(ip), 128, 0),
+            // An IPv4 database knows nothing about IPv6 addresses.
+            IpAddr::V6(_) => return Ok(None),
+        };
+
+        for i in (0..len).rev() {
+            if node >= self.node_count {
+                break;
+            }
+            node = self.read_record(node, (bits >> i) & 1 == 1)?;
+        }
+
+        match node.cmp(&self.node_count) {
+            std::cmp::Ordering::Less => Err(MmdbError::InvalidTree),
+            std::cmp::Ordering::Equal => Ok(None),
+            std::cmp::Ordering::Greater => {
+                let offset = ((node - self.node_count) as usize)
+                    .checked_sub(DATA_SECTION_SEPARATOR)
+                    .ok_or(MmdbError::InvalidTree)?;
+                let decoder = Decoder {
+                    buf: &self.buf[self.data_section..],
+                    base: self.data_section,
+                };
+                decoder.decode(offset).map(|(value, _)| Some(value))
+            }
+        }
+    }
+
+    fn read_record(&self, node: u32, right: bool) -> Result<u32, MmdbError> {
+        let node_size = self.record_size as usize / 4;
+        let offset = node as usize * node_size;
+        let bytes = offset
+            .checked_add(node_size)
+            .and_then(|end| self.buf.get(offset..end))
+            .ok_or(MmdbError::InvalidTree)?;
+        let be = |b: &[u8]| b.iter().fold(0u32, |acc, b| acc << 8 | *b as u32);
+        Ok(match (self.record_size, right) {
+            (24, false) => be(&bytes[0..3]),
+            (24, true) => be(&bytes[3..6]),
+            (28, false) => (bytes[3] as u32 & 0xf0) << 20 | be(&bytes[0..3]),
+            (28, true) => (bytes[3] as u32 & 0x0f) << 24 | be(&bytes[4..7]),
+            (_, false) => be(&bytes[0..4]),
+            (_, true) => be(&bytes[4..8]),
+        })
+    }
+}
+
+struct Decoder<'a> {
+    buf: &'a [u8],
+    /// Offset of `buf` in the whole file, for error reporting.
+    base: usize,
+}
+
+impl Decoder<'_> {
+    fn bytes(&self, offset: usize, len: usize) -> Result<&[u8], MmdbError> {
+        offset
+            .checked_add(len)
+            .and_then(|end| self.buf.get(offset..end))
+            .ok_or(MmdbError::UnexpectedEof(self.base + offset))
+    }
+
+    fn uint(&self, offset: usize, len: usize) -> Result<u128, MmdbError> {
+        let bytes = self.bytes(offset, len)?;
+        Ok(bytes.iter().fold(0u128, |acc, b| acc << 8 | *b as u128))
+    }
+
+    /// Decodes the value at `offset`, returns it along with the offset of the
+    /// next value.
+    fn decode(&self, offset: usize) -> Result<(Value, usize), MmdbError> {
+        self.decode_nested(offset, 0)
+    }
+
+    /// Like `decode`, `depth` counts the enclosing maps, arrays and pointers.
+    fn decode_nested(&self, offset: usize, depth: usize) -> Result<(Value, usize), MmdbError> {
+        let invalid = || MmdbError::InvalidData(self.base + offset);
+        if depth > MAX_DEPTH {
+            return Err(invalid());
+        }
+        let ctrl = self.bytes(offset, 1)?[0];
+        let mut cursor = offset + 1;
+        let mut kind = ctrl >> 5;
+
+        if kind == 1 {
+            let len = ((ctrl >> 3) & 0x3) as usize + 1;
+            let raw = self.uint(cursor, len)? as usize;
+            let low = (ctrl & 0x7) as usize;
+            let pointer = match len {
+                1 => low << 8 | raw,
+                2 => (low << 16 | raw) + 2048,
+                3 => (low << 24 | raw) + 526336,
+                _ => raw,
+            };
+            // A pointer to a pointer is invalid, and would allow loops.
+            if self.bytes(pointer, 1)?[0] >> 5 == 1 {
+                return Err(invalid());
+            }
+            let (value, _) = self.decode_nested(pointer, depth + 1)?;
+            return Ok((value, cursor + len));
+        }
+
+        if kind == 0 {
+            kind = self.bytes(cursor, 1)?[0]
+                .checked_add(7)
+                .ok_or_else(invalid)?;
+            cursor += 1;
+        }
+
+        let mut size = (ctrl & 0x1f) as usize;
+        if size >= 29 && kind != 14 {
+            let len = size - 28;
+            let extra = self.uint(cursor, len)? as usize;
+            size = match len {
+                1 => 29 + extra,
+                2 => 285 + extra,
+                _ => 65821 + extra,
+            };
+            cursor += len;
+        }
+
+        let value = match kind {
+            2 => {
+                let s = std::str::from_utf8(self.bytes(cursor, size)?).map_err(|_| invalid())?;
+                cursor += size;
+                Value::String(s.to_string())
+            }
+            3 => {
+                let bytes = self.bytes(cursor, 8)?;
+                cursor += 8;
+                Value::Double(f64::from_be_bytes(bytes.try_into().expect("8 bytes")))
+            }
+            4 => {
+                let bytes = self.bytes(cursor, size)?.to_vec();
+                cursor += size;
+                Value::Bytes(bytes)
+            }
+            5 | 6 | 9 | 10 => {
+                if size > 16 {
+                    return Err(invalid());
+                }
+                let n = self.uint(cursor, size)?;
+                cursor += size;
+                Value::Uint(n)
+            }
+            7 => {
+                let mut entries = Vec::with_capacity(size.min(self.buf.len()));
+                for _ in 0..size {
+                    let (key, next) = self.decode_nested(cursor, depth + 1)?;
+                    let Value::String(key) = key else {
+                        return Err(MmdbError::InvalidData(self.base + cursor));
+                    };
+                    let (value, next) = self.decode_nested(next, depth + 1)?;
+                    entries.push((key, value));
+                    cursor = next;
+                }
+                Value::Map(entries)
+            }
+            8 => {
+                if size > 4 {
+                    return Err(invalid());
+                }
+                let n = self.uint(cursor, size)? as u32;
+                cursor += size;
+                Value::Int(n as i32)
+            }
+            11 => {
+                let mut items = Vec::with_capacity(size.min(self.buf.len()));
+                for _ in 0..size {
+                    let (item, next) = self.decode_nested(cursor, depth + 1)?;
+                    items.push(item);
+                    cursor = next;
+                }
+                Value::Array(items)
+            }
+            14 => Value::Bool(size != 0),
+            15 => {
+                let bytes = self.bytes(cursor, 4)?;
+                cursor += 4;
+                Value::Float(f32::from_be_bytes(bytes.try_into().expect("4 bytes")))
+            }
+            _ => return Err(invalid()),
+        };
+        Ok((value, cursor))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    /// Encodes `value` in the MMDB data format, without pointers.
+    fn encode(value: &Value, out: &mut Vec<u8>) {
+        let header = |kind: u8, size: usize, out: &mut Vec<u8>| {
+            let (size_bits, extra): (u8, Vec<u8>) = match size {
+                0..=28 => (size as u8, vec![]),
+                29..=284 => (29, vec![(size - 29) as u8]),
+                _ => (30, ((size - 285) as u16).to_be_bytes().to_vec()),
+            };
+            if kind <= 7 {
+                out.push(kind << 5 | size_bits);
+            } else {
+                out.push(size_bits);
+                out.push(kind - 7);
+            }
+            out.extend(extra);
+        };
+        match value {
+            Value::String(s) => {
+                header(2, s.len(), out);
+                out.extend(s.as_bytes());
+            }
+            Value::Uint(n) => {
+                let bytes = n.to_be_bytes();
+                let skip = bytes.iter().take_while(|b| **b == 0).count();
+                let kind = if bytes.len() - skip <= 4 { 6 } else { 9 };
+                header(kind, bytes.len() - skip, out);
+                out.extend(&bytes[skip..]);
+            }
+            Value::Map(entries) => {
+                header(7, entries.len(), out);
+                for (key, value) in entries {
+                    encode(&Value::String(key.clone()), out);
+                    encode(value, out);
+                }
+            }
+            Value::Bool(b) => header(14, *b as usize, out),
+            _ => unimplemented!("not needed by the tests"),
+        }
+    }
+
+    pub(crate) fn map(entries: &[(&str, Value)]) -> Value {
+        Value::Map(
+            entries
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.clone()))
+                .collect(),
+        )
+    }
+
+    /// Builds an IPv6 database with 24-bit records from `(network, prefix, record)`.
+    pub(crate) fn build(networks: &[(IpAddr, u8, Value)]) -> Vec<u8> {
+        // Each node is `[left, right]`.
+        #[derive(Clone, Copy)]
+        enum Slot {
+            Empty,
+            Node(usize),
+            Data(usize),
+        }
+        let mut nodes = vec![[Slot::Empty; 2]];
+        let mut data = vec![];
+        for (ip, prefix, record) in networks {
+            let (bits, prefix) = match ip {
+                IpAddr::V4(ip) => (u32::from(*ip) as u128, *prefix as u32 + 96),
+                IpAddr::V6(ip) => (u128::from(*ip), *prefix as u32),
+            };
+            let offset = data.len();
+            encode(record, &mut data);
+            let mut node = 0;
+            for i in 0..prefix {
+                let bit = ((bits >> (127 - i)) & 1) as usize;
+                if i + 1 == prefix {
+                    nodes[node][bit] = Slot::Data(offset);
+                    break;
+                }
+                node = match nodes[node][bit] {
+                    Slot::Node(next) => next,
+                    _ => {
+                        nodes.push([Slot::Empty; 2]);
+                        let next = nodes.len() - 1;
+                        nodes[node][bit] = Slot::Node(next);
+                        next
+                    }
+                };
+            }
+        }
+
+        let node_count = nodes.len();
+        let mut out = vec![];
+        for node in &nodes {
+            for slot in node {
+                let record = match slot {
+                    Slot::Empty => node_count,
+                    Slot::Node(n) => *n,
+                    Slot::Data(offset) => node_count + DATA_SECTION_SEPARATOR + offset,
+                };
+                out.extend(&(record as u32).to_be_bytes()[1..]);
+            }
+        }
+        out.extend([0; DATA_SECTION_SEPARATOR]);
+        out.extend(data);
+        out.extend(METADATA_MARKER);
+        encode(
+            &map(&[
+                ("node_count", Value::Uint(node_count as u128)),
+                ("record_size", Value::Uint(24)),
+                ("ip_version", Value::Uint(6)),
+                ("database_type", Value::String("Test".to_string())),
+            ]),
+            &mut out,
+        );
+        out
+    }
+
+    #[test]
+    fn lookup() {
+        let db = build(&[
+            (
+                "1.2.3.0".parse().unwrap(),
+                24,
+                map(&[("country", map(&[("iso_code", Value::String("AU".into()))]))]),
+            ),
+            (
+                "2001:db8::".parse().unwrap(),
+                32,
+                map(&[
+                    ("autonomous_system_number", Value::Uint(64512)),
+                    ("anycast", Value::Bool(true)),
+                ]),
+            ),
+        ]);
+        let reader = Reader::from_bytes(db).expect("failed to read database");
+
+        let record = reader.lookup("1.2.3.4".parse().unwrap()).unwrap().unwrap();
+        assert_eq!(
+            record
+                .path(&["country", "iso_code"])
+                .and_then(Value::as_str),
+            Some("AU")
+        );
+
+        let record = reader
+            .lookup("2001:db8:1::1".parse().unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            record
+                .get("autonomous_system_number")
+                .and_then(Value::as_u64),
+            Some(64512)
+        );
+        assert_eq!(record.get("anycast"), Some(&Value::Bool(true)));
+
+        assert_eq!(reader.lookup("1.2.4.1".parse().unwrap()), Ok(None));
+        assert_eq!(reader.lookup("2001:db9::1".parse().unwrap()), Ok(None));
+    }
+
+    #[test]
+    fn decode_pointer() {
+        // A map whose value is a pointer back to the string at offset 0.
+        let mut buf = vec![];
+        encode(&Value::String("US".into()), &mut buf);
+        let map_offset = buf.len();
+        buf.push(7 << 5 | 1);
+        encode(&Value::String("iso_code".into()), &mut buf);
+        buf.extend([1 << 5, 0]);
+
+        let decoder = Decoder { buf: &buf, base: 0 };
+        let (value, next) = decoder.decode(map_offset).unwrap();
+        assert_eq!(value, map(&[("iso_code", Value::String("US".into()))]));
+        assert_eq!(next, buf.len());
+    }
+
+    #[test]
+    fn decode_pointer_cycle() {
+        // A map whose value points back to the map itself.
+        let mut buf = vec![7 << 5 | 1];
+        encode(&Value::String("loop".into()), &mut buf);
+        buf.extend([1 << 5, 0]);
+
+        let decoder = Decoder { buf: &buf, base: 0 };
+        assert!(matches!(decoder.decode(0), Err(MmdbError::InvalidData(_))));
+    }
+
+    #[test]
+    fn record_in_separator() {
+        // The left record of the only node points into the data section
+        // separator, which holds no data.
+        let reader = Reader {
+            buf: vec![0, 0, 2, 0, 0, 1],
+            node_count: 1,
+            record_size: 24,
+            ip_version: 4,
+            data_section: 6 + DATA_SECTION_SEPARATOR,
+            ipv4_start: 0,
+        };
+        assert_eq!(
+            reader.lookup("0.0.0.1".parse().unwrap()),
+            Err(MmdbError::InvalidTree)
+        );
+    }
+
+    #[test]
+    fn missing_metadata() {
+        assert_eq!(
+            Reader::from_bytes(vec![0; 64]).err(),
+            Some(MmdbError::MissingMetadata)
+        );
+    }
+}
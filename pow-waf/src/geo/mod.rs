@@ -0,0 +1,125 @@
+pub mod mmdb;
+
+use std::net::IpAddr;
+
+use base64::prelude::*;
+use mmdb::{Reader, Value};
+
+use crate::config::GeoDatabase;
+
+/// What the GeoIP databases know about a client address.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct GeoInfo {
+    /// ISO 3166-1 alpha-2 country code.
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+}
+
+/// Country and ASN lookup over one or more MMDB databases, e.g. a
+/// GeoLite2-Country and a GeoLite2-ASN database side by side.
+#[derive(Default)]
+pub struct GeoIp {
+    readers: Vec<Reader>,
+}
+
+impl GeoIp {
+    pub fn new(readers: Vec<Reader>) -> Self {
+        Self { readers }
+    }
+
+    /// Opens every configured database, `vm_configuration` backs
+    /// [`GeoDatabase::VmConfiguration`].
+    pub fn load(
+        databases: Vec<GeoDatabase>,
+        vm_configuration: Option<&[u8]>,
+    ) -> Result<Self, String> {
+        let mut readers = vec![];
+        for database in databases {
+            let bytes = match database {
+                GeoDatabase::VmConfiguration => vm_configuration
+                    .ok_or("VM configuration is empty")?
+                    .to_vec(),
+                GeoDatabase::Inline(encoded) => BASE64_STANDARD
+                    .decode(encoded.trim())
+                    .map_err(|e| format!("malformed base64: {}", e))?,
+            };
+            readers.push(Reader::from_bytes(bytes).map_err(|e| e.to_string())?);
+        }
+        Ok(Self::new(readers))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.readers.is_empty()
+    }
+
+    /// The first database that knows a field wins.
+    pub fn lookup(&self, ip: IpAddr) -> GeoInfo {
+        let mut info = GeoInfo::default();
+        for reader in &self.readers {
+            let record = match reader.lookup(ip) {
+                Ok(Some(record)) => record,
+                Ok(None) => continue,
+                Err(e) => {
+                    log::warn!("failed to lookup {} in GeoIP database: {}", ip, e);
+                    continue;
+                }
+            };
+            if info.country.is_none() {
+                info.country = record
+                    .path(&["country", "iso_code"])
+                    .or_else(|| record.path(&["registered_country", "iso_code"]))
+                    .and_then(Value::as_str)
+                    .map(str::to_string);
+            }
+            if info.asn.is_none() {
+                info.asn = record
+                    .get("autonomous_system_number")
+                    .and_then(Value::as_u64)
+                    .and_then(|asn| u32::try_from(asn).ok());
+            }
+        }
+        info
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::mmdb::test::{build, map};
+    use super::*;
+
+    #[test]
+    fn merge_databases() {
+        let country = build(&[(
+            "203.0.113.0".parse().unwrap(),
+            24,
+            map(&[(
+                "registered_country",
+                map(&[("iso_code", Value::String("JP".into()))]),
+            )]),
+        )]);
+        let asn = build(&[(
+            "203.0.0.0".parse().unwrap(),
+            16,
+            map(&[("autonomous_system_number", Value::Uint(64500))]),
+        )]);
+        let geo = GeoIp::new(vec![
+            Reader::from_bytes(country).unwrap(),
+            Reader::from_bytes(asn).unwrap(),
+        ]);
+
+        assert_eq!(
+            geo.lookup("203.0.113.9".parse().unwrap()),
+            GeoInfo {
+                country: Some("JP".to_string()),
+                asn: Some(64500),
+            }
+        );
+        assert_eq!(
+            geo.lookup("203.0.1.1".parse().unwrap()),
+            GeoInfo {
+                country: None,
+                asn: Some(64500),
+            }
+        );
+    }
+}
@@ -0,0 +1,106 @@
+use std::time::Duration;
+
+use pow_runtime::kv_store::{Error, KVStore};
+use serde::{Deserialize, Serialize};
+
+use crate::config::HealthSlo;
+
+/// Weight of the newest sample in the moving averages.
+const SMOOTHING: f64 = 0.1;
+
+/// Exponentially weighted upstream health of a single route.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Health {
+    pub error_rate: f64,
+    pub latency_ms: f64,
+}
+
+impl Health {
+    fn observe(mut self, status: u32, latency: Duration) -> Self {
+        let error = if status >= 500 { 1.0 } else { 0.0 };
+        self.error_rate += (error - self.error_rate) * SMOOTHING;
+        self.latency_ms += (latency.as_millis() as f64 - self.latency_ms) * SMOOTHING;
+        self
+    }
+
+    /// How far the route is beyond its SLO, as a multiple of the global `difficulty`.
+    /// Returns 0 while the upstream meets every configured objective.
+    pub fn multiplier(&self, slo: &HealthSlo) -> u64 {
+        let error_ratio = slo
+            .error_rate_percent
+            .map(|limit| self.error_rate * 100.0 / limit as f64)
+            .unwrap_or(0.0);
+        let latency_ratio = slo
+            .latency_ms
+            .map(|limit| self.latency_ms / limit as f64)
+            .unwrap_or(0.0);
+        let ratio = error_ratio.max(latency_ratio);
+        if ratio <= 1.0 {
+            return 0;
+        }
+        (ratio as u64).clamp(1, slo.max_multiplier)
+    }
+}
+
+/// Per-route health estimates kept in shared data, so every worker thread
+/// sees the same view of the upstream.
+pub struct HealthEstimator {
+    store: KVStore<Health>,
+}
+
+impl HealthEstimator {
+    pub fn new(context_id: u32) -> Self {
+        Self {
+            store: KVStore::new(context_id, "health"),
+        }
+    }
+
+    pub fn get(&self, route: &str) -> Result<Health, Error> {
+        Ok(self.store.get(route)?.unwrap_or_default())
+    }
+
+    pub fn observe(&self, route: &str, status: u32, latency: Duration) -> Result<Health, Error> {
+        self.store
+            .update(route, |health| health.unwrap_or_default().observe(status, latency))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn slo() -> HealthSlo {
+        HealthSlo {
+            error_rate_percent: Some(5),
+            latency_ms: Some(200),
+            max_multiplier: 8,
+        }
+    }
+
+    #[test]
+    fn healthy_upstream_adds_nothing() {
+        let health = (0..100).fold(Health::default(), |h, _| {
+            h.observe(200, Duration::from_millis(50))
+        });
+        assert_eq!(health.multiplier(&slo()), 0);
+    }
+
+    #[test]
+    fn errors_scale_up_and_recover() {
+        let failing = (0..50).fold(Health::default(), |h, _| {
+            h.observe(503, Duration::from_millis(50))
+        });
+        assert_eq!(failing.multiplier(&slo()), 8);
+
+        let recovered = (0..100).fold(failing, |h, _| h.observe(200, Duration::from_millis(50)));
+        assert_eq!(recovered.multiplier(&slo()), 0);
+    }
+
+    #[test]
+    fn latency_over_slo() {
+        let slow = (0..100).fold(Health::default(), |h, _| {
+            h.observe(200, Duration::from_millis(650))
+        });
+        assert_eq!(slow.multiplier(&slo()), 3);
+    }
+}
@@ -1,50 +1,96 @@
+pub mod breaker;
 pub mod chain;
 pub mod config;
+pub mod decision;
+pub mod difficulty;
+pub mod geo;
+pub mod health;
+pub mod metrics;
+pub mod reload;
 
-use chain::btc::BTC;
+use breaker::CircuitBreaker;
+use chain::Chain;
+use config::ClientAggregation;
 use config::Config;
+use config::DecisionLog;
+use config::GeoAction;
+use config::OnError;
 use config::Setting;
+use decision::{Decision, Outcome};
+use difficulty::{Difficulty, Load};
+use geo::GeoIp;
+use health::{Health, HealthEstimator};
 use log::info;
+use metrics::RouteMetrics;
 use pow_runtime::counter_bucket::CounterBucket;
+use pow_runtime::headers::HeaderMutations;
 use pow_runtime::response::Response;
 use pow_runtime::Ctx;
 use pow_runtime::HttpHook;
-use pow_runtime::{Runtime, RuntimeBox};
+use pow_runtime::Runtime;
 use pow_types::bytearray32::ByteArray32;
 use pow_types::cidr::CIDR;
-use pow_types::config::Router;
+use pow_types::config::{Found, Router};
+use pow_types::timestamp::TimestampError;
 use proxy_wasm::traits::*;
 use proxy_wasm::types::*;
+use reload::{RouteChanges, RouteTable};
 use sha2::Digest;
 use std::net::SocketAddr;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 
+const HEADER_TIMESTAMP_NAME: &str = "X-PoW-Timestamp";
+const HEADER_NONCE_NAME: &str = "X-PoW-Nonce";
+const HEADER_BASE_NAME: &str = "X-PoW-Base";
+const HEADER_VERIFIED_DIFFICULTY_NAME: &str = "X-PoW-Verified-Difficulty";
+
+#[cfg(target_arch = "wasm32")]
 proxy_wasm::main! {{
     proxy_wasm::set_log_level(LogLevel::Trace);
-    proxy_wasm::set_root_context(move |context_id| -> Box<dyn RootContext> {
-        Box::new(RuntimeBox::new(Plugin { context_id, inner: None }))
+    proxy_wasm::set_root_context(move |context_id| -> Box<dyn proxy_wasm::traits::RootContext> {
+        Box::new(pow_runtime::RuntimeBox::new(Plugin {
+            context_id,
+            vm_configuration: None,
+            inner: None,
+            routes: RouteTable::new(),
+        }))
     });
 }}
 
 struct Inner {
-    btc: BTC,
+    chain: Chain,
     router: Router<Setting>,
-    counter_bucket: CounterBucket,
+    /// Shared with the configuration that replaces this one, see `on_configure`.
+    counter_bucket: Arc<CounterBucket>,
+    health: HealthEstimator,
     whitelist: Vec<CIDR>,
     difficulty: u64,
+    decision_log: Option<DecisionLog>,
+    client_aggregation: ClientAggregation,
+    geo: GeoIp,
+    anchor_breaker: CircuitBreaker,
+    counter_breaker: CircuitBreaker,
 }
 
+// Only constructed by the wasm entry point.
 #[derive(Clone)]
+#[cfg_attr(not(target_arch = "wasm32"), allow(dead_code))]
 struct Plugin {
     context_id: u32,
+    /// Kept for the GeoIP database, which may be shipped as VM configuration.
+    vm_configuration: Option<Vec<u8>>,
     inner: Option<Arc<Inner>>,
+    /// Routes of the current configuration, to log what a reload changed.
+    routes: RouteTable,
 }
 
 impl Context for Plugin {}
 impl Runtime for Plugin {
     type Hook = Hook;
-    fn on_vm_start(&mut self, _vm_configuration_size: usize) -> bool {
+    fn on_vm_start(&mut self, vm_configuration: Option<Vec<u8>>) -> bool {
         info!("PoW filter starting...");
+        self.vm_configuration = vm_configuration;
         true
     }
 
@@ -76,7 +122,39 @@ impl Runtime for Plugin {
 
         let whitelist = config.whitelist.take().unwrap_or_default();
         let difficulty = config.difficulty;
-        let mempool_upstream_name = config.mempool_upstream_name.clone();
+        let decision_log = config.decision_log.take();
+        let client_aggregation = config.client_aggregation.take().unwrap_or_default();
+        if let Err(e) = client_aggregation.validate() {
+            log::error!("invalid client_aggregation: {}", e);
+            return false;
+        }
+        if let Err(e) = config.validate_routes() {
+            log::error!("invalid route {}", e);
+            return false;
+        }
+        let geo = match GeoIp::load(
+            config.geo_databases.take().unwrap_or_default(),
+            self.vm_configuration.as_deref(),
+        ) {
+            Ok(geo) => geo,
+            Err(e) => {
+                log::error!("failed to load GeoIP database: {}", e);
+                return false;
+            }
+        };
+        let chain_config = match config.chain() {
+            Ok(chain_config) => chain_config,
+            Err(e) => {
+                log::error!("invalid chain source: {}", e);
+                return false;
+            }
+        };
+        let anchor_policy = config.anchors.unwrap_or_default();
+        if let Err(e) = anchor_policy.validate() {
+            log::error!("invalid anchors: {}", e);
+            return false;
+        }
+        let routes = reload::route_table(&config.virtual_hosts);
 
         let router: Router<Setting> = match config.virtual_hosts.try_into() {
             Ok(router) => router,
@@ -91,12 +169,41 @@ impl Runtime for Plugin {
             }
         };
 
+        // Streams in flight keep the previous `Inner` until they finish. The
+        // chain poller and the counters carry over, so a reload neither drops
+        // the anchors clients are solving against nor resets rate limits.
+        let (chain, counter_bucket) = match self.inner.take() {
+            Some(previous) => {
+                let changes = RouteChanges::diff(&self.routes, &routes);
+                info!("PoW filter reconfigured, routes: {}", changes);
+                let chain = if *previous.chain.config() == chain_config
+                    && *previous.chain.policy() == anchor_policy
+                {
+                    previous.chain.clone()
+                } else {
+                    info!("chain source changed, restarting poller");
+                    previous.chain.replace(chain_config, anchor_policy)
+                };
+                (chain, previous.counter_bucket.clone())
+            }
+            None => (
+                Chain::new(chain_config, anchor_policy),
+                Arc::new(CounterBucket::new(self.context_id, "rate_limit")),
+            ),
+        };
+        self.routes = routes;
         self.inner = Some(Arc::new(Inner {
-            btc: BTC::new(mempool_upstream_name),
+            chain,
             router,
-            counter_bucket: CounterBucket::new(self.context_id, "rate_limit"),
+            counter_bucket,
+            health: HealthEstimator::new(self.context_id),
             whitelist,
             difficulty,
+            decision_log,
+            client_aggregation,
+            geo,
+            anchor_breaker: CircuitBreaker::new("chain anchor"),
+            counter_breaker: CircuitBreaker::new("shared counters"),
         }));
         info!("PoW filter configured");
         true
@@ -106,6 +213,8 @@ impl Runtime for Plugin {
         Some(Hook {
             ctx: Ctx::new(_context_id),
             plugin: self.inner.clone().expect("plugin not initialized"),
+            route: Mutex::new(None),
+            decision: Mutex::new(Decision::default()),
         })
     }
 }
@@ -113,6 +222,9 @@ impl Runtime for Plugin {
 pub struct Hook {
     ctx: Ctx,
     plugin: Arc<Inner>,
+    /// Route whose upstream health is tracked, set once the request matched it.
+    route: Mutex<Option<String>>,
+    decision: Mutex<Decision>,
 }
 
 fn transform_u64_to_u8_array(mut value: u64) -> [u8; 8] {
@@ -138,6 +250,8 @@ fn get_difficulty(level: u64) -> ByteArray32 {
 struct DifficultyResponse {
     current: ByteArray32,
     difficulty: ByteArray32,
+    /// Server clock in unix seconds, for clients to correct their skew.
+    server_time: u64,
     error: String,
     message: String,
 }
@@ -213,6 +327,7 @@ fn too_many_request(current: ByteArray32, difficulty: u64, error: String) -> Err
     let body = DifficultyResponse {
         current,
         difficulty: target,
+        server_time: now(),
         error,
         message: "Access restriction triggered".to_string(),
     };
@@ -228,6 +343,16 @@ fn too_many_request(current: ByteArray32, difficulty: u64, error: String) -> Err
     })
 }
 
+fn unavailable() -> Error {
+    let body = serde_json::json!({ "message": "temporarily unavailable, please retry later" });
+    Error::response(Response {
+        code: 503,
+        headers: vec![("Content-Type".to_string(), "application/json".to_string())],
+        body: Some(body.to_string().into_bytes()),
+        trailers: vec![],
+    })
+}
+
 fn forbidden(message: String) -> Error {
     let body = serde_json::json!({ "message": message });
     Error::response(Response {
@@ -254,7 +379,12 @@ impl Hook {
     }
 
     fn get_current_hash(&self) -> Result<ByteArray32, Error> {
-        let Some(last_hash) = self.plugin.btc.get_latest_hash() else {
+        let latest = self
+            .plugin
+            .chain
+            .latest_anchor()
+            .map_err(|s| Error::status("failed to read latest hash", s))?;
+        let Some(last_hash) = latest.map(|a| a.hash) else {
             return Err(Error::status("failed to get latest hash", Status::NotFound));
         };
 
@@ -269,123 +399,429 @@ impl Hook {
     }
 
     fn get_timestamp(&self) -> Result<u64, Error> {
-        self.get_header("X-PoW-Timestamp")?
+        self.get_header(HEADER_TIMESTAMP_NAME)?
             .parse()
             .map_err(|e| forbidden(format!("failed to parse timestamp: {}", e)))
     }
 }
 
-fn now() -> u64 {
+pub(crate) fn now() -> u64 {
     std::time::SystemTime::now()
         .duration_since(std::time::UNIX_EPOCH)
         .expect("failed to get timestamp")
         .as_secs()
 }
 
-impl HttpHook for Hook {
-    fn filter_name() -> Option<&'static str> {
-        Some("PoW")
+fn route_metrics<'a>(found: &'a Option<Found<'_, Setting>>) -> RouteMetrics<'a> {
+    match found {
+        Some(found) => RouteMetrics::new(found.virtual_host(), found.pattern()),
+        None => RouteMetrics::unmatched(),
     }
+}
 
-    async fn on_request_headers(
-        &self,
-        _num_headers: usize,
-        _end_of_stream: bool,
-    ) -> Result<(), impl Into<Response>> {
+impl Hook {
+    fn decision(&self) -> MutexGuard<'_, Decision> {
+        self.decision.lock().expect("failed to lock decision")
+    }
+
+    fn log_decision(&self, sink: &DecisionLog) {
+        let json = self.decision().to_json();
+        let ret = match sink {
+            DecisionLog::Log(level) => proxy_wasm::hostcalls::log((*level).into(), &json),
+            DecisionLog::FilterState => self
+                .ctx
+                .set_property(vec!["pow_waf.decision"], Some(json.as_bytes())),
+        };
+        if let Err(e) = ret {
+            log::warn!("failed to write decision log: {:?}", e);
+        }
+    }
+
+    /// Strips the proof and any client-supplied verification result, then
+    /// tells the upstream which difficulty the client actually paid.
+    fn forwarded_headers(&self) -> HeaderMutations {
+        let mutations = HeaderMutations::default()
+            .remove(HEADER_TIMESTAMP_NAME)
+            .remove(HEADER_NONCE_NAME)
+            .remove(HEADER_BASE_NAME)
+            .remove(HEADER_VERIFIED_DIFFICULTY_NAME);
+        let decision = self.decision();
+        match (decision.outcome, decision.difficulty) {
+            (Outcome::Solved, Some(difficulty)) => {
+                mutations.set(HEADER_VERIFIED_DIFFICULTY_NAME, difficulty.to_string())
+            }
+            _ => mutations,
+        }
+    }
+
+    async fn check(&self) -> Result<(), Error> {
         let addr = self.get_client_address()?;
         let addr: SocketAddr = addr
             .parse()
             .map_err(|s| forbidden(format!("invalid client address {}: {}", s, addr)))?;
+        self.decision().client = Some(addr.ip().to_string());
         if self
             .plugin
             .whitelist
             .iter()
             .any(|cidr| cidr.contains(addr.ip()))
         {
+            // Whitelisted clients pass whatever headers they send, their
+            // metrics name the route only if there is one.
+            let found = match (
+                self.ctx.get_http_request_header(":authority"),
+                self.get_path(),
+            ) {
+                (Ok(Some(host)), Ok(path)) => self.plugin.router.matches(&host, &path),
+                _ => None,
+            };
+            let metrics = route_metrics(&found);
+            metrics.seen();
+            metrics.whitelisted();
+            self.decision().outcome = Outcome::Whitelisted;
             return Ok(());
         }
+
         let host = self.get_header(":authority")?;
         let path = self.get_path()?;
+        self.decision().host = Some(host.clone());
 
         log::debug!("{} -> {}{}", addr, host, path);
 
-        let Some(found) = self.plugin.router.matches(&host, &path) else {
+        let found = self.plugin.router.matches(&host, &path);
+        let metrics = route_metrics(&found);
+        metrics.seen();
+
+        let Some(found) = &found else {
             log::debug!("no matched route found, skip rate limit");
+            self.decision().outcome = Outcome::Unmatched;
             return Ok(());
         };
+        self.decision().route = Some(found.pattern().to_string());
 
-        let key = format!(
-            "{}:{}:{}{}",
-            addr.ip(),
-            found.rate_limit.current_bucket(),
-            host,
-            found.pattern()
-        );
-        let counter = self
+        let geo_action = if self.plugin.geo.is_empty() {
+            None
+        } else {
+            let info = self.plugin.geo.lookup(addr.ip());
+            let action = found.geo_action(&info);
+            let mut decision = self.decision();
+            decision.country = info.country;
+            decision.asn = info.asn;
+            action
+        };
+        match geo_action {
+            Some(GeoAction::Block) => {
+                metrics.rejected("geo_blocked");
+                self.decision().reject("geo_blocked");
+                return Err(forbidden("access from your network is blocked".to_string()));
+            }
+            Some(GeoAction::Bypass) => {
+                metrics.bypassed();
+                self.decision().outcome = Outcome::Bypassed;
+                return Ok(());
+            }
+            _ => {}
+        }
+
+        let on_error = found.on_error.unwrap_or_default();
+        let breaker = &self.plugin.counter_breaker;
+        if !breaker.allow(now()) {
+            return self.degraded(on_error, None, found, &path, &metrics);
+        }
+        let (difficulty, keys) = match self.rate_limit(addr, &host, found, geo_action).await {
+            Ok(limited) => {
+                breaker.success();
+                limited
+            }
+            Err(e) => {
+                breaker.failure(&e, now());
+                return self.degraded(on_error, None, found, &path, &metrics);
+            }
+        };
+        let count = || {
+            for key in &keys {
+                self.plugin.counter_bucket.inc(key, 1);
+            }
+        };
+
+        if difficulty == 0 {
+            count();
+            self.decision().outcome = Outcome::Allowed;
+            return Ok(());
+        }
+
+        // Only a challenge needs the anchor, the counters above work without it.
+        let breaker = &self.plugin.anchor_breaker;
+        let current = if breaker.allow(now()) {
+            match self.get_current_hash() {
+                Ok(current) => {
+                    breaker.success();
+                    Some(current)
+                }
+                Err(e) => {
+                    breaker.failure(&e, now());
+                    None
+                }
+            }
+        } else {
+            None
+        };
+        let result = match current {
+            Some(current) => self.verify(current, difficulty, found, &path, &metrics),
+            None => self.degraded(on_error, Some(difficulty), found, &path, &metrics),
+        };
+        if result.is_ok() {
+            count();
+        }
+        result
+    }
+
+    /// Reads the counters of the request and prices it. Returns the
+    /// difficulty along with the counter keys to bump once the request passes.
+    async fn rate_limit(
+        &self,
+        addr: SocketAddr,
+        host: &str,
+        found: &Found<'_, Setting>,
+        geo_action: Option<&GeoAction>,
+    ) -> Result<(u64, Vec<String>), Error> {
+        let bucket = found.rate_limit.current_bucket();
+        let route_key = format!("route:{}:{}{}", bucket, host, found.pattern());
+        let route_counter = self
             .plugin
             .counter_bucket
-            .get(&key)
-            .map_err(|s| Error::other("failed to get counter", s))?;
-        let difficulty =
-            counter / found.rate_limit.requests_per_unit as u64 * self.plugin.difficulty;
-        let current = self.get_current_hash()?;
+            .get(&route_key)
+            .map_err(|s| Error::other("failed to get route counter", s))?;
+        self.plugin.counter_bucket.inc(&route_key, 1);
+        let health = match &found.health {
+            Some(_) => {
+                let route = format!("{}{}", host, found.pattern());
+                let health = self
+                    .plugin
+                    .health
+                    .get(&route)
+                    .map_err(|s| Error::other("failed to get route health", s))?;
+                *self.route.lock().expect("failed to lock route") = Some(route);
+                health
+            }
+            None => Health::default(),
+        };
+
+        let mut keys = vec![];
+        let mut tiers = vec![];
+        let tier_keys = self
+            .plugin
+            .client_aggregation
+            .keys(addr.ip(), bucket, host, found.pattern())
+            .map_err(|e| Error::other("failed to aggregate client address", e))?;
+        for (key, tier) in tier_keys {
+            let counter = self
+                .plugin
+                .counter_bucket
+                .get(&key)
+                .map_err(|s| Error::other("failed to get counter", s))?;
+            keys.push(key);
+            tiers.push((tier, counter));
+        }
+        let load = Load {
+            route_counter,
+            health,
+            tiers,
+            geo_action,
+        };
+        let computed = Difficulty::compute(found, self.plugin.difficulty, &load);
+        let difficulty = computed.value;
+        let (key, counter) = computed
+            .busiest
+            .map(|i| (keys[i].clone(), load.tiers[i].1))
+            .unwrap_or_default();
+        {
+            let mut decision = self.decision();
+            decision.key = Some(key.clone());
+            decision.counter = Some(counter);
+            decision.difficulty = Some(difficulty);
+        }
         log::debug!(
-            "key: {}, counter: {}, difficulty: {}",
+            "key: {}, counter: {}, route counter: {}, difficulty: {}",
             key,
             counter,
+            route_counter,
             difficulty
         );
 
-        if difficulty == 0 {
-            self.plugin.counter_bucket.inc(&key, 1);
-            return Ok(());
+        Ok((difficulty, keys))
+    }
+
+    /// Handles a request the way its route's `on_error` policy says, while
+    /// the rate limit can't be evaluated. A challenge costs at least the
+    /// global difficulty, or the `computed` one if the counters could be read.
+    fn degraded(
+        &self,
+        on_error: OnError,
+        computed: Option<u64>,
+        found: &Found<'_, Setting>,
+        path: &str,
+        metrics: &RouteMetrics<'_>,
+    ) -> Result<(), Error> {
+        let policy = match on_error {
+            OnError::Allow => "allow",
+            OnError::Deny => "deny",
+            OnError::Challenge => "challenge",
+        };
+        metrics.degraded(policy);
+        self.decision().degraded = Some(policy);
+        match on_error {
+            OnError::Allow => {
+                self.decision().outcome = Outcome::Allowed;
+                Ok(())
+            }
+            OnError::Deny => {
+                metrics.rejected("unavailable");
+                self.decision().reject("unavailable");
+                Err(unavailable())
+            }
+            OnError::Challenge => {
+                let current = match (self.get_current_hash(), self.plugin.chain.fallback_seed()) {
+                    (Ok(current), _) => current,
+                    (Err(_), Some(seed)) => seed
+                        .as_str()
+                        .try_into()
+                        .expect("local seed is a 32-byte hex string"),
+                    (Err(e), None) => {
+                        log::warn!("no anchor and no local seed: {:?}", e);
+                        metrics.rejected("unavailable");
+                        self.decision().reject("unavailable");
+                        return Err(unavailable());
+                    }
+                };
+                let difficulty = computed.map_or(self.plugin.difficulty, |computed| {
+                    computed.max(self.plugin.difficulty)
+                });
+                self.decision().difficulty = Some(difficulty);
+                self.verify(current, difficulty, found, path, metrics)
+            }
         }
+    }
 
-        let target = get_difficulty(difficulty);
+    /// Checks the proof of work carried by the request, answering with a
+    /// challenge against `current` when it is missing or wrong.
+    fn verify(
+        &self,
+        current: ByteArray32,
+        difficulty: u64,
+        found: &Found<'_, Setting>,
+        path: &str,
+        metrics: &RouteMetrics<'_>,
+    ) -> Result<(), Error> {
+        metrics.challenged();
 
-        let make_body = |error: &str| too_many_request(current, difficulty, error.to_string());
+        let make_body = |reason: &'static str, error: &str| {
+            metrics.rejected(reason);
+            self.decision().reject(reason);
+            too_many_request(current, difficulty, error.to_string())
+        };
 
-        let timestamp = self
-            .get_timestamp()
-            .map_err(|_| make_body("Missing X-PoW-Timestamp in header, or malformed"))?;
+        let timestamp = self.get_timestamp().map_err(|_| {
+            make_body(
+                "missing_timestamp",
+                "Missing X-PoW-Timestamp in header, or malformed",
+            )
+        })?;
 
-        if timestamp + 60 < now() {
-            return Err(make_body("timestamp expired"));
+        match found.timestamp_window.check(timestamp, now()) {
+            Ok(()) => {}
+            Err(e @ TimestampError::Expired) => {
+                return Err(make_body("timestamp_expired", &e.to_string()))
+            }
+            Err(e @ TimestampError::InFuture) => {
+                return Err(make_body("timestamp_in_future", &e.to_string()))
+            }
         }
 
         let nonce = self
-            .get_header("X-PoW-Nonce")
-            .map_err(|_| make_body("Missing X-PoW-Nonce in header"))?;
+            .get_header(HEADER_NONCE_NAME)
+            .map_err(|_| make_body("missing_nonce", "Missing X-PoW-Nonce in header"))?;
 
-        let nonce = hex::decode(nonce)
-            .map_err(|s| make_body(&format!("X-PoW-Nonce must be a hex string: {}", s)))?;
+        let nonce = hex::decode(nonce).map_err(|s| {
+            make_body(
+                "malformed_nonce",
+                &format!("X-PoW-Nonce must be a hex string: {}", s),
+            )
+        })?;
 
         let last = self
-            .get_header("X-PoW-Base")
-            .map_err(|_| make_body("Missing X-PoW-Base in header"))?;
+            .get_header(HEADER_BASE_NAME)
+            .map_err(|_| make_body("missing_base", "Missing X-PoW-Base in header"))?;
 
-        if !self.plugin.btc.check_in_list(&last) {
-            return Err(make_body("X-PoW-Base are expired, please use current"));
-        }
+        let depth = self.plugin.chain.anchor_depth(&last).or_else(|| {
+            // Only while there is no anchor, see `OnError::Challenge`.
+            (self.plugin.chain.fallback_seed().as_deref() == Some(last.as_str())).then_some(0)
+        });
+        let Some(depth) = depth else {
+            return Err(make_body(
+                "expired_base",
+                "X-PoW-Base are expired, please use current",
+            ));
+        };
+        // Older anchors cost more, the verified difficulty is what was paid.
+        let difficulty = self.plugin.chain.policy().difficulty(difficulty, depth);
+        self.decision().difficulty = Some(difficulty);
+        let target = get_difficulty(difficulty);
 
-        let last: ByteArray32 = last
-            .as_str()
-            .try_into()
-            .map_err(|e| make_body(&format!("failed to parse X-PoW-Base hash: {}", e)))?;
+        let last: ByteArray32 = last.as_str().try_into().map_err(|e| {
+            make_body(
+                "malformed_base",
+                &format!("failed to parse X-PoW-Base hash: {}", e),
+            )
+        })?;
 
         let mut data = last.as_bytes().to_vec();
         data.extend(timestamp.to_be_bytes());
         data.extend(path.as_bytes());
 
         if !valid_nonce(&data, target, &nonce) {
-            return Err(make_body("Invalid nonce, maybe difficulty upgraded"));
+            return Err(make_body(
+                "invalid_nonce",
+                "Invalid nonce, maybe difficulty upgraded",
+            ));
         }
 
-        self.plugin.counter_bucket.inc(&key, 1);
+        metrics.solved(difficulty);
+        self.decision().outcome = Outcome::Solved;
         Ok(())
     }
 }
 
+impl HttpHook for Hook {
+    fn filter_name() -> Option<&'static str> {
+        Some("PoW")
+    }
+
+    async fn on_request_headers(
+        &self,
+        _num_headers: usize,
+        _end_of_stream: bool,
+    ) -> Result<impl Into<HeaderMutations>, impl Into<Response>> {
+        let start = Instant::now();
+        let result = self.check().await;
+        if let Some(sink) = &self.plugin.decision_log {
+            self.decision().latency_us = start.elapsed().as_micros() as u64;
+            self.log_decision(sink);
+        }
+        result.map(|()| self.forwarded_headers())
+    }
+
+    fn on_upstream_response(&self, status: u32, latency: Duration) {
+        let Some(route) = self.route.lock().expect("failed to lock route").take() else {
+            return;
+        };
+        match self.plugin.health.observe(&route, status, latency) {
+            Ok(health) => log::debug!("route: {}, health: {:?}", route, health),
+            Err(e) => log::warn!("failed to update route health: {}", e),
+        }
+    }
+}
+
 fn valid_nonce(data: &[u8], difficulty: ByteArray32, nonce: &[u8]) -> bool {
     let mut hasher = sha2::Sha256::new();
     hasher.update(data);
@@ -398,8 +834,516 @@ fn valid_nonce(data: &[u8], difficulty: ByteArray32, nonce: &[u8]) -> bool {
 
 #[cfg(test)]
 mod test {
-    use crate::valid_nonce;
+    use std::collections::VecDeque;
+
+    use crate::{chain::Anchor, get_difficulty, now, reload::RouteTable, valid_nonce, Plugin};
+    use pow_host::{Host, HttpResponse, Stream};
+    use pow_runtime::RuntimeBox;
     use pow_types::bytearray32::ByteArray32;
+    use proxy_wasm::types::Action;
+
+    /// The genesis block, its header verifies like any other.
+    const TIP: &str = "000000000019d6689c085ae165831e934ff763ae46a2a6c172b3f1b60a8ce26f";
+    const TIP_HEADER: &str = "0100000000000000000000000000000000000000000000000000000000000000000000003ba3edfd7a7b12b27ac72c3e67768f617fc81bc3888a51323a9fb8aa4b1e5e4a29ab5f49ffff001d1dac2b7c";
+
+    /// The chain fixtures are early mainnet blocks, far below the default
+    /// minimum work.
+    const CONFIG: &str = r#"
+chain:
+  btc:
+    upstreams:
+      - upstream: mempool
+    min_work_bits: 0x1d00ffff
+difficulty: 16
+log_level: debug
+decision_log: filter_state
+whitelist:
+  - 192.168.0.0/16
+virtual_hosts:
+  - host: example.com
+    routes:
+      - path: "/"
+        rate_limit:
+          unit: minute
+          requests_per_unit: 1
+"#;
+
+    /// A host with the mempool upstreams scripted, not started yet.
+    fn host() -> Host {
+        let host = Host::new(|context_id| {
+            Box::new(RuntimeBox::new(Plugin {
+                context_id,
+                vm_configuration: None,
+                inner: None,
+                routes: RouteTable::new(),
+            }))
+        });
+        for upstream in ["mempool", "mempool-backup"] {
+            host.upstream(upstream, |call| match call.header(":path") {
+                Some("/api/blocks/tip/hash") => Some(HttpResponse::ok(TIP)),
+                Some(path) if path == format!("/api/block/{}/header", TIP) => {
+                    Some(HttpResponse::ok(TIP_HEADER))
+                }
+                path => panic!("unexpected path {:?}", path),
+            });
+        }
+        host
+    }
+
+    fn start() -> Host {
+        let host = host();
+        assert!(host.start(None, CONFIG.as_bytes()));
+        // Dispatch the poll, deliver the tip hash, deliver its header.
+        host.ticks(3);
+        assert_eq!(host.http_calls().len(), 2);
+        host
+    }
+
+    fn request(host: &Host, client: &str, headers: &[(&str, &str)]) -> Stream {
+        let stream = host.stream(client);
+        assert_eq!(stream.request_headers(headers, true), Action::Pause);
+        host.tick();
+        stream
+    }
+
+    fn decision(stream: &Stream) -> serde_json::Value {
+        let raw = stream
+            .property(&["pow_waf.decision"])
+            .expect("missing decision");
+        serde_json::from_slice(&raw).expect("malformed decision")
+    }
+
+    #[test]
+    fn passes_unmatched_and_whitelisted() {
+        let host = start();
+
+        let stream = request(
+            &host,
+            "10.0.0.1:1234",
+            &[(":authority", "other.com"), (":path", "/")],
+        );
+        assert!(stream.request_resumed());
+        assert_eq!(decision(&stream)["outcome"], "unmatched");
+
+        for _ in 0..3 {
+            let stream = request(
+                &host,
+                "192.168.1.1:1234",
+                &[(":authority", "example.com"), (":path", "/")],
+            );
+            assert!(stream.request_resumed());
+            assert_eq!(decision(&stream)["outcome"], "whitelisted");
+            stream.finish();
+        }
+
+        // Whitelisted clients don't need to name a host.
+        let stream = request(&host, "192.168.1.1:1234", &[(":path", "/")]);
+        assert!(stream.request_resumed());
+        assert_eq!(decision(&stream)["outcome"], "whitelisted");
+    }
+
+    #[test]
+    fn challenges_over_rate_limit() {
+        let host = start();
+        let headers = [
+            (":authority", "example.com"),
+            (":path", "/"),
+            ("X-PoW-Verified-Difficulty", "1000000"),
+        ];
+
+        let stream = request(&host, "10.0.0.1:1234", &headers);
+        assert!(stream.request_resumed());
+        assert_eq!(decision(&stream)["outcome"], "allowed");
+        // A client can't claim a difficulty it never paid.
+        assert_eq!(stream.request_header("X-PoW-Verified-Difficulty"), None);
+
+        let stream = request(&host, "10.0.0.1:1234", &headers);
+        assert!(!stream.request_resumed());
+        let response = stream.local_response().expect("missing challenge");
+        assert_eq!(response.status, 429);
+        let body: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(body["current"], TIP);
+        assert_eq!(
+            body["error"],
+            "Missing X-PoW-Timestamp in header, or malformed"
+        );
+        assert_eq!(decision(&stream)["reason"], "missing_timestamp");
+
+        // Other clients keep their own quota.
+        let stream = request(&host, "10.0.0.2:1234", &headers);
+        assert!(stream.request_resumed());
+    }
+
+    /// Timestamp and nonce of a proof for `/` against `base`.
+    fn solve(base: &str, difficulty: u64) -> (String, String) {
+        let base: ByteArray32 = base.try_into().unwrap();
+        let timestamp = now();
+        let mut data = base.as_bytes().to_vec();
+        data.extend(timestamp.to_be_bytes());
+        data.extend(b"/");
+        let nonce = loop {
+            let nonce = rand::random::<[u8; 8]>();
+            if valid_nonce(&data, get_difficulty(difficulty), &nonce) {
+                break nonce;
+            }
+        };
+        (timestamp.to_string(), hex::encode(nonce))
+    }
+
+    #[test]
+    fn accepts_solved_challenge() {
+        let host = start();
+        let headers = [(":authority", "example.com"), (":path", "/")];
+        request(&host, "10.0.0.1:1234", &headers).finish();
+
+        let (timestamp, nonce) = solve(TIP, 16);
+        let mut solved = headers.to_vec();
+        solved.extend([
+            ("X-PoW-Base", TIP),
+            ("X-PoW-Timestamp", timestamp.as_str()),
+            ("X-PoW-Nonce", nonce.as_str()),
+        ]);
+        let stream = request(&host, "10.0.0.1:1234", &solved);
+        assert!(stream.request_resumed(), "{:?}", stream.local_response());
+        assert_eq!(decision(&stream)["outcome"], "solved");
+        let verified = stream.request_header("X-PoW-Verified-Difficulty");
+        assert_eq!(verified.as_deref(), Some("16"));
+        assert_eq!(stream.request_header("X-PoW-Nonce"), None);
+        assert_eq!(stream.request_header("X-PoW-Base"), None);
+
+        // The same proof against a stale base is refused.
+        solved[2].1 = "0000000000000000000000000000000000000000000000000000000000000000";
+        let stream = request(&host, "10.0.0.1:1234", &solved);
+        assert_eq!(stream.local_response().map(|r| r.status), Some(429));
+        assert_eq!(decision(&stream)["reason"], "expired_base");
+    }
+
+    #[test]
+    fn reload_keeps_state() {
+        let host = start();
+        let headers = [(":authority", "example.com"), (":path", "/")];
+        request(&host, "10.0.0.1:1234", &headers).finish();
+
+        let reloaded = CONFIG.replace("requests_per_unit: 1", "requests_per_unit: 2")
+            + r#"
+      - path: "/login"
+        rate_limit:
+          unit: minute
+          requests_per_unit: 1
+"#;
+        assert!(host.configure(reloaded.as_bytes()));
+        assert!(host.logs().iter().any(|(_, message)| message
+            == "PoW filter reconfigured, routes: 1 added [example.com/login], \
+                1 changed [example.com/], 0 unchanged"));
+
+        host.ticks(2);
+        // The poller carried over, nothing polled again.
+        assert_eq!(host.http_calls().len(), 2);
+        // So did the counter, the second request still fits the new quota.
+        let stream = request(&host, "10.0.0.1:1234", &headers);
+        assert!(stream.request_resumed());
+        assert_eq!(decision(&stream)["counter"], 1);
+        let stream = request(&host, "10.0.0.1:1234", &headers);
+        assert_eq!(stream.local_response().map(|r| r.status), Some(429));
+
+        // A broken configuration leaves the running one in place.
+        assert!(!host.configure(b"virtual_hosts: 42"));
+        let stream = request(&host, "10.0.0.2:1234", &headers);
+        assert!(stream.request_resumed());
+    }
+
+    #[test]
+    fn reload_restarts_poller_on_new_upstream() {
+        let host = start();
+        let reloaded = CONFIG.replace("upstream: mempool\n", "upstream: mempool-backup\n");
+        assert!(host.configure(reloaded.as_bytes()));
+        host.ticks(2);
+
+        // The tip is known already, its header isn't fetched again.
+        let calls = host.http_calls();
+        assert_eq!(calls.len(), 3);
+        assert_eq!(calls[2].upstream, "mempool-backup");
+
+        // The anchors survived the swap.
+        let stream = request(
+            &host,
+            "10.0.0.1:1234",
+            &[(":authority", "example.com"), (":path", "/")],
+        );
+        assert!(stream.request_resumed());
+    }
+
+    #[test]
+    fn polls_ethereum_source() {
+        let host = host();
+        let block = "0x8e38b4dbf6b11fcc3b9dee84fb7986e29ca0a02cecd8977c161ff7333329681e";
+        host.upstream("geth", move |call| {
+            assert_eq!(call.header(":method"), Some("POST"));
+            assert_eq!(call.header(":authority"), Some("rpc.example.com"));
+            let request: serde_json::Value =
+                serde_json::from_slice(call.body.as_deref().unwrap_or_default()).unwrap();
+            assert_eq!(request["method"], "eth_getBlockByNumber");
+            let response = serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": request["id"],
+                "result": { "number": "0x1", "hash": block },
+            });
+            Some(HttpResponse::ok(response.to_string()))
+        });
+        let config = CONFIG.replace("  btc:\n", "  ethereum:\n").replace(
+            "      - upstream: mempool\n    min_work_bits: 0x1d00ffff\n",
+            "      - upstream: geth\n        authority: rpc.example.com\n",
+        );
+        assert!(host.start(None, config.as_bytes()));
+        host.ticks(2);
+        assert_eq!(host.http_calls()[0].upstream, "geth");
+        assert_eq!(current_base(&host), &block[2..]);
+    }
+
+    /// The base a client over its quota is challenged with.
+    fn current_base(host: &Host) -> serde_json::Value {
+        let headers = [(":authority", "example.com"), (":path", "/")];
+        request(host, "10.9.9.9:1234", &headers).finish();
+        let stream = request(host, "10.9.9.9:1234", &headers);
+        let response = stream.local_response().expect("missing challenge");
+        let body: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        body["current"].clone()
+    }
+
+    #[test]
+    fn previous_anchor_costs_more() {
+        const NEXT: &str = "00000000839a8e6886ab5951d76f411475428afc90947ee320161bbf18eb6048";
+        const NEXT_HEADER: &str = "010000006fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000982051fd1e4ba744bbbe680e1fee14677ba1a3c3540bf7b1cdb606e857233e0e61bc6649ffff001d01e36299";
+        let host = host();
+        host.upstream("next", |call| match call.header(":path") {
+            Some("/api/blocks/tip/hash") => Some(HttpResponse::ok(NEXT)),
+            _ => Some(HttpResponse::ok(NEXT_HEADER)),
+        });
+        let config = CONFIG.to_string()
+            + r#"
+anchors:
+  poll_interval_secs: 1
+  stale_multiplier: 4
+"#;
+        assert!(host.start(None, config.as_bytes()));
+        host.ticks(3);
+
+        // The next block shows up once the old poller lets go of the anchors.
+        let next = config.replace("upstream: mempool\n", "upstream: next\n");
+        assert!(host.configure(next.as_bytes()));
+        assert!(host.tick_until(100, || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            current_base(&host) == NEXT
+        }));
+
+        let headers = [(":authority", "example.com"), (":path", "/")];
+        for (client, base, paid) in [("10.0.0.1:1234", NEXT, "16"), ("10.1.0.1:1234", TIP, "64")] {
+            request(&host, client, &headers).finish();
+            let (timestamp, nonce) = solve(base, paid.parse().unwrap());
+            let mut solved = headers.to_vec();
+            solved.extend([
+                ("X-PoW-Base", base),
+                ("X-PoW-Timestamp", timestamp.as_str()),
+                ("X-PoW-Nonce", nonce.as_str()),
+            ]);
+            let stream = request(&host, client, &solved);
+            assert!(stream.request_resumed(), "{:?}", stream.local_response());
+            let verified = stream.request_header("X-PoW-Verified-Difficulty");
+            assert_eq!(verified.as_deref(), Some(paid));
+        }
+    }
+
+    /// A host whose chain never yields an anchor, `on_error` set to `policy`.
+    fn start_without_anchor(policy: &str) -> Host {
+        let host = host();
+        host.upstream("down", |_| None);
+        let config = CONFIG
+            .replace("upstream: mempool\n", "upstream: down\n")
+            .replace(
+                "          requests_per_unit: 1\n",
+                &format!(
+                    "          requests_per_unit: 1\n        on_error: {}\n",
+                    policy
+                ),
+            );
+        assert!(host.start(None, config.as_bytes()));
+        host.ticks(2);
+        host
+    }
+
+    #[test]
+    fn denies_without_anchor_and_logs_once() {
+        let host = start_without_anchor("deny");
+        let headers = [(":authority", "example.com"), (":path", "/")];
+        // Under the limit nobody needs an anchor.
+        let stream = request(&host, "10.0.0.1:1234", &headers);
+        assert!(stream.request_resumed());
+
+        for _ in 0..3 {
+            let stream = request(&host, "10.0.0.1:1234", &headers);
+            assert_eq!(stream.local_response().map(|r| r.status), Some(503));
+            assert_eq!(decision(&stream)["degraded"], "deny");
+        }
+        // The open anchor circuit leaves the counters to clients under quota.
+        let stream = request(&host, "10.1.0.1:1234", &headers);
+        assert!(stream.request_resumed());
+        assert_eq!(decision(&stream)["outcome"], "allowed");
+        assert_eq!(decision(&stream)["degraded"], serde_json::Value::Null);
+
+        let warnings = host
+            .logs()
+            .into_iter()
+            .filter(|(_, m)| m.starts_with("chain anchor unavailable"))
+            .count();
+        assert_eq!(warnings, 1);
+    }
+
+    #[test]
+    fn allows_without_anchor() {
+        let host = start_without_anchor("allow");
+        let headers = [(":authority", "example.com"), (":path", "/")];
+        for _ in 0..3 {
+            let stream = request(&host, "10.0.0.1:1234", &headers);
+            assert!(stream.request_resumed());
+        }
+        let stream = request(&host, "10.0.0.1:1234", &headers);
+        assert_eq!(decision(&stream)["outcome"], "allowed");
+        assert_eq!(decision(&stream)["degraded"], "allow");
+    }
+
+    #[test]
+    fn challenges_against_local_seed() {
+        let host = start_without_anchor("challenge");
+        let headers = [(":authority", "example.com"), (":path", "/")];
+        request(&host, "10.0.0.1:1234", &headers).finish();
+
+        let stream = request(&host, "10.0.0.1:1234", &headers);
+        let response = stream.local_response().expect("missing challenge");
+        assert_eq!(response.status, 429);
+        let body: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        let seed = body["current"].as_str().unwrap().to_string();
+
+        let (timestamp, nonce) = solve(&seed, 16);
+        let mut solved = headers.to_vec();
+        solved.extend([
+            ("X-PoW-Base", seed.as_str()),
+            ("X-PoW-Timestamp", timestamp.as_str()),
+            ("X-PoW-Nonce", nonce.as_str()),
+        ]);
+        let stream = request(&host, "10.0.0.1:1234", &solved);
+        assert!(stream.request_resumed(), "{:?}", stream.local_response());
+        assert_eq!(decision(&stream)["outcome"], "solved");
+        assert_eq!(decision(&stream)["degraded"], "challenge");
+        assert_eq!(decision(&stream)["difficulty"], 16);
+        stream.finish();
+
+        // The quota still counts while the anchor is missing.
+        let stream = request(&host, "10.0.0.1:1234", &headers);
+        assert_eq!(stream.local_response().map(|r| r.status), Some(429));
+        assert_eq!(decision(&stream)["degraded"], "challenge");
+        assert_eq!(decision(&stream)["difficulty"], 32);
+    }
+
+    #[test]
+    fn challenges_against_local_seed_on_unreadable_anchors() {
+        let host = start();
+        let key = std::any::type_name::<VecDeque<Anchor>>();
+        host.set_shared_data(key, b"garbage");
+        let headers = [(":authority", "example.com"), (":path", "/")];
+        request(&host, "10.0.0.1:1234", &headers).finish();
+
+        let stream = request(&host, "10.0.0.1:1234", &headers);
+        let response = stream.local_response().expect("missing challenge");
+        assert_eq!(response.status, 429);
+        let body: serde_json::Value = serde_json::from_slice(&response.body).unwrap();
+        assert_ne!(body["current"], TIP);
+        assert_eq!(decision(&stream)["degraded"], "challenge");
+        assert!(host
+            .logs()
+            .iter()
+            .any(|(_, m)| m.starts_with("chain anchor unavailable")));
+    }
+
+    #[test]
+    fn fails_over_to_next_upstream() {
+        let host = host();
+        host.upstream("down", |_| None);
+        let config = CONFIG.replace(
+            "      - upstream: mempool\n",
+            "      - upstream: down\n      - upstream: mempool\n",
+        );
+        assert!(host.start(None, config.as_bytes()));
+        // Dispatch, fail over, deliver the tip, deliver its header.
+        host.ticks(4);
+
+        let calls: Vec<_> = host.http_calls().into_iter().map(|c| c.upstream).collect();
+        assert_eq!(calls, ["down", "mempool", "mempool"]);
+        assert_eq!(current_base(&host), TIP);
+    }
+
+    #[test]
+    fn waits_for_quorum() {
+        let host = host();
+        let fork = "00000000000000000002ffffffffffffffffffffffffffffffffffffffffffff";
+        host.upstream("fork", move |_| Some(HttpResponse::ok(fork)));
+        let config = CONFIG.replace(
+            "      - upstream: mempool\n",
+            r#"      - upstream: fork
+      - upstream: mempool
+      - upstream: mempool-backup
+    quorum: 2
+"#,
+        );
+        assert!(host.start(None, config.as_bytes()));
+        host.ticks(3);
+
+        // Three tips, three headers, the fork's one malformed.
+        assert_eq!(host.http_calls().len(), 6);
+        assert_eq!(current_base(&host), TIP);
+    }
+
+    #[test]
+    fn rejects_unverified_tip() {
+        let host = host();
+        let forged = "0000000000000000000000000000000000000000000000000000000000000001";
+        host.upstream("forged", move |call| match call.header(":path") {
+            Some("/api/blocks/tip/hash") => Some(HttpResponse::ok(forged)),
+            // Someone else's header, carrying real work.
+            _ => Some(HttpResponse::ok(TIP_HEADER)),
+        });
+        let config = CONFIG.replace("upstream: mempool\n", "upstream: forged\n");
+        assert!(host.start(None, config.as_bytes()));
+        host.ticks(3);
+
+        assert_eq!(host.http_calls().len(), 2);
+        let message = format!("header of {} hashes to {}", forged, TIP);
+        assert!(host.logs().iter().any(|(_, m)| *m == message));
+        // No anchor, the challenge is against the local seed.
+        let headers = [(":authority", "example.com"), (":path", "/")];
+        request(&host, "10.0.0.1:1234", &headers).finish();
+        let stream = request(&host, "10.0.0.1:1234", &headers);
+        assert_eq!(stream.local_response().map(|r| r.status), Some(429));
+        assert_eq!(decision(&stream)["degraded"], "challenge");
+    }
+
+    #[test]
+    fn rejects_tip_below_minimum_work() {
+        let host = host();
+        let config = CONFIG.replace("    min_work_bits: 0x1d00ffff\n", "");
+        assert!(host.start(None, config.as_bytes()));
+        host.ticks(3);
+
+        let message = format!(
+            "block {} carries less work than the minimum, its target is 1d00ffff",
+            TIP
+        );
+        assert!(host.logs().iter().any(|(_, m)| *m == message));
+        let headers = [(":authority", "example.com"), (":path", "/")];
+        request(&host, "10.0.0.1:1234", &headers).finish();
+        let stream = request(&host, "10.0.0.1:1234", &headers);
+        assert_eq!(stream.local_response().map(|r| r.status), Some(429));
+        assert_eq!(decision(&stream)["degraded"], "challenge");
+    }
 
     #[test]
     fn mine() {
@@ -431,7 +1375,7 @@ mod test {
 
     #[test]
     fn decode() {
-        let nonce = "aaed9b41fcf6dc5";
+        let nonce = "aaed9b41fcf6dc52";
         let hex = hex::decode(nonce).expect("invalid hex");
         print_hex(&hex);
     }
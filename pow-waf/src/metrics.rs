@@ -0,0 +1,92 @@
+use pow_runtime::metrics::{self, Metric};
+use proxy_wasm::types::Status;
+
+const REQUESTS_SEEN: &str = "pow_waf.requests_seen";
+const REQUESTS_WHITELISTED: &str = "pow_waf.requests_whitelisted";
+const REQUESTS_BYPASSED: &str = "pow_waf.requests_bypassed";
+const REQUESTS_CHALLENGED: &str = "pow_waf.requests_challenged";
+const REQUESTS_SOLVED: &str = "pow_waf.requests_solved";
+const REQUESTS_REJECTED: &str = "pow_waf.requests_rejected";
+const REQUESTS_DEGRADED: &str = "pow_waf.requests_degraded";
+const DIFFICULTY_PAID: &str = "pow_waf.difficulty_paid";
+const CHAIN_HASH_AGE: &str = "pow_waf.chain_hash_age";
+
+const UNMATCHED: &str = "unmatched";
+
+fn report(metric: Result<Metric, Status>, f: impl FnOnce(Metric) -> Result<(), Status>) {
+    if let Err(e) = metric.and_then(f) {
+        log::warn!("failed to report metric: {:?}", e);
+    }
+}
+
+/// Decision metrics of a request, tagged by virtual host and route pattern.
+pub struct RouteMetrics<'a> {
+    virtual_host: &'a str,
+    route: &'a str,
+}
+
+impl<'a> RouteMetrics<'a> {
+    pub fn new(virtual_host: &'a str, route: &'a str) -> Self {
+        Self {
+            virtual_host,
+            route,
+        }
+    }
+
+    pub fn unmatched() -> Self {
+        Self::new(UNMATCHED, UNMATCHED)
+    }
+
+    fn tags(&self) -> [(&'static str, &'a str); 2] {
+        [("vhost", self.virtual_host), ("route", self.route)]
+    }
+
+    fn increment(&self, name: &str) {
+        report(metrics::counter(name, &self.tags()), |m| m.increment(1));
+    }
+
+    pub fn seen(&self) {
+        self.increment(REQUESTS_SEEN);
+    }
+
+    pub fn whitelisted(&self) {
+        self.increment(REQUESTS_WHITELISTED);
+    }
+
+    pub fn bypassed(&self) {
+        self.increment(REQUESTS_BYPASSED);
+    }
+
+    pub fn challenged(&self) {
+        self.increment(REQUESTS_CHALLENGED);
+    }
+
+    pub fn solved(&self, difficulty: u64) {
+        self.increment(REQUESTS_SOLVED);
+        report(metrics::histogram(DIFFICULTY_PAID, &self.tags()), |m| {
+            m.record(difficulty)
+        });
+    }
+
+    pub fn rejected(&self, reason: &str) {
+        let [vhost, route] = self.tags();
+        report(
+            metrics::counter(REQUESTS_REJECTED, &[vhost, route, ("reason", reason)]),
+            |m| m.increment(1),
+        );
+    }
+
+    /// Handled by the `on_error` policy of the route instead of the rate limit.
+    pub fn degraded(&self, policy: &str) {
+        let [vhost, route] = self.tags();
+        report(
+            metrics::counter(REQUESTS_DEGRADED, &[vhost, route, ("policy", policy)]),
+            |m| m.increment(1),
+        );
+    }
+}
+
+/// Seconds since the latest chain hash was first seen.
+pub fn chain_hash_age(age: u64) {
+    report(metrics::gauge(CHAIN_HASH_AGE, &[]), |m| m.record(age));
+}
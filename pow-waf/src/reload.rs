@@ -0,0 +1,125 @@
+use std::{collections::BTreeMap, fmt};
+
+use pow_types::config::VirtualHost;
+
+use crate::config::Setting;
+
+/// Every route of a configuration keyed by host and full pattern, kept to
+/// tell what a reload changed.
+pub type RouteTable = BTreeMap<String, Setting>;
+
+pub fn route_table(virtual_hosts: &[VirtualHost<Setting>]) -> RouteTable {
+    virtual_hosts
+        .iter()
+        .flat_map(|vh| {
+            vh.settings()
+                .into_iter()
+                .map(|(pattern, setting)| (format!("{}{}", vh.host, pattern), setting.clone()))
+        })
+        .collect()
+}
+
+/// Routes that differ between two configurations.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RouteChanges {
+    pub added: Vec<String>,
+    pub removed: Vec<String>,
+    pub changed: Vec<String>,
+    pub unchanged: usize,
+}
+
+impl RouteChanges {
+    pub fn diff(old: &RouteTable, new: &RouteTable) -> Self {
+        let mut changes = Self::default();
+        for (route, setting) in new {
+            match old.get(route) {
+                None => changes.added.push(route.clone()),
+                Some(previous) if previous != setting => changes.changed.push(route.clone()),
+                Some(_) => changes.unchanged += 1,
+            }
+        }
+        changes.removed = old
+            .keys()
+            .filter(|route| !new.contains_key(*route))
+            .cloned()
+            .collect();
+        changes
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl fmt::Display for RouteChanges {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (name, routes) in [
+            ("added", &self.added),
+            ("removed", &self.removed),
+            ("changed", &self.changed),
+        ] {
+            if !routes.is_empty() {
+                write!(f, "{} {} [{}], ", routes.len(), name, routes.join(", "))?;
+            }
+        }
+        write!(f, "{} unchanged", self.unchanged)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn table(source: &str) -> RouteTable {
+        let virtual_hosts: Vec<VirtualHost<Setting>> =
+            serde_yaml::from_str(source).expect("failed to parse virtual hosts");
+        route_table(&virtual_hosts)
+    }
+
+    #[test]
+    fn diff_routes() {
+        let old = table(
+            r#"
+- host: example.com
+  routes:
+    - path: "/"
+      rate_limit: { unit: minute, requests_per_unit: 100 }
+      children:
+        - path: "/login"
+          rate_limit: { unit: minute, requests_per_unit: 5 }
+    - path: "/old"
+      rate_limit: { unit: minute, requests_per_unit: 100 }
+"#,
+        );
+        let new = table(
+            r#"
+- host: example.com
+  routes:
+    - path: "/"
+      rate_limit: { unit: minute, requests_per_unit: 100 }
+      children:
+        - path: "/login"
+          rate_limit: { unit: minute, requests_per_unit: 10 }
+    - path: "/new"
+      rate_limit: { unit: minute, requests_per_unit: 100 }
+"#,
+        );
+
+        let changes = RouteChanges::diff(&old, &new);
+        assert_eq!(
+            changes,
+            RouteChanges {
+                added: vec!["example.com/new".to_string()],
+                removed: vec!["example.com/old".to_string()],
+                changed: vec!["example.com/login".to_string()],
+                unchanged: 1,
+            }
+        );
+        assert_eq!(
+            changes.to_string(),
+            "1 added [example.com/new], 1 removed [example.com/old], \
+             1 changed [example.com/login], 1 unchanged"
+        );
+        assert!(RouteChanges::diff(&new, &new).is_empty());
+    }
+}